@@ -0,0 +1,137 @@
+//! Access log with one structured event per completed request.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{self, BoxBody, Bytes, HttpBody},
+    http::{HeaderMap, Request},
+    middleware::Next,
+    response::Response,
+};
+use hyper::body::SizeHint;
+
+use crate::config::{Configuration, LogFormat};
+
+/// The key a response was served from, recorded by the proxy.
+#[derive(Clone, Debug)]
+pub struct BucketPath(pub String);
+
+/// A request whose response is still being sent.
+struct Entry {
+    method: String,
+    path: Option<String>,
+    bucket_path: Option<String>,
+    status: u16,
+    started: Instant,
+}
+
+impl Entry {
+    fn emit(&self, bytes: u64, duration: Duration) {
+        tracing::info!(
+            target: "access_log",
+            method = %self.method,
+            path = self.path.as_deref(),
+            bucket_path = self.bucket_path.as_deref(),
+            status = self.status,
+            bytes,
+            duration_ms = duration.as_secs_f64() * 1000.0,
+            "request completed"
+        );
+    }
+}
+
+/// A response body logging its [`Entry`] with the bytes sent once it's
+/// dropped, after it was fully sent or the client went away.
+struct LoggedBody {
+    inner: BoxBody,
+    entry: Entry,
+    bytes: u64,
+}
+
+impl HttpBody for LoggedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_data(cx);
+        if let Poll::Ready(Some(Ok(data))) = &poll {
+            self.bytes += data.len() as u64;
+        }
+
+        poll
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        self.entry.emit(self.bytes, self.entry.started.elapsed());
+    }
+}
+
+/// Whether requests for `path` are left out of the access log.
+fn is_excluded(config: &Configuration, path: &str) -> bool {
+    !config.log_probes()
+        && (matches!(path, "/healthz" | "/healthz/buckets" | "/readyz")
+            || (config.metrics().enabled() && path == config.metrics().path()))
+}
+
+/// Logs every request with `log_format: json`, after its response completed.
+///
+/// Expects the [`Configuration`] in the request's extensions, so it has to be
+/// layered inside of it.
+pub async fn log_requests<B>(request: Request<B>, next: Next<B>) -> Response {
+    let Some(config) = request.extensions().get::<Configuration>() else {
+        return next.run(request).await;
+    };
+    if config.log_format() != LogFormat::Json || is_excluded(config, request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    let log_paths = config.log_paths();
+    let method = request.method().to_string();
+    let path = log_paths.format(request.uri().path());
+    let started = Instant::now();
+
+    let response = next.run(request).await;
+
+    let entry = Entry {
+        method,
+        path,
+        bucket_path: response
+            .extensions()
+            .get::<BucketPath>()
+            .and_then(|BucketPath(bucket_path)| log_paths.format(bucket_path)),
+        status: response.status().as_u16(),
+        started,
+    };
+
+    response.map(|inner| {
+        body::boxed(LoggedBody {
+            inner,
+            entry,
+            bytes: 0,
+        })
+    })
+}
@@ -0,0 +1,189 @@
+//! Administrative endpoints for operators, protected by a bearer token.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use axum::{
+    extract::Extension,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    cache_bust::EtagCache,
+    config::{Admin, Bucket, Configuration},
+};
+
+/// When the server was started, for reporting the uptime.
+#[derive(Clone, Copy)]
+pub struct StartTime(pub Instant);
+
+/// Whether maintenance mode is on, which can be toggled at runtime.
+#[derive(Clone)]
+pub struct MaintenanceMode(Arc<AtomicBool>);
+
+impl MaintenanceMode {
+    pub fn new(enabled: bool) -> Self {
+        Self(Arc::new(AtomicBool::new(enabled)))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.0.store(enabled, Ordering::Relaxed);
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct MaintenanceState {
+    enabled: bool,
+}
+
+#[derive(Serialize)]
+struct DebugInfo<'a> {
+    uptime_secs: u64,
+    config: ConfigSummary<'a>,
+    etag_cache_entries: usize,
+    /// Requests whose response is still being sent, this one included.
+    active_streams: i64,
+}
+
+/// The configuration without any credentials.
+#[derive(Serialize)]
+struct ConfigSummary<'a> {
+    bucket: Option<BucketSummary<'a>>,
+    endpoints: Vec<EndpointSummary<'a>>,
+}
+
+#[derive(Serialize)]
+struct BucketSummary<'a> {
+    endpoint: Option<&'a str>,
+    region: &'a str,
+    bucket_name: &'a str,
+    anonymous: bool,
+    has_credentials: bool,
+}
+
+impl<'a> From<&'a Bucket> for BucketSummary<'a> {
+    fn from(bucket: &'a Bucket) -> Self {
+        Self {
+            endpoint: bucket.endpoint(),
+            region: bucket.region(),
+            bucket_name: bucket.bucket_name(),
+            anonymous: bucket.anonymous(),
+            has_credentials: bucket.access_key().is_some() && bucket.secret_key().is_some(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct EndpointSummary<'a> {
+    path: &'a str,
+    /// Only set for endpoints overriding the global bucket.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bucket: Option<BucketSummary<'a>>,
+    bucket_path: &'a str,
+}
+
+/// Compares the tokens in constant time, so the time taken doesn't reveal how
+/// much of `token` matched. Both are hashed first to not reveal the length
+/// either.
+pub fn token_matches(token: &str, expected: &str) -> bool {
+    let (token, expected) = (Sha256::digest(token), Sha256::digest(expected));
+
+    token
+        .iter()
+        .zip(expected.iter())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+fn is_authorized(admin: &Admin, headers: &HeaderMap) -> bool {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| token_matches(token, admin.token()))
+}
+
+fn make_unauthorized_response() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"))],
+        "Unauthorized",
+    )
+        .into_response()
+}
+
+/// Reports the state of the proxy as JSON.
+pub async fn debug(
+    headers: HeaderMap,
+    Extension(config): Extension<Configuration>,
+    Extension(etags): Extension<EtagCache>,
+    Extension(StartTime(started)): Extension<StartTime>,
+) -> Response {
+    match config.admin() {
+        Some(admin) if is_authorized(admin, &headers) => {}
+        _ => return make_unauthorized_response(),
+    }
+
+    Json(DebugInfo {
+        uptime_secs: started.elapsed().as_secs(),
+        config: ConfigSummary {
+            bucket: config.bucket().map(BucketSummary::from),
+            endpoints: config
+                .endpoints()
+                .iter()
+                .map(|endpoint| EndpointSummary {
+                    path: endpoint.path(),
+                    bucket: endpoint.bucket().map(BucketSummary::from),
+                    bucket_path: endpoint.bucket_path(),
+                })
+                .collect(),
+        },
+        etag_cache_entries: etags.len(),
+        active_streams: config.registry().in_flight(),
+    })
+    .into_response()
+}
+
+/// Switches maintenance mode on or off without a restart.
+pub async fn set_maintenance(
+    headers: HeaderMap,
+    Extension(config): Extension<Configuration>,
+    Extension(maintenance): Extension<MaintenanceMode>,
+    Json(state): Json<MaintenanceState>,
+) -> Response {
+    match config.admin() {
+        Some(admin) if is_authorized(admin, &headers) => {}
+        _ => return make_unauthorized_response(),
+    }
+
+    tracing::info!("Maintenance mode enabled: {}", state.enabled);
+    maintenance.set_enabled(state.enabled);
+
+    Json(state).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_matches() {
+        assert!(token_matches("secret", "secret"));
+        assert!(!token_matches("secreT", "secret"));
+        assert!(!token_matches("secret2", "secret"));
+        assert!(!token_matches("", "secret"));
+    }
+}
@@ -0,0 +1,80 @@
+//! The S3 buckets requests are proxied to, created at startup and rebuilt on
+//! reloads and before temporary credentials expire.
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use s3::Bucket;
+
+use crate::config::{ConfigError, Configuration, Endpoint};
+
+#[derive(Clone)]
+pub struct Buckets {
+    default: Option<Bucket>,
+    /// Buckets of endpoints overriding the default, keyed by endpoint path.
+    endpoints: Arc<HashMap<String, Bucket>>,
+}
+
+impl Buckets {
+    pub fn new(config: &Configuration) -> Result<Self, ConfigError> {
+        let default = config
+            .bucket()
+            .map(|bucket| bucket.make_s3_bucket())
+            .transpose()?;
+
+        let mut endpoints = HashMap::new();
+        for endpoint in config.endpoints().iter() {
+            if let Some(bucket) = endpoint.bucket() {
+                endpoints.insert(endpoint.path().to_owned(), bucket.make_s3_bucket()?);
+            }
+        }
+
+        Ok(Self {
+            default,
+            endpoints: Arc::new(endpoints),
+        })
+    }
+
+    /// Returns the bucket `endpoint` is served from.
+    ///
+    /// # Panics
+    ///
+    /// If `endpoint` has no bucket, which the configuration's validation rules out.
+    pub fn get(&self, endpoint: &Endpoint) -> &Bucket {
+        self.endpoints
+            .get(endpoint.path())
+            .or(self.default.as_ref())
+            .expect("every endpoint has a bucket")
+    }
+
+    /// Returns all buckets, the default one first.
+    pub fn iter(&self) -> impl Iterator<Item = &Bucket> {
+        self.default.iter().chain(self.endpoints.values())
+    }
+
+    /// Returns all buckets like [`Buckets::iter`], labeled `default` or with
+    /// the path of the endpoint overriding it. Unlike their URLs, the labels
+    /// don't reveal where the buckets are hosted.
+    pub fn labeled(&self) -> impl Iterator<Item = (&str, &Bucket)> {
+        self.default.iter().map(|bucket| ("default", bucket)).chain(
+            self.endpoints
+                .iter()
+                .map(|(path, bucket)| (path.as_str(), bucket)),
+        )
+    }
+
+    /// Returns when the first temporary credentials of the buckets expire,
+    /// like those of the instance metadata or an assumed role.
+    pub fn credentials_expiration(&self) -> Option<SystemTime> {
+        self.iter()
+            .filter_map(|bucket| bucket.credentials.expiration)
+            .map(|expiration| {
+                let secs = u64::try_from(expiration.unix_timestamp()).unwrap_or_default();
+                SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+            })
+            .min()
+    }
+}
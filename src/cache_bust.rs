@@ -0,0 +1,386 @@
+//! Rewriting of asset links in HTML documents to include a version derived
+//! from the referenced object's ETag, e.g. `app.js` becomes `app.js?v=3f9a0c1d`.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    ops::Range,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::OnceCell;
+
+/// How long a looked up ETag is reused before asking the bucket again.
+const ETAG_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Caches ETags of referenced assets, keyed by bucket path.
+#[derive(Clone, Default)]
+pub struct EtagCache(Arc<EtagCacheInner>);
+
+#[derive(Default)]
+struct EtagCacheInner {
+    entries: Mutex<HashMap<String, (Instant, String)>>,
+    /// Lookups of missing entries in flight, keyed by bucket path.
+    in_flight: Mutex<HashMap<String, Arc<OnceCell<Option<String>>>>>,
+}
+
+impl EtagCache {
+    pub fn get(&self, bucket_path: &str) -> Option<String> {
+        let entries = self.0.entries.lock().unwrap();
+
+        entries
+            .get(bucket_path)
+            .filter(|(fetched, _)| fetched.elapsed() < ETAG_CACHE_TTL)
+            .map(|(_, etag)| etag.clone())
+    }
+
+    /// Returns the number of cached ETags, including expired ones.
+    pub fn len(&self) -> usize {
+        self.0.entries.lock().unwrap().len()
+    }
+
+    pub fn insert(&self, bucket_path: String, etag: String) {
+        self.0
+            .entries
+            .lock()
+            .unwrap()
+            .insert(bucket_path, (Instant::now(), etag));
+    }
+
+    /// Returns the cached ETag, or looks it up with `fetch` and caches it.
+    ///
+    /// Concurrent misses for the same path share a single lookup, so the entry
+    /// is only written once.
+    pub async fn get_or_fetch<F, Fut>(&self, bucket_path: &str, fetch: F) -> Option<String>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Option<String>>,
+    {
+        if let Some(etag) = self.get(bucket_path) {
+            return Some(etag);
+        }
+
+        let cell = self
+            .0
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(bucket_path.to_owned())
+            .or_default()
+            .clone();
+
+        let etag = cell
+            .get_or_init(|| async {
+                // a lookup may have finished since the entry was checked
+                if let Some(etag) = self.get(bucket_path) {
+                    return Some(etag);
+                }
+
+                let etag = fetch().await;
+                if let Some(etag) = &etag {
+                    self.insert(bucket_path.to_owned(), etag.clone());
+                }
+                etag
+            })
+            .await
+            .clone();
+
+        let mut in_flight = self.0.in_flight.lock().unwrap();
+        if in_flight
+            .get(bucket_path)
+            .is_some_and(|current| Arc::ptr_eq(current, &cell))
+        {
+            in_flight.remove(bucket_path);
+        }
+
+        etag
+    }
+}
+
+/// A link to an asset found in an HTML document.
+#[derive(Debug, PartialEq, Eq)]
+pub struct AssetLink {
+    /// Byte range of the URL within the document.
+    pub range: Range<usize>,
+    pub url: String,
+}
+
+/// Finds all `<script src>` and `<link href>` URLs in `html`.
+pub fn find_asset_links(html: &str) -> Vec<AssetLink> {
+    let mut links = Vec::new();
+    let mut offset = 0;
+
+    while let Some(start) = html[offset..].find('<') {
+        let tag_start = offset + start + 1;
+        let tag_end = match html[tag_start..].find('>') {
+            Some(end) => tag_start + end,
+            None => break,
+        };
+        offset = tag_end;
+
+        let tag = &html[tag_start..tag_end];
+        let name_len = tag
+            .find(|c: char| c.is_ascii_whitespace())
+            .unwrap_or(tag.len());
+
+        let attribute = match &tag[..name_len] {
+            name if name.eq_ignore_ascii_case("script") => "src",
+            name if name.eq_ignore_ascii_case("link") => "href",
+            _ => continue,
+        };
+
+        if let Some(range) = find_attribute(&tag[name_len..], attribute) {
+            let range = (tag_start + name_len + range.start)..(tag_start + name_len + range.end);
+            links.push(AssetLink {
+                url: html[range.clone()].to_owned(),
+                range,
+            });
+        }
+    }
+
+    links
+}
+
+/// Returns the byte range of the value of attribute `name` in `attributes`.
+fn find_attribute(attributes: &str, name: &str) -> Option<Range<usize>> {
+    let bytes = attributes.as_bytes();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        while i < bytes.len() && (bytes[i].is_ascii_whitespace() || bytes[i] == b'/') {
+            i += 1;
+        }
+
+        let name_start = i;
+        while i < bytes.len() && !bytes[i].is_ascii_whitespace() && !b"=/".contains(&bytes[i]) {
+            i += 1;
+        }
+        let attribute_name = &attributes[name_start..i];
+
+        while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if i >= bytes.len() || bytes[i] != b'=' {
+            if attribute_name.is_empty() {
+                i += 1;
+            }
+            continue;
+        }
+        i += 1;
+        while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+
+        let value = match bytes.get(i) {
+            Some(quote @ (b'"' | b'\'')) => {
+                let start = i + 1;
+                let end = attributes[start..]
+                    .find(*quote as char)
+                    .map_or(bytes.len(), |end| start + end);
+                i = end + 1;
+                start..end
+            }
+            _ => {
+                let start = i;
+                while i < bytes.len() && !bytes[i].is_ascii_whitespace() {
+                    i += 1;
+                }
+                start..i
+            }
+        };
+
+        if attribute_name.eq_ignore_ascii_case(name) {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+/// Resolves an asset URL against the path of the document referencing it.
+///
+/// The URL is percent-decoded like request paths are, so the result can be
+/// looked up as is. Returns [`None`] for URLs that are not served by this host
+/// or that already carry a query string.
+pub fn resolve_asset_path(document_path: &str, url: &str) -> Option<String> {
+    if url.is_empty()
+        || url.starts_with("//")
+        || url.contains("://")
+        || url.starts_with("data:")
+        || url.contains('?')
+    {
+        return None;
+    }
+
+    let url = url.split('#').next()?;
+    let url = percent_encoding::percent_decode_str(url)
+        .decode_utf8()
+        .ok()?;
+
+    let joined = if url.starts_with('/') {
+        url.into_owned()
+    } else {
+        let base = &document_path[..document_path.rfind('/').map_or(0, |i| i + 1)];
+        format!("{base}{url}")
+    };
+
+    let mut segments = Vec::new();
+    for segment in joined.split('/') {
+        match segment {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    let path = segments.join("/");
+    Some(if path.starts_with('/') {
+        path
+    } else {
+        format!("/{path}")
+    })
+}
+
+/// Derives a short version string from an ETag.
+pub fn etag_version(etag: &str) -> String {
+    etag.trim_start_matches("W/")
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .take(8)
+        .collect()
+}
+
+/// Appends `?v=<version>` to every link which has a version in `versions`.
+pub fn rewrite_asset_links(html: &str, links: &[AssetLink], versions: &[Option<String>]) -> String {
+    let mut rewritten = String::with_capacity(html.len());
+    let mut offset = 0;
+
+    for (link, version) in links.iter().zip(versions) {
+        if let Some(version) = version {
+            let insert_at = link.range.start + link.url.find('#').unwrap_or(link.url.len());
+
+            rewritten.push_str(&html[offset..insert_at]);
+            rewritten.push_str("?v=");
+            rewritten.push_str(version);
+            offset = insert_at;
+        }
+    }
+
+    rewritten.push_str(&html[offset..]);
+    rewritten
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_coalesced_cache_writes() {
+        let etags = EtagCache::default();
+        let fetches = Arc::new(AtomicUsize::new(0));
+
+        let lookups: Vec<_> = (0..10)
+            .map(|_| {
+                let etags = etags.clone();
+                let fetches = fetches.clone();
+                tokio::spawn(async move {
+                    etags
+                        .get_or_fetch("/assets/app.js", || async {
+                            fetches.fetch_add(1, Ordering::Relaxed);
+                            tokio::time::sleep(Duration::from_millis(50)).await;
+                            Some("\"abc\"".to_owned())
+                        })
+                        .await
+                })
+            })
+            .collect();
+        let mut results = Vec::new();
+        for lookup in lookups {
+            results.push(lookup.await.unwrap());
+        }
+
+        assert!(results
+            .iter()
+            .all(|etag| etag.as_deref() == Some("\"abc\"")));
+        assert_eq!(fetches.load(Ordering::Relaxed), 1);
+        assert_eq!(etags.len(), 1);
+        assert!(etags.0.in_flight.lock().unwrap().is_empty());
+
+        // failed lookups aren't cached
+        let etag = etags
+            .get_or_fetch("/assets/missing.js", || async { None })
+            .await;
+        assert_eq!(etag, None);
+        assert_eq!(etags.len(), 1);
+    }
+
+    #[test]
+    fn test_find_asset_links() {
+        let html = r#"<html><head>
+            <link rel="stylesheet" href="style.css">
+            <script type="module" src='/js/app.js'></script>
+            <img src="image.png">
+            <script>let a = 1 < 2;</script>
+        </head></html>"#;
+
+        let urls = find_asset_links(html)
+            .into_iter()
+            .map(|link| link.url)
+            .collect::<Vec<_>>();
+
+        assert_eq!(urls, vec!["style.css", "/js/app.js"]);
+    }
+
+    #[test]
+    fn test_resolve_asset_path() {
+        assert_eq!(
+            resolve_asset_path("/site/index.html", "style.css").as_deref(),
+            Some("/site/style.css")
+        );
+        assert_eq!(
+            resolve_asset_path("/site/docs/", "../js/app.js#main").as_deref(),
+            Some("/site/js/app.js")
+        );
+        assert_eq!(
+            resolve_asset_path("/site/index.html", "/js/app.js").as_deref(),
+            Some("/js/app.js")
+        );
+        assert_eq!(
+            resolve_asset_path("/site/index.html", "https://cdn.example.com/a.js"),
+            None
+        );
+        assert_eq!(resolve_asset_path("/site/", "//cdn.example.com/a.js"), None);
+        assert_eq!(resolve_asset_path("/site/", "app.js?v=1"), None);
+        assert_eq!(
+            resolve_asset_path("/100% site/", "caf%C3%A9%2Bmenu.css").as_deref(),
+            Some("/100% site/café+menu.css")
+        );
+    }
+
+    #[test]
+    fn test_rewrite_asset_links() {
+        let html =
+            r#"<link href="a.css"><script src="b.js#x"></script><script src="c.js"></script>"#;
+        let links = find_asset_links(html);
+
+        let rewritten = rewrite_asset_links(
+            html,
+            &links,
+            &[
+                Some(etag_version("\"0123456789abcdef\"")),
+                Some(etag_version("W/\"fedcba9876543210\"")),
+                None,
+            ],
+        );
+
+        assert_eq!(
+            rewritten,
+            r#"<link href="a.css?v=01234567"><script src="b.js?v=fedcba98#x"></script><script src="c.js"></script>"#
+        );
+    }
+}
@@ -0,0 +1,139 @@
+//! Coalescing of identical concurrent upstream requests, so a burst of
+//! requests for the same object only reaches the bucket once.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::{self, Bytes, Full},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use tokio::sync::OnceCell;
+
+/// A fully buffered response, shared between all requests waiting for it.
+#[derive(Clone, Debug)]
+struct SharedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl IntoResponse for SharedResponse {
+    fn into_response(self) -> Response {
+        let mut response = Response::new(body::boxed(Full::from(self.body)));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers;
+        response
+    }
+}
+
+/// The outcome of a request in flight.
+#[derive(Clone, Debug)]
+enum Flight {
+    Shared(SharedResponse),
+    /// The response was too large to be buffered and was streamed to the
+    /// request that made it only.
+    Streamed,
+}
+
+/// Identifies a request by everything its response depends on, so only
+/// requests that would get the same response are coalesced.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Key {
+    /// The bucket's URL, naming both the bucket and where it's hosted.
+    pub bucket: String,
+    /// The path of the endpoint, whose settings shape the response.
+    pub endpoint: String,
+    /// The request path, which redirects are built from.
+    pub path: String,
+    pub bucket_path: String,
+}
+
+/// Requests in flight, keyed by [`Key`].
+#[derive(Clone, Debug, Default)]
+pub struct Coalescer(Arc<Mutex<HashMap<Key, Arc<OnceCell<Flight>>>>>);
+
+impl Coalescer {
+    /// Runs `fetch` unless a request for `key` is already in flight, in which
+    /// case its response is awaited instead. If the request in flight is
+    /// cancelled, one of the waiting requests takes over.
+    ///
+    /// Responses with a `Content-Length` of at most `max_size` are buffered
+    /// to be shared, an error while reading the body is answered with `502 Bad
+    /// Gateway` for all waiting requests. Larger responses and those of unknown
+    /// length are streamed to the request that made them, the waiting requests
+    /// then make their own.
+    pub async fn run<F, Fut>(&self, key: Key, max_size: u64, fetch: F) -> Response
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Response>,
+    {
+        let cell = self
+            .0
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+
+        let mut fetch = Some(fetch);
+        let mut streamed = None;
+
+        let flight = cell
+            .get_or_init(|| async {
+                let fetch = fetch.take().expect("only the first request fetches");
+                let response = fetch().await;
+
+                let length = response
+                    .headers()
+                    .get(header::CONTENT_LENGTH)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.parse::<u64>().ok());
+                if length.is_none_or(|length| length > max_size) {
+                    streamed = Some(response);
+                    return Flight::Streamed;
+                }
+
+                let (parts, body) = response.into_parts();
+                match hyper::body::to_bytes(body).await {
+                    Ok(body) => Flight::Shared(SharedResponse {
+                        status: parts.status,
+                        headers: parts.headers,
+                        body,
+                    }),
+                    Err(err) => {
+                        tracing::warn!("Couldn't read upstream response: {}", err);
+                        Flight::Shared(SharedResponse {
+                            status: StatusCode::BAD_GATEWAY,
+                            headers: HeaderMap::new(),
+                            body: Bytes::new(),
+                        })
+                    }
+                }
+            })
+            .await
+            .clone();
+
+        // the first request to finish ends the flight, later ones start a new one
+        {
+            let mut in_flight = self.0.lock().unwrap();
+            if in_flight
+                .get(&key)
+                .is_some_and(|current| Arc::ptr_eq(current, &cell))
+            {
+                in_flight.remove(&key);
+            }
+        }
+
+        match (flight, streamed, fetch) {
+            (Flight::Shared(response), _, _) => response.into_response(),
+            (Flight::Streamed, Some(response), _) => response,
+            (Flight::Streamed, None, Some(fetch)) => fetch().await,
+            (Flight::Streamed, None, None) => unreachable!("the fetching request streams"),
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! Compression of text responses with gzip and brotli.
+
+use axum::{
+    http::{header, HeaderValue, Request, Response, StatusCode},
+    middleware::{self, Next},
+    Router,
+};
+use hyper::body::HttpBody;
+use tower_http::compression::{predicate::Predicate, CompressionLayer};
+
+/// Whether a response is worth compressing: text of at least `min_size`
+/// bytes, or of unknown size. Partial content is left alone, as its ranges
+/// refer to the uncompressed object.
+#[derive(Clone, Copy)]
+struct Compressible {
+    min_size: u64,
+}
+
+impl Compressible {
+    fn is_compressible<B>(&self, response: &Response<B>) -> bool {
+        let headers = response.headers();
+        let is_text = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(is_compressible_type);
+        let is_large = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+            .is_none_or(|len| len >= self.min_size);
+
+        response.status() != StatusCode::PARTIAL_CONTENT && is_text && is_large
+    }
+}
+
+impl Predicate for Compressible {
+    fn should_compress<B>(&self, response: &Response<B>) -> bool
+    where
+        B: HttpBody,
+    {
+        self.is_compressible(response)
+    }
+}
+
+/// Whether responses of `content_type` are text worth compressing.
+pub fn is_compressible_type(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || matches!(
+            essence.as_str(),
+            "application/javascript" | "application/json" | "application/xml"
+        )
+}
+
+/// Adds `Vary: Accept-Encoding` to compressible responses, as their encoding
+/// depends on the client.
+async fn vary_accept_encoding<B>(
+    compressible: Compressible,
+    request: Request<B>,
+    next: Next<B>,
+) -> axum::response::Response {
+    let mut response = next.run(request).await;
+
+    let varies = response
+        .headers()
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|name| name.trim().eq_ignore_ascii_case("accept-encoding"));
+
+    if compressible.is_compressible(&response) && !varies {
+        response
+            .headers_mut()
+            .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    }
+
+    response
+}
+
+/// Encodings of pre-compressed variants and the extension they're stored
+/// with, in order of preference.
+pub const PRECOMPRESSED_VARIANTS: [(&str, &str); 2] = [("br", "br"), ("gzip", "gz")];
+
+/// Returns the encodings of [`PRECOMPRESSED_VARIANTS`] `accept_encoding`
+/// accepts, by quality. Encodings of equal quality are ordered by preference,
+/// `*` stands for any encoding not listed.
+pub fn negotiate_encodings(accept_encoding: &str) -> Vec<(&'static str, &'static str)> {
+    let codings = accept_encoding
+        .split(',')
+        .filter_map(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().filter(|name| !name.is_empty())?;
+            let quality = params
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())?;
+
+            Some((name, quality))
+        })
+        .collect::<Vec<_>>();
+    let quality = |encoding: &str| {
+        codings
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(encoding))
+            .or_else(|| codings.iter().find(|(name, _)| *name == "*"))
+            .map_or(0.0, |(_, quality)| *quality)
+    };
+
+    let mut encodings = PRECOMPRESSED_VARIANTS
+        .into_iter()
+        .map(|variant| (variant, quality(variant.0)))
+        .filter(|(_, quality)| *quality > 0.0)
+        .collect::<Vec<_>>();
+    // stable, so encodings of equal quality keep their preference
+    encodings.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+    encodings.into_iter().map(|(variant, _)| variant).collect()
+}
+
+/// Compresses text responses of `router` of at least `min_size` bytes for
+/// clients accepting gzip or brotli. Compressed responses are streamed
+/// without a `Content-Length`.
+pub fn compress(router: Router, min_size: u64) -> Router {
+    let compressible = Compressible { min_size };
+
+    router
+        .layer(middleware::from_fn(move |request, next| {
+            vary_accept_encoding(compressible, request, next)
+        }))
+        .layer(CompressionLayer::new().compress_when(compressible))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_encodings() {
+        let encodings = |accept_encoding| {
+            negotiate_encodings(accept_encoding)
+                .into_iter()
+                .map(|(encoding, _)| encoding)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(encodings("gzip, deflate, br"), ["br", "gzip"]);
+        assert_eq!(encodings("gzip;q=1.0, br;q=0.8"), ["gzip", "br"]);
+        assert_eq!(encodings("gzip"), ["gzip"]);
+        assert_eq!(encodings("*"), ["br", "gzip"]);
+        assert_eq!(encodings("br;q=0, *;q=0.5"), ["gzip"]);
+        assert_eq!(encodings("GZIP"), ["gzip"]);
+        assert!(encodings("identity").is_empty());
+        assert!(encodings("").is_empty());
+    }
+
+    #[test]
+    fn test_is_compressible_type() {
+        for content_type in [
+            "text/css",
+            "text/html; charset=utf-8",
+            "application/javascript",
+            "application/json",
+            "application/ld+json",
+            "image/svg+xml",
+            "Application/JSON",
+        ] {
+            assert!(is_compressible_type(content_type), "{content_type}");
+        }
+
+        for content_type in ["image/png", "application/octet-stream", "video/mp4", ""] {
+            assert!(!is_compressible_type(content_type), "{content_type}");
+        }
+    }
+}
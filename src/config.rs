@@ -1,6 +1,19 @@
-use std::{net::SocketAddr, path::Path};
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
+use anyhow::Context;
+use axum::http::{header::HeaderName, HeaderValue};
+use regex::Regex;
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::Semaphore;
+
+use crate::{admin::token_matches, coalesce::Coalescer, metrics::Registry};
 
 #[derive(Debug, thiserror::Error)]
 pub enum ConfigError {
@@ -8,6 +21,77 @@ pub enum ConfigError {
     UnkownRegion,
     #[error("Couldn't parse bind address: {0}")]
     AddrParseError(#[from] std::net::AddrParseError),
+    #[error("Invalid header name: {0}")]
+    InvalidHeaderName(String),
+    #[error("Invalid header value: {0}")]
+    InvalidHeaderValue(String),
+    #[error("Duplicate endpoint path: {0}")]
+    DuplicateEndpoint(String),
+    #[error("Bucket {0} is not in allowed_buckets")]
+    BucketNotAllowed(String),
+    #[error("No bucket configured for endpoint {0}")]
+    MissingBucket(String),
+    #[error("Invalid locale: {0}")]
+    InvalidLocale(String),
+    #[error("Invalid metrics path: {0}")]
+    InvalidMetricsPath(String),
+    #[error("Environment variable {0} is not set")]
+    MissingEnvVar(String),
+    #[error("TLS requires both cert_path and key_path, {0} is missing")]
+    IncompleteTls(&'static str),
+    #[error("TLS isn't supported when binding to a unix socket")]
+    UnixSocketTls,
+    #[error("http.bind needs at least one address")]
+    MissingBind,
+    #[error("rate_limit needs a positive requests_per_second and a burst of at least 1")]
+    InvalidRateLimit,
+    #[error("Invalid fingerprint pattern {0}: {1}")]
+    InvalidFingerprintPattern(String, regex::Error),
+    #[error("No usable credentials for bucket {0}: {1}")]
+    MissingCredentials(String, String),
+    #[error("Invalid basic_auth of endpoint {0}: {1}")]
+    InvalidBasicAuth(String, &'static str),
+    #[error("presign_expiry of endpoint {0} must be between 1 and 604800 seconds")]
+    InvalidPresignExpiry(String),
+}
+
+/// Replaces `${VAR}` and `${VAR:-default}` in `yaml` with the values `lookup`
+/// returns, falling back to the default. Anything else, including a `${`
+/// without a closing brace, is kept as is.
+fn interpolate_env(
+    yaml: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<String, ConfigError> {
+    let mut interpolated = String::with_capacity(yaml.len());
+    let mut rest = yaml;
+
+    while let Some(start) = rest.find("${") {
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+        let reference = &rest[start + 2..start + len];
+        let (name, default) = match reference.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (reference, None),
+        };
+
+        // like in shells, empty values are replaced by the default as well
+        let value = lookup(name)
+            .filter(|value| !value.is_empty() || default.is_none())
+            .or_else(|| default.map(str::to_owned))
+            .ok_or_else(|| ConfigError::MissingEnvVar(name.to_owned()))?;
+
+        interpolated.push_str(&rest[..start]);
+        interpolated.push_str(&value);
+        rest = &rest[start + len + 1..];
+    }
+    interpolated.push_str(rest);
+
+    Ok(interpolated)
+}
+
+fn interpolate_process_env(yaml: &str) -> Result<String, ConfigError> {
+    interpolate_env(yaml, |name| std::env::var(name).ok())
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -17,6 +101,16 @@ pub struct Bucket {
     bucket_name: String,
     access_key: Option<String>,
     secret_key: Option<String>,
+    session_token: Option<String>,
+    #[serde(default, alias = "public")]
+    anonymous: bool,
+    credential_sources: Option<Vec<CredentialSource>>,
+    path_style: Option<bool>,
+    max_upstream_concurrency: Option<usize>,
+    #[serde(default)]
+    upstream_queue_timeout: u64,
+    #[serde(skip)]
+    upstream_permits: Option<Arc<Semaphore>>,
 }
 
 impl Bucket {
@@ -32,14 +126,21 @@ impl Bucket {
         &self.bucket_name
     }
 
+    /// Whether both configure the same bucket, possibly with different
+    /// credentials.
+    pub fn is_same_bucket(&self, other: &Bucket) -> bool {
+        self.endpoint == other.endpoint
+            && self.region == other.region
+            && self.bucket_name == other.bucket_name
+    }
+
     /// Returns the configured access key.
     ///
     /// If no key is configured, it tried to get `AWS_S3_ACCESS_KEY_ID` from the
     /// environment. If that environment variable is not set, [`None`] is returned.
     pub fn access_key(&self) -> Option<String> {
         self.access_key
-            .as_deref()
-            .and_then(|s| Some(s.to_owned()))
+            .clone()
             .or_else(|| std::env::var("AWS_S3_ACCESS_KEY_ID").ok())
     }
 
@@ -49,11 +150,93 @@ impl Bucket {
     /// environment. If that environment variable is not set, [`None`] is returned.
     pub fn secret_key(&self) -> Option<String> {
         self.secret_key
-            .as_deref()
-            .and_then(|s| Some(s.to_owned()))
+            .clone()
             .or_else(|| std::env::var("AWS_S3_SECRET_KEY").ok())
     }
 
+    /// Returns the configured session token, for temporary credentials.
+    ///
+    /// If no token is configured, it tried to get `AWS_SESSION_TOKEN` from the
+    /// environment. If that environment variable is not set, [`None`] is returned.
+    pub fn session_token(&self) -> Option<String> {
+        self.session_token
+            .clone()
+            .or_else(|| std::env::var("AWS_SESSION_TOKEN").ok())
+    }
+
+    /// Whether requests are sent unsigned, for public buckets. Can also be
+    /// configured as `public`.
+    ///
+    /// Configured or environment credentials are ignored in this case.
+    pub fn anonymous(&self) -> bool {
+        self.anonymous
+    }
+
+    /// Returns the semaphore limiting concurrent requests to the bucket to
+    /// `max_upstream_concurrency`, if configured.
+    pub fn upstream_permits(&self) -> Option<&Arc<Semaphore>> {
+        self.upstream_permits.as_ref()
+    }
+
+    /// Returns how long a request waits for one of the
+    /// [`upstream_permits`](Self::upstream_permits) before it's rejected.
+    pub fn upstream_queue_timeout(&self) -> Duration {
+        Duration::from_secs(self.upstream_queue_timeout)
+    }
+
+    /// Returns the sources credentials are looked up from, in order.
+    ///
+    /// If none are configured, the configured keys are used, or the default
+    /// chain of rust-s3 if there are none.
+    pub fn credential_sources(&self) -> Option<&[CredentialSource]> {
+        self.credential_sources.as_deref()
+    }
+
+    fn make_credentials(&self) -> Result<s3::creds::Credentials, ConfigError> {
+        if self.anonymous() {
+            return Ok(s3::creds::Credentials::anonymous().unwrap());
+        }
+
+        let Some(sources) = self.credential_sources() else {
+            // without keys, rust-s3 looks them up from the environment, the
+            // default profile and the instance metadata of EC2 or ECS
+            return Ok(s3::creds::Credentials::new(
+                self.access_key().as_deref(),
+                self.secret_key().as_deref(),
+                None,
+                self.session_token().as_deref(),
+                None,
+            )
+            .unwrap_or_else(|err| {
+                tracing::warn!(
+                    "No credentials found for bucket {}, sending unsigned requests: {err}",
+                    self.bucket_name()
+                );
+                s3::creds::Credentials::anonymous().unwrap()
+            }));
+        };
+
+        first_credentials(sources, |source| source.credentials(self)).map_err(|errors| {
+            ConfigError::MissingCredentials(self.bucket_name().to_owned(), errors.join(", "))
+        })
+    }
+
+    fn initialize(&mut self) {
+        self.upstream_permits = self
+            .max_upstream_concurrency
+            .map(|permits| Arc::new(Semaphore::new(permits)));
+    }
+
+    /// Takes over the permits of `previous` if it's the same bucket with the
+    /// same limit.
+    fn keep_permits(&mut self, previous: &Bucket) {
+        if self.is_same_bucket(previous)
+            && self.max_upstream_concurrency == previous.max_upstream_concurrency
+        {
+            self.upstream_permits = previous.upstream_permits.clone();
+        }
+    }
+
     pub fn make_s3_region(&self) -> Result<s3::region::Region, ConfigError> {
         if let Some(endpoint) = self.endpoint() {
             Ok(s3::Region::Custom {
@@ -65,173 +248,2338 @@ impl Bucket {
         }
     }
 
+    /// Whether the bucket is addressed in the path (`endpoint/bucket/key`)
+    /// instead of the host (`bucket.endpoint/key`), which is the default as
+    /// it's what most S3-compatible servers expect.
+    pub fn path_style(&self) -> bool {
+        self.path_style.unwrap_or(true)
+    }
+
     pub fn make_s3_bucket(&self) -> Result<s3::Bucket, ConfigError> {
-        let credentials = s3::creds::Credentials::new(
-            self.access_key().as_deref(),
-            self.secret_key().as_deref(),
-            None,
-            None,
-            None,
-        )
-        .unwrap();
+        let credentials = self.make_credentials()?;
 
         let mut bucket = s3::Bucket::new(self.bucket_name(), self.make_s3_region()?, credentials)
             .expect("Bucket::new panicked, that shouldn't happen.");
 
-        bucket.set_path_style(); // this should probably be configurable
+        if self.path_style() {
+            bucket.set_path_style();
+        }
 
         Ok(bucket)
     }
 }
 
+/// A source of credentials for a bucket.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CredentialSource {
+    /// The configured `access_key`, `secret_key` and `session_token`, or the
+    /// `AWS_S3_ACCESS_KEY_ID`, `AWS_S3_SECRET_KEY` and `AWS_SESSION_TOKEN`
+    /// environment variables.
+    Static,
+    /// The `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` environment
+    /// variables, with an optional `AWS_SESSION_TOKEN`.
+    Env,
+    /// The default profile in `~/.aws/credentials`.
+    Profile,
+    /// The instance metadata service of EC2 or ECS.
+    InstanceMetadata,
+}
+
+impl CredentialSource {
+    /// Returns the name the source is configured with.
+    fn name(self) -> &'static str {
+        match self {
+            CredentialSource::Static => "static",
+            CredentialSource::Env => "env",
+            CredentialSource::Profile => "profile",
+            CredentialSource::InstanceMetadata => "instance_metadata",
+        }
+    }
+
+    fn credentials(self, bucket: &Bucket) -> Result<s3::creds::Credentials, String> {
+        let credentials = match self {
+            CredentialSource::Static => match (bucket.access_key(), bucket.secret_key()) {
+                (Some(access_key), Some(secret_key)) => s3::creds::Credentials::new(
+                    Some(&access_key),
+                    Some(&secret_key),
+                    None,
+                    bucket.session_token().as_deref(),
+                    None,
+                ),
+                _ => return Err("static: no access_key and secret_key configured".to_owned()),
+            },
+            CredentialSource::Env => s3::creds::Credentials::from_env(),
+            CredentialSource::Profile => s3::creds::Credentials::from_profile(None),
+            CredentialSource::InstanceMetadata => s3::creds::Credentials::from_instance_metadata(),
+        };
+
+        credentials.map_err(|err| format!("{}: {err}", self.name()))
+    }
+}
+
+/// Returns the credentials of the first source `load` succeeds for, or the
+/// errors of all sources.
+fn first_credentials(
+    sources: &[CredentialSource],
+    mut load: impl FnMut(CredentialSource) -> Result<s3::creds::Credentials, String>,
+) -> Result<s3::creds::Credentials, Vec<String>> {
+    let mut errors = Vec::new();
+
+    for &source in sources {
+        match load(source) {
+            Ok(credentials) => {
+                tracing::debug!("Using credentials from {}", source.name());
+                return Ok(credentials);
+            }
+            Err(err) => errors.push(err),
+        }
+    }
+
+    if errors.is_empty() {
+        errors.push("no credential_sources configured".to_owned());
+    }
+
+    Err(errors)
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Cors {
+    allowed_origins: Vec<String>,
+    allowed_methods: Option<Vec<EndpointMethod>>,
+    #[serde(default)]
+    allowed_headers: Vec<String>,
+    max_age: Option<u64>,
+}
+
+impl Cors {
+    /// Returns the value for `Access-Control-Allow-Origin` if `origin` is allowed.
+    ///
+    /// An exact match is echoed back, otherwise a configured `*` allows any origin.
+    pub fn allow_origin<'a>(&self, origin: &'a str) -> Option<&'a str> {
+        if self.allowed_origins.iter().any(|allowed| allowed == origin) {
+            Some(origin)
+        } else if self.allowed_origins.iter().any(|allowed| allowed == "*") {
+            Some("*")
+        } else {
+            None
+        }
+    }
+
+    /// Returns the methods allowed for cross-origin requests, which default to
+    /// the methods enabled for the endpoint.
+    pub fn allowed_methods(&self) -> Option<&[EndpointMethod]> {
+        self.allowed_methods.as_deref()
+    }
+
+    /// Returns the request headers allowed for cross-origin requests, where
+    /// `*` allows any header.
+    pub fn allowed_headers(&self) -> &[String] {
+        &self.allowed_headers
+    }
+
+    /// Returns how long preflight responses may be cached by clients.
+    pub fn max_age(&self) -> Option<Duration> {
+        self.max_age.map(Duration::from_secs)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        for name in self.allowed_headers.iter().filter(|name| *name != "*") {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| ConfigError::InvalidHeaderName(name.to_owned()))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Credentials required for an endpoint with HTTP Basic authentication.
 #[derive(Clone, Debug, Deserialize)]
-pub struct Endpoint {
-    path: String,
-    bucket_path: String,
+pub struct BasicAuth {
+    username: String,
+    password: Option<String>,
+    /// A bcrypt hash of the password, instead of the plaintext.
+    password_hash: Option<String>,
+    #[serde(default = "default_realm")]
+    realm: String,
 }
 
-impl Endpoint {
-    pub fn new(path: String, bucket_path: String) -> Self {
-        Self { path, bucket_path }
+fn default_realm() -> String {
+    "s3-proxy".to_owned()
+}
+
+impl BasicAuth {
+    /// Returns the realm announced in `WWW-Authenticate`.
+    pub fn realm(&self) -> &str {
+        &self.realm
     }
 
-    pub fn path(&self) -> &str {
-        &self.path
+    /// Checks `username` and `password` against the configured credentials.
+    /// Both are checked in constant time, and the password even if the
+    /// username is wrong, so the time taken doesn't reveal which matched.
+    pub fn verify(&self, username: &str, password: &str) -> bool {
+        let username_matches = token_matches(username, &self.username);
+        let password_matches = match (&self.password, &self.password_hash) {
+            (Some(expected), _) => token_matches(password, expected),
+            (None, Some(hash)) => bcrypt::verify(password, hash).unwrap_or(false),
+            (None, None) => false,
+        };
+
+        username_matches & password_matches
     }
 
-    pub fn bucket_path(&self) -> &str {
-        &&self.bucket_path
+    fn validate(&self, endpoint: &str) -> Result<(), ConfigError> {
+        let invalid = |reason| Err(ConfigError::InvalidBasicAuth(endpoint.to_owned(), reason));
+
+        match (&self.password, &self.password_hash) {
+            (Some(_), Some(_)) => return invalid("both password and password_hash are set"),
+            (None, None) => return invalid("password or password_hash is required"),
+            (None, Some(hash)) if hash.parse::<bcrypt::HashParts>().is_err() => {
+                return invalid("password_hash isn't a bcrypt hash")
+            }
+            _ => {}
+        }
+
+        // the realm is sent as a quoted string
+        if self.realm.contains('"') || HeaderValue::from_str(&self.realm).is_err() {
+            return invalid("the realm must be a valid header value without quotes");
+        }
+
+        Ok(())
     }
 }
 
+/// Settings of the administrative endpoints below `/admin/`.
 #[derive(Clone, Debug, Deserialize)]
-pub struct Endpoints(Vec<Endpoint>);
+pub struct Admin {
+    token: String,
+}
 
-impl Endpoints {
-    pub fn from_vec(vec: Vec<Endpoint>) -> Self {
-        let mut endpoints = Self(vec);
-        endpoints.sort_endpoints();
+impl Admin {
+    /// Returns the bearer token required for administrative requests.
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+}
 
-        endpoints
+/// The page served instead of any object during maintenance.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Maintenance {
+    #[serde(default)]
+    enabled: bool,
+    #[serde(default = "default_maintenance_page")]
+    page: String,
+    #[serde(default = "default_retry_after")]
+    retry_after: u64,
+}
+
+fn default_maintenance_page() -> String {
+    "<!DOCTYPE html><title>Maintenance</title><p>We'll be back shortly.</p>".to_owned()
+}
+
+fn default_retry_after() -> u64 {
+    300
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            page: default_maintenance_page(),
+            retry_after: default_retry_after(),
+        }
     }
+}
 
-    fn sort_endpoints(&mut self) {
-        // reverse sort by length, so the longest path comes first
-        self.0.sort_by_key(|key| key.path().len());
-        self.0.reverse();
+impl Maintenance {
+    /// Whether maintenance mode is on at startup. It can be toggled at runtime
+    /// through the admin endpoints.
+    pub fn enabled(&self) -> bool {
+        self.enabled
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = &Endpoint> {
-        self.0.iter()
+    /// Returns the HTML served during maintenance.
+    pub fn page(&self) -> &str {
+        &self.page
+    }
+
+    /// Returns the number of seconds sent in `Retry-After`.
+    pub fn retry_after(&self) -> u64 {
+        self.retry_after
     }
 }
 
+/// Settings of requests to the buckets.
 #[derive(Clone, Debug, Deserialize)]
-pub struct Http {
-    bind: String,
-    port: u16,
+pub struct Upstream {
+    #[serde(default)]
+    retries: u32,
+    #[serde(default = "default_retry_base_delay")]
+    retry_base_delay: u64,
 }
 
-impl Default for Http {
+fn default_retry_base_delay() -> u64 {
+    100
+}
+
+impl Default for Upstream {
     fn default() -> Self {
         Self {
-            bind: "127.0.0.1".to_owned(),
-            port: 8000,
+            retries: 0,
+            retry_base_delay: default_retry_base_delay(),
         }
     }
 }
 
-impl Http {
-    pub fn bind(&self) -> &str {
-        &self.bind
+impl Upstream {
+    /// Returns how often GET and HEAD requests failing with a connection
+    /// error or `5xx` are retried.
+    pub fn retries(&self) -> u32 {
+        self.retries
     }
 
-    pub fn port(&self) -> u16 {
-        self.port
+    /// Returns the delay before the first retry, which doubles with every
+    /// further one.
+    pub fn retry_base_delay(&self) -> Duration {
+        Duration::from_millis(self.retry_base_delay)
+    }
+}
+
+/// Limits the requests of each client IP with a token bucket, which holds up
+/// to `burst` requests and refills with `requests_per_second`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RateLimit {
+    requests_per_second: f64,
+    burst: u32,
+}
+
+impl RateLimit {
+    pub fn requests_per_second(&self) -> f64 {
+        self.requests_per_second
+    }
+
+    pub fn burst(&self) -> u32 {
+        self.burst
     }
 
-    /// Creates a [`SocketAddr`] from the instances [`bind`] and [`port`].
-    pub fn make_socketaddr(&self) -> Result<SocketAddr, ConfigError> {
-        Ok(format!("{}:{}", self.bind(), self.port()).parse()?)
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.requests_per_second > 0.0 && self.burst >= 1 {
+            Ok(())
+        } else {
+            Err(ConfigError::InvalidRateLimit)
+        }
     }
 }
 
+/// Settings of the Prometheus metrics endpoint.
 #[derive(Clone, Debug, Deserialize)]
-pub struct Configuration {
-    bucket: Bucket,
-    endpoints: Endpoints,
-    http: Http,
+pub struct Metrics {
+    #[serde(default)]
+    enabled: bool,
+    #[serde(default = "default_metrics_path")]
+    path: String,
 }
 
-impl Configuration {
-    pub async fn from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
-        let yaml = tokio::fs::read_to_string(path).await?;
+fn default_metrics_path() -> String {
+    "/metrics".to_owned()
+}
 
-        let mut config = serde_yaml::from_str::<Self>(&yaml)?;
-        config.initialize();
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: default_metrics_path(),
+        }
+    }
+}
 
-        Ok(config)
+impl Metrics {
+    pub fn enabled(&self) -> bool {
+        self.enabled
     }
 
-    fn initialize(&mut self) {
-        self.endpoints.sort_endpoints();
+    /// Returns the path the metrics are served at.
+    pub fn path(&self) -> &str {
+        &self.path
     }
+}
+
+/// Settings of compressing text responses for clients accepting it.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Compression {
+    #[serde(default)]
+    enabled: bool,
+    #[serde(default = "default_compression_min_size")]
+    min_size: u64,
+}
+
+fn default_compression_min_size() -> u64 {
+    1024
+}
 
-    pub fn bucket(&self) -> &Bucket {
-        &self.bucket
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_size: default_compression_min_size(),
+        }
     }
+}
 
-    pub fn endpoints(&self) -> &Endpoints {
-        &self.endpoints
+impl Compression {
+    pub fn enabled(&self) -> bool {
+        self.enabled
     }
 
-    pub fn http(&self) -> &Http {
-        &self.http
+    /// Returns the `Content-Length` below which responses aren't compressed.
+    pub fn min_size(&self) -> u64 {
+        self.min_size
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    #[default]
+    Md5,
+    Sha256,
+}
 
-    #[test]
-    fn test_bucket_configuration_with_custom_endpoint() {
-        let conf = Bucket {
-            endpoint: Some("https://s3.fr-par.scw.cloud".to_owned()),
-            region: "fr-par".to_owned(),
-            bucket_name: "test".to_owned(),
-            access_key: None,
-            secret_key: None,
-        };
+/// Layout of buckets which shard objects into prefixes by a hash of their key.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Sharding {
+    #[serde(default)]
+    algorithm: HashAlgorithm,
+    #[serde(default = "default_shard_depth")]
+    depth: usize,
+}
 
-        assert_eq!(conf.endpoint().unwrap(), "https://s3.fr-par.scw.cloud");
-        assert_eq!(conf.region(), "fr-par");
-        assert_eq!(conf.bucket_name(), "test");
+fn default_shard_depth() -> usize {
+    2
+}
 
-        assert!(matches!(
-            conf.make_s3_region().unwrap(),
-            s3::region::Region::Custom { region, endpoint }
-            if &region == "fr-par" && &endpoint == "https://s3.fr-par.scw.cloud"
-        ));
+impl Sharding {
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
     }
 
-    #[test]
-    fn test_bucket_configuration_without_custom_endpoint() {
-        let conf = Bucket {
-            endpoint: None,
-            region: "eu-west-1".to_owned(),
-            bucket_name: "test".to_owned(),
-            access_key: None,
-            secret_key: None,
-        };
+    /// Number of two hex-digit prefix segments, e.g. `2` for `ab/cd/key`.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+}
 
-        assert!(conf.endpoint().is_none());
-        assert_eq!(conf.region(), "eu-west-1");
-        assert_eq!(conf.bucket_name(), "test");
+/// How objects which are only "folder" markers are served.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DirectoryMarkers {
+    /// Serve the marker like any other object.
+    #[default]
+    Serve,
+    /// Respond as if the object didn't exist.
+    NotFound,
+    /// Redirect to the same path with a trailing slash.
+    Redirect,
+}
 
-        assert!(matches!(
-            conf.make_s3_region().unwrap(),
-            s3::region::Region::EuWest1
-        ));
+/// How requests for a prefix without a trailing slash are answered, e.g.
+/// `/docs` when there's no object `docs` but objects below `docs/`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImplicitDirectory {
+    /// Respond as if the object didn't exist.
+    #[default]
+    Off,
+    /// Redirect to the same path with a trailing slash.
+    Redirect,
+    /// Serve the index document below the prefix.
+    ServeIndex,
+}
+
+/// How HEAD requests with a `Range` header are answered.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HeadRange {
+    /// `200 OK` with the full length, ignoring the range.
+    #[default]
+    Full,
+    /// `206 Partial Content` with the `Content-Range` a ranged GET would have.
+    Partial,
+}
+
+/// How `Range` headers with a unit other than `bytes` are answered.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnsupportedRangeUnit {
+    /// Serve the full object, as if there was no `Range` header.
+    #[default]
+    Ignore,
+    /// `400 Bad Request`.
+    Reject,
+}
+
+/// How requests are answered that didn't reach the proxy over HTTPS.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HttpsOnly {
+    /// `301 Moved Permanently` to the same URL with the `https` scheme.
+    #[default]
+    Redirect,
+    /// `403 Forbidden`.
+    Forbid,
+}
+
+/// How an endpoint serves objects.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EndpointMode {
+    /// Stream objects through the proxy.
+    #[default]
+    Proxy,
+    /// Redirect GET requests to a presigned URL of the object, so clients
+    /// download it from the bucket directly.
+    Redirect,
+}
+
+/// A request method an endpoint can be enabled for.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum EndpointMethod {
+    Get,
+    Head,
+    /// Uploads, only enabled without `read_only`.
+    Put,
+    /// Deletions, only enabled with `allow_delete`.
+    Delete,
+}
+
+impl EndpointMethod {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Get => "GET",
+            Self::Head => "HEAD",
+            Self::Put => "PUT",
+            Self::Delete => "DELETE",
+        }
+    }
+}
+
+fn default_methods() -> Vec<EndpointMethod> {
+    vec![EndpointMethod::Get, EndpointMethod::Head]
+}
+
+/// How request paths are recorded in logs.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogPaths {
+    #[default]
+    Full,
+    /// A truncated SHA-256 of the path, for correlating requests without
+    /// revealing keys.
+    Hashed,
+    Omitted,
+}
+
+impl LogPaths {
+    /// Returns the value recorded for `path`.
+    pub fn format(self, path: &str) -> Option<String> {
+        match self {
+            Self::Full => Some(path.to_owned()),
+            Self::Hashed => Some(format!("{:x}", Sha256::digest(path))[..16].to_owned()),
+            Self::Omitted => None,
+        }
+    }
+}
+
+/// Format of the logs, including the access log.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Human readable lines, without an access log.
+    #[default]
+    Text,
+    /// One JSON object per line, with an access log entry per request.
+    Json,
+}
+
+/// Minimum TLS version for connections to the bucket.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+pub enum TlsVersion {
+    #[serde(rename = "1.0")]
+    Tls10,
+    #[serde(rename = "1.1")]
+    Tls11,
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Endpoint {
+    path: String,
+    /// Overrides the global bucket for this endpoint.
+    bucket: Option<Bucket>,
+    bucket_path: Option<String>,
+    cors: Option<Cors>,
+    allowed_referers: Option<Vec<String>>,
+    #[serde(default = "default_true")]
+    allow_empty_referer: bool,
+    #[serde(default)]
+    cache_bust_assets: bool,
+    sharding: Option<Sharding>,
+    #[serde(default)]
+    directory_markers: DirectoryMarkers,
+    #[serde(default)]
+    treat_empty_as_missing: bool,
+    #[serde(default)]
+    lowercase_extension: bool,
+    upstream_connect_timeout: Option<u64>,
+    upstream_idle_timeout: Option<u64>,
+    #[serde(default)]
+    image_variants: bool,
+    #[serde(default)]
+    precompressed: bool,
+    #[serde(default)]
+    tenant_from_subdomain: bool,
+    #[serde(default = "default_methods")]
+    methods: Vec<EndpointMethod>,
+    #[serde(default)]
+    i18n_variants: bool,
+    #[serde(default)]
+    locales: Vec<String>,
+    #[serde(default)]
+    implicit_directory: ImplicitDirectory,
+    index_document: Option<String>,
+    spa_fallback: Option<String>,
+    basic_auth: Option<BasicAuth>,
+    #[serde(default)]
+    mode: EndpointMode,
+    #[serde(default = "default_presign_expiry")]
+    presign_expiry: u64,
+    not_found_redirect: Option<String>,
+    #[serde(default)]
+    not_found_redirect_permanent: bool,
+    #[serde(default)]
+    not_found_redirect_append_path: bool,
+    default_cache_control: Option<String>,
+    fingerprint_pattern: Option<String>,
+    #[serde(skip)]
+    fingerprint_regex: Option<Regex>,
+    max_concurrency: Option<usize>,
+    #[serde(skip)]
+    permits: Option<Arc<Semaphore>>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+const DEFAULT_INDEX_DOCUMENT: &str = "index.html";
+
+fn default_upstream_connect_timeout() -> u64 {
+    30
+}
+
+fn default_presign_expiry() -> u64 {
+    3600
+}
+
+impl Endpoint {
+    #[cfg(test)]
+    pub fn new(path: String, bucket_path: String) -> Self {
+        Self {
+            path,
+            bucket: None,
+            bucket_path: Some(bucket_path),
+            cors: None,
+            allowed_referers: None,
+            allow_empty_referer: true,
+            cache_bust_assets: false,
+            sharding: None,
+            directory_markers: DirectoryMarkers::default(),
+            treat_empty_as_missing: false,
+            lowercase_extension: false,
+            upstream_connect_timeout: None,
+            upstream_idle_timeout: None,
+            image_variants: false,
+            precompressed: false,
+            tenant_from_subdomain: false,
+            methods: default_methods(),
+            i18n_variants: false,
+            locales: Vec::new(),
+            implicit_directory: ImplicitDirectory::default(),
+            index_document: None,
+            spa_fallback: None,
+            basic_auth: None,
+            mode: EndpointMode::default(),
+            presign_expiry: default_presign_expiry(),
+            not_found_redirect: None,
+            not_found_redirect_permanent: false,
+            not_found_redirect_append_path: false,
+            default_cache_control: None,
+            fingerprint_pattern: None,
+            fingerprint_regex: None,
+            max_concurrency: None,
+            permits: None,
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn bucket(&self) -> Option<&Bucket> {
+        self.bucket.as_ref()
+    }
+
+    /// Returns the bucket prefix the endpoint serves, which defaults to the
+    /// endpoint's `path`.
+    pub fn bucket_path(&self) -> &str {
+        self.bucket_path.as_deref().unwrap_or(&self.path)
+    }
+
+    /// Returns the CORS policy overriding the global one for this endpoint.
+    pub fn cors(&self) -> Option<&Cors> {
+        self.cors.as_ref()
+    }
+
+    /// Returns the hash sharding applied to keys of this endpoint.
+    pub fn sharding(&self) -> Option<&Sharding> {
+        self.sharding.as_ref()
+    }
+
+    pub fn directory_markers(&self) -> DirectoryMarkers {
+        self.directory_markers
+    }
+
+    /// Returns the semaphore limiting concurrent upstream requests of this
+    /// endpoint to `max_concurrency`, if configured. Requests wait for a
+    /// permit as long as for one of the bucket's.
+    pub fn permits(&self) -> Option<&Arc<Semaphore>> {
+        self.permits.as_ref()
+    }
+
+    fn initialize(&mut self) {
+        self.permits = self
+            .max_concurrency
+            .map(|permits| Arc::new(Semaphore::new(permits)));
+        // invalid patterns are rejected when validating
+        self.fingerprint_regex = self
+            .fingerprint_pattern
+            .as_deref()
+            .and_then(|pattern| Regex::new(pattern).ok());
+
+        if let Some(bucket) = &mut self.bucket {
+            bucket.initialize();
+        }
+    }
+
+    /// Takes over the permits of `previous` and its bucket if their limits
+    /// are the same.
+    fn keep_permits(&mut self, previous: &Endpoint) {
+        if self.max_concurrency == previous.max_concurrency {
+            self.permits = previous.permits.clone();
+        }
+        if let (Some(bucket), Some(previous)) = (&mut self.bucket, &previous.bucket) {
+            bucket.keep_permits(previous);
+        }
+    }
+
+    /// Returns the `Cache-Control` sent for objects without their own.
+    pub fn default_cache_control(&self) -> Option<HeaderValue> {
+        self.default_cache_control
+            .as_deref()
+            .and_then(|value| HeaderValue::from_str(value).ok())
+    }
+
+    /// Whether the object at `bucket_path` has a content hash in its name
+    /// according to `fingerprint_pattern`, so it never changes.
+    pub fn is_fingerprinted(&self, bucket_path: &str) -> bool {
+        self.fingerprint_regex
+            .as_ref()
+            .is_some_and(|regex| regex.is_match(bucket_path))
+    }
+
+    pub fn implicit_directory(&self) -> ImplicitDirectory {
+        self.implicit_directory
+    }
+
+    /// Returns the name of the object served for paths ending in `/`.
+    pub fn index_document(&self) -> Option<&str> {
+        self.index_document.as_deref()
+    }
+
+    /// Returns the path, relative to the endpoint, of the document served for
+    /// missing objects to clients accepting HTML, e.g. a single-page app's
+    /// `index.html`.
+    pub fn spa_fallback(&self) -> Option<&str> {
+        self.spa_fallback.as_deref()
+    }
+
+    pub fn mode(&self) -> EndpointMode {
+        self.mode
+    }
+
+    /// Returns how long presigned URLs of [`EndpointMode::Redirect`] are valid.
+    pub fn presign_expiry(&self) -> Duration {
+        Duration::from_secs(self.presign_expiry)
+    }
+
+    /// Returns the credentials required for requests to this endpoint.
+    pub fn basic_auth(&self) -> Option<&BasicAuth> {
+        self.basic_auth.as_ref()
+    }
+
+    /// Returns the URL requests for missing objects are redirected to instead
+    /// of answering with `404 Not Found`.
+    pub fn not_found_redirect(&self) -> Option<&str> {
+        self.not_found_redirect.as_deref()
+    }
+
+    /// Whether the redirect to `not_found_redirect` is permanent (`301`)
+    /// instead of temporary (`302`).
+    pub fn not_found_redirect_permanent(&self) -> bool {
+        self.not_found_redirect_permanent
+    }
+
+    /// Whether the request path is appended to `not_found_redirect`.
+    pub fn not_found_redirect_append_path(&self) -> bool {
+        self.not_found_redirect_append_path
+    }
+
+    /// Returns the name of the object served for a prefix with
+    /// [`ImplicitDirectory::ServeIndex`], which defaults to `index.html`.
+    pub fn implicit_index_document(&self) -> &str {
+        self.index_document().unwrap_or(DEFAULT_INDEX_DOCUMENT)
+    }
+
+    /// Whether zero-byte objects are answered with `404 Not Found`, for
+    /// endpoints where empty files indicate errors.
+    pub fn treat_empty_as_missing(&self) -> bool {
+        self.treat_empty_as_missing
+    }
+
+    /// Whether the file extension of keys is lowercased, for buckets where only
+    /// extensions are normalized.
+    pub fn lowercase_extension(&self) -> bool {
+        self.lowercase_extension
+    }
+
+    /// Whether keys are prefixed with the leftmost label of the request's
+    /// `Host`, for serving a tenant per subdomain.
+    pub fn tenant_from_subdomain(&self) -> bool {
+        self.tenant_from_subdomain
+    }
+
+    /// Returns the locales of localized variants like `page.fr.html`, which
+    /// are served instead of `page.html` to clients preferring them.
+    pub fn i18n_locales(&self) -> Option<&[String]> {
+        (self.i18n_variants && !self.locales.is_empty()).then_some(&self.locales[..])
+    }
+
+    /// Returns the request methods the endpoint is enabled for, GET and HEAD
+    /// by default.
+    pub fn methods(&self) -> &[EndpointMethod] {
+        &self.methods
+    }
+
+    /// Whether `foo.jpg.avif` or `foo.jpg.webp` is served instead of `foo.jpg`
+    /// to clients accepting these formats, if it exists.
+    pub fn image_variants(&self) -> bool {
+        self.image_variants
+    }
+
+    /// Whether `app.js.br` or `app.js.gz` is served with its `Content-Encoding`
+    /// instead of `app.js` to clients accepting it, if it exists.
+    pub fn precompressed(&self) -> bool {
+        self.precompressed
+    }
+
+    /// Returns the endpoint's override of
+    /// [`Configuration::upstream_connect_timeout`].
+    pub fn upstream_connect_timeout(&self) -> Option<Duration> {
+        self.upstream_connect_timeout.map(Duration::from_secs)
+    }
+
+    /// Returns the endpoint's override of
+    /// [`Configuration::upstream_idle_timeout`].
+    pub fn upstream_idle_timeout(&self) -> Option<Duration> {
+        self.upstream_idle_timeout.map(Duration::from_secs)
+    }
+
+    /// Whether asset links in HTML documents get a `?v=` query derived from
+    /// the asset's ETag.
+    ///
+    /// This buffers HTML documents and costs an extra HEAD request per asset
+    /// (cached for a short time), so it's off by default.
+    pub fn cache_bust_assets(&self) -> bool {
+        self.cache_bust_assets
+    }
+
+    /// Checks the host of a request's `Referer` or `Origin` against the
+    /// endpoint's `allowed_referers`.
+    ///
+    /// Requests without a referer are allowed unless `allow_empty_referer` is
+    /// disabled. Without an allowlist, every request is allowed.
+    pub fn referer_allowed(&self, referer_host: Option<&str>) -> bool {
+        match (&self.allowed_referers, referer_host) {
+            (None, _) => true,
+            (Some(_), None) => self.allow_empty_referer,
+            (Some(allowed), Some(host)) => allowed
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(host)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(from = "Vec<Endpoint>")]
+pub struct Endpoints(Vec<Endpoint>);
+
+impl From<Vec<Endpoint>> for Endpoints {
+    fn from(vec: Vec<Endpoint>) -> Self {
+        Self::from_vec(vec)
+    }
+}
+
+impl Endpoints {
+    pub fn from_vec(vec: Vec<Endpoint>) -> Self {
+        let mut endpoints = Self(vec);
+        endpoints.sort_endpoints();
+
+        endpoints
+    }
+
+    fn sort_endpoints(&mut self) {
+        // reverse sort by length, so the longest path comes first
+        self.0.sort_by_key(|key| key.path().len());
+        self.0.reverse();
+    }
+
+    /// Adds `other` to the endpoints, keeping them sorted.
+    pub fn extend(&mut self, other: Endpoints) {
+        self.0.extend(other.0);
+        self.sort_endpoints();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Endpoint> {
+        self.0.iter()
+    }
+
+    /// Finds the endpoint with the longest path matching `request_path`.
+    pub fn find(&self, request_path: &str) -> Option<&Endpoint> {
+        self.iter()
+            .find(|endpoint| request_path.starts_with(endpoint.path()))
+    }
+}
+
+/// The certificate and private key to terminate TLS with, both PEM encoded.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Tls {
+    cert_path: Option<PathBuf>,
+    key_path: Option<PathBuf>,
+}
+
+impl Tls {
+    fn validate(&self) -> Result<(), ConfigError> {
+        match (&self.cert_path, &self.key_path) {
+            (Some(_), Some(_)) => Ok(()),
+            (None, _) => Err(ConfigError::IncompleteTls("cert_path")),
+            (_, None) => Err(ConfigError::IncompleteTls("key_path")),
+        }
+    }
+
+    pub fn cert_path(&self) -> &Path {
+        self.cert_path.as_deref().expect("both paths are validated")
+    }
+
+    pub fn key_path(&self) -> &Path {
+        self.key_path.as_deref().expect("both paths are validated")
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Http {
+    /// Addresses, or `unix:` followed by the path of a unix socket. A single
+    /// one can be configured without a list.
+    #[serde(deserialize_with = "deserialize_one_or_many")]
+    bind: Vec<String>,
+    #[serde(default = "default_port")]
+    port: u16,
+    #[serde(default)]
+    tcp_nodelay: bool,
+    tcp_keepalive: Option<u64>,
+    #[serde(default = "default_shutdown_timeout")]
+    shutdown_timeout: u64,
+    tls: Option<Tls>,
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
+fn default_readiness_probe_timeout() -> u64 {
+    5
+}
+
+fn default_coalesce_max_size() -> u64 {
+    1024 * 1024
+}
+
+fn default_max_upload_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_port() -> u16 {
+    8000
+}
+
+fn deserialize_one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(one) => vec![one],
+        OneOrMany::Many(many) => many,
+    })
+}
+
+/// An address a server listens on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl std::fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Default for Http {
+    fn default() -> Self {
+        Self {
+            bind: vec!["127.0.0.1".to_owned()],
+            port: default_port(),
+            tcp_nodelay: false,
+            tcp_keepalive: None,
+            shutdown_timeout: default_shutdown_timeout(),
+            tls: None,
+        }
+    }
+}
+
+impl Http {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Returns the addresses to listen on, a server is started for each.
+    ///
+    /// Binds of the form `unix:<path>` are unix sockets. Other binds are IP
+    /// addresses listening on [`port`](Self::port), which IPv6 addresses can
+    /// be given for with or without brackets, or addresses with their own port
+    /// like `[::1]:8080`.
+    pub fn listen_addrs(&self) -> Result<Vec<ListenAddr>, ConfigError> {
+        self.bind
+            .iter()
+            .map(|bind| match bind.strip_prefix("unix:") {
+                Some(path) => Ok(ListenAddr::Unix(PathBuf::from(path))),
+                None => Ok(ListenAddr::Tcp(self.make_socketaddr(bind)?)),
+            })
+            .collect()
+    }
+
+    /// Whether Nagle's algorithm is disabled on accepted connections.
+    pub fn tcp_nodelay(&self) -> bool {
+        self.tcp_nodelay
+    }
+
+    /// Returns the TCP keepalive interval for accepted connections, if enabled.
+    pub fn tcp_keepalive(&self) -> Option<Duration> {
+        self.tcp_keepalive.map(Duration::from_secs)
+    }
+
+    /// Returns the TLS settings, if connections are served over HTTPS.
+    pub fn tls(&self) -> Option<&Tls> {
+        self.tls.as_ref()
+    }
+
+    /// Returns how long in-flight requests are waited for on shutdown before
+    /// their connections are closed.
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout)
+    }
+
+    /// Creates a [`SocketAddr`] from `bind` and the instance's [`port`],
+    /// unless `bind` includes a port itself.
+    ///
+    /// [`port`]: Self::port
+    fn make_socketaddr(&self, bind: &str) -> Result<SocketAddr, ConfigError> {
+        if let Ok(addr) = bind.parse() {
+            return Ok(addr);
+        }
+
+        let ip = bind
+            .strip_prefix('[')
+            .and_then(|ip| ip.strip_suffix(']'))
+            .unwrap_or(bind);
+
+        Ok(SocketAddr::new(ip.parse()?, self.port()))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Configuration {
+    bucket: Option<Bucket>,
+    #[serde(default)]
+    endpoints: Endpoints,
+    /// Additional endpoints, read from a file relative to the configuration file.
+    endpoints_file: Option<PathBuf>,
+    http: Http,
+    cors: Option<Cors>,
+    #[serde(default)]
+    startup_probe_endpoints: bool,
+    #[serde(default)]
+    strict_range: bool,
+    #[serde(default)]
+    unsupported_range_unit: UnsupportedRangeUnit,
+    #[serde(default)]
+    rewrite_location: bool,
+    #[serde(default)]
+    strip_response_headers: Vec<String>,
+    #[serde(default)]
+    head_range: HeadRange,
+    #[serde(default = "default_upstream_connect_timeout")]
+    upstream_connect_timeout: u64,
+    upstream_idle_timeout: Option<u64>,
+    #[serde(default)]
+    min_upstream_tls_version: TlsVersion,
+    #[serde(default)]
+    access_denied_as_not_found: bool,
+    timing_allow_origin: Option<String>,
+    #[serde(default)]
+    validate_if_range: bool,
+    admin: Option<Admin>,
+    #[serde(default)]
+    content_location: bool,
+    #[serde(default)]
+    log_paths: LogPaths,
+    #[serde(default)]
+    log_format: LogFormat,
+    /// Includes the health and metrics endpoints in the access log.
+    #[serde(default)]
+    log_probes: bool,
+    #[serde(default)]
+    reject_get_body: bool,
+    /// Guards against pointing the proxy at an unintended bucket.
+    allowed_buckets: Option<Vec<String>>,
+    #[serde(default)]
+    verbose_errors: bool,
+    #[serde(default)]
+    maintenance: Maintenance,
+    #[serde(default)]
+    coalesce_requests: bool,
+    #[serde(default = "default_coalesce_max_size")]
+    coalesce_max_size: u64,
+    service_unavailable_retry_after: Option<u64>,
+    /// Disables PUT for all endpoints, regardless of their methods.
+    #[serde(default = "default_true")]
+    read_only: bool,
+    /// Enables DELETE for endpoints with it in their methods.
+    #[serde(default)]
+    allow_delete: bool,
+    #[serde(default = "default_max_upload_size")]
+    max_upload_size: u64,
+    max_path_depth: Option<usize>,
+    #[serde(default)]
+    ensure_content_length: bool,
+    #[serde(default)]
+    guess_content_type: bool,
+    readiness_probe_key: Option<String>,
+    #[serde(default = "default_readiness_probe_timeout")]
+    readiness_probe_timeout: u64,
+    bucket_health_interval: Option<u64>,
+    #[serde(default)]
+    upstream: Upstream,
+    rate_limit: Option<RateLimit>,
+    #[serde(default)]
+    metrics: Metrics,
+    #[serde(default)]
+    compression: Compression,
+    https_only: Option<HttpsOnly>,
+    /// Addresses of reverse proxies whose `X-Forwarded-Proto` is trusted.
+    #[serde(default)]
+    trusted_proxies: Vec<IpAddr>,
+    #[serde(skip)]
+    coalescer: Coalescer,
+    #[serde(skip)]
+    metrics_registry: Registry,
+}
+
+impl Configuration {
+    pub async fn from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let yaml = tokio::fs::read_to_string(path).await?;
+        let mut config = serde_yaml::from_str::<Self>(&interpolate_process_env(&yaml)?)?;
+
+        if let Some(endpoints_file) = &config.endpoints_file {
+            let endpoints_file = path
+                .parent()
+                .unwrap_or_else(|| Path::new(""))
+                .join(endpoints_file);
+            let yaml = tokio::fs::read_to_string(&endpoints_file)
+                .await
+                .with_context(|| format!("Couldn't read {}", endpoints_file.display()))?;
+
+            config
+                .endpoints
+                .extend(serde_yaml::from_str(&interpolate_process_env(&yaml)?)?);
+        }
+
+        Ok(config.finish()?)
+    }
+
+    #[cfg(test)]
+    pub fn from_yaml(yaml: &str) -> anyhow::Result<Self> {
+        Ok(serde_yaml::from_str::<Self>(yaml)?.finish()?)
+    }
+
+    fn finish(mut self) -> Result<Self, ConfigError> {
+        self.initialize();
+        self.validate()?;
+
+        Ok(self)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(allowed_buckets) = &self.allowed_buckets {
+            let buckets = self.bucket.iter().chain(
+                self.endpoints
+                    .iter()
+                    .filter_map(|endpoint| endpoint.bucket()),
+            );
+
+            for bucket in buckets {
+                let bucket_name = bucket.bucket_name();
+
+                if !allowed_buckets.iter().any(|allowed| allowed == bucket_name) {
+                    return Err(ConfigError::BucketNotAllowed(bucket_name.to_owned()));
+                }
+            }
+        }
+
+        if self.bucket.is_none() && self.endpoints.iter().next().is_none() {
+            return Err(ConfigError::MissingBucket("/".to_owned()));
+        }
+
+        let mut paths = HashSet::new();
+        for endpoint in self.endpoints.iter() {
+            if !paths.insert(endpoint.path()) {
+                return Err(ConfigError::DuplicateEndpoint(endpoint.path().to_owned()));
+            }
+
+            if self.endpoint_bucket(endpoint).is_none() {
+                return Err(ConfigError::MissingBucket(endpoint.path().to_owned()));
+            }
+
+            if let Some(value) = &endpoint.default_cache_control {
+                HeaderValue::from_str(value)
+                    .map_err(|_| ConfigError::InvalidHeaderValue(value.to_owned()))?;
+            }
+
+            if let Some(cors) = &endpoint.cors {
+                cors.validate()?;
+            }
+
+            if let Some(basic_auth) = &endpoint.basic_auth {
+                basic_auth.validate(endpoint.path())?;
+            }
+
+            // the longest expiry S3 accepts for presigned URLs is 7 days
+            if !(1..=604_800).contains(&endpoint.presign_expiry) {
+                return Err(ConfigError::InvalidPresignExpiry(
+                    endpoint.path().to_owned(),
+                ));
+            }
+
+            if let Some(url) = &endpoint.not_found_redirect {
+                HeaderValue::from_str(url)
+                    .map_err(|_| ConfigError::InvalidHeaderValue(url.to_owned()))?;
+            }
+
+            if let Some(pattern) = &endpoint.fingerprint_pattern {
+                Regex::new(pattern).map_err(|err| {
+                    ConfigError::InvalidFingerprintPattern(pattern.to_owned(), err)
+                })?;
+            }
+
+            // locales end up in object keys and `Content-Language`
+            for locale in &endpoint.locales {
+                if locale.is_empty()
+                    || !locale
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+                {
+                    return Err(ConfigError::InvalidLocale(locale.to_owned()));
+                }
+            }
+        }
+
+        for name in &self.strip_response_headers {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| ConfigError::InvalidHeaderName(name.to_owned()))?;
+        }
+
+        if let Some(value) = &self.timing_allow_origin {
+            HeaderValue::from_str(value)
+                .map_err(|_| ConfigError::InvalidHeaderValue(value.to_owned()))?;
+        }
+
+        if let Some(cors) = &self.cors {
+            cors.validate()?;
+        }
+
+        if let Some(rate_limit) = &self.rate_limit {
+            rate_limit.validate()?;
+        }
+
+        let listen_addrs = self.http.listen_addrs()?;
+        if listen_addrs.is_empty() {
+            return Err(ConfigError::MissingBind);
+        }
+
+        if let Some(tls) = &self.http.tls {
+            tls.validate()?;
+
+            if listen_addrs
+                .iter()
+                .any(|addr| matches!(addr, ListenAddr::Unix(_)))
+            {
+                return Err(ConfigError::UnixSocketTls);
+            }
+        }
+
+        // the probes and admin routes are served next to the metrics
+        let metrics_path = self.metrics.path();
+        if self.metrics.enabled
+            && (!metrics_path.starts_with('/')
+                || ["/healthz", "/healthz/buckets", "/readyz"].contains(&metrics_path)
+                || metrics_path.starts_with("/admin/"))
+        {
+            return Err(ConfigError::InvalidMetricsPath(metrics_path.to_owned()));
+        }
+
+        Ok(())
+    }
+
+    fn initialize(&mut self) {
+        self.endpoints.sort_endpoints();
+
+        if let Some(bucket) = &mut self.bucket {
+            bucket.initialize();
+        }
+        for endpoint in self.endpoints.0.iter_mut() {
+            endpoint.initialize();
+        }
+    }
+
+    /// Returns the bucket of all endpoints that don't have their own.
+    pub fn bucket(&self) -> Option<&Bucket> {
+        self.bucket.as_ref()
+    }
+
+    /// Returns the bucket `endpoint` is served from, which always exists for
+    /// endpoints of a validated configuration.
+    pub fn endpoint_bucket<'a>(&'a self, endpoint: &'a Endpoint) -> Option<&'a Bucket> {
+        endpoint.bucket().or(self.bucket())
+    }
+
+    pub fn endpoints(&self) -> &Endpoints {
+        &self.endpoints
+    }
+
+    pub fn http(&self) -> &Http {
+        &self.http
+    }
+
+    /// Returns the settings of the administrative endpoints, which are only
+    /// served if configured.
+    pub fn admin(&self) -> Option<&Admin> {
+        self.admin.as_ref()
+    }
+
+    /// Returns the global CORS policy, used for endpoints without their own.
+    pub fn cors(&self) -> Option<&Cors> {
+        self.cors.as_ref()
+    }
+
+    /// Whether malformed or multi-range `Range` headers are rejected with
+    /// `400 Bad Request` instead of serving the full object.
+    pub fn strict_range(&self) -> bool {
+        self.strict_range
+    }
+
+    /// Returns how ranges in units other than `bytes` are answered, unless
+    /// they're rejected by [`strict_range`](Self::strict_range) anyway.
+    pub fn unsupported_range_unit(&self) -> UnsupportedRangeUnit {
+        self.unsupported_range_unit
+    }
+
+    pub fn head_range(&self) -> HeadRange {
+        self.head_range
+    }
+
+    /// Whether `AccessDenied` errors of the bucket are answered with `404 Not
+    /// Found` instead of `403 Forbidden`.
+    ///
+    /// S3 answers requests for missing keys with `AccessDenied` if the
+    /// credentials aren't allowed to list the bucket, so this hides whether an
+    /// object exists.
+    pub fn access_denied_as_not_found(&self) -> bool {
+        self.access_denied_as_not_found
+    }
+
+    /// Returns the `Timing-Allow-Origin` header added to responses, allowing
+    /// other origins to read Resource Timing details of proxied objects.
+    pub fn timing_allow_origin(&self) -> Option<HeaderValue> {
+        self.timing_allow_origin
+            .as_deref()
+            .and_then(|value| HeaderValue::from_str(value).ok())
+    }
+
+    /// Whether ranged GETs with `If-Range` are checked against the object, so a
+    /// client whose copy is outdated gets the whole object instead of a part
+    /// of the new one.
+    pub fn validate_if_range(&self) -> bool {
+        self.validate_if_range
+    }
+
+    /// Whether responses serving a different resource than the requested one,
+    /// like image variants, carry a `Content-Location` pointing at it.
+    pub fn content_location(&self) -> bool {
+        self.content_location
+    }
+
+    /// Whether GET requests with a body are rejected with `400 Bad Request`
+    /// instead of ignoring the body.
+    pub fn reject_get_body(&self) -> bool {
+        self.reject_get_body
+    }
+
+    /// Whether upstream error details are included in responses, which may
+    /// reveal the bucket's host and name to clients.
+    pub fn verbose_errors(&self) -> bool {
+        self.verbose_errors
+    }
+
+    pub fn maintenance(&self) -> &Maintenance {
+        &self.maintenance
+    }
+
+    /// Returns the `Retry-After` seconds for 503 responses other than the
+    /// maintenance page, which has its own.
+    pub fn service_unavailable_retry_after(&self) -> Option<u64> {
+        self.service_unavailable_retry_after
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn allow_delete(&self) -> bool {
+        self.allow_delete
+    }
+
+    /// Returns the maximum size of a PUT body in bytes. Uploads are buffered
+    /// in memory before they're sent to the bucket.
+    pub fn max_upload_size(&self) -> u64 {
+        self.max_upload_size
+    }
+
+    /// Whether the length of full objects is asked for with a HEAD request
+    /// when the bucket's GET response doesn't include it.
+    pub fn ensure_content_length(&self) -> bool {
+        self.ensure_content_length
+    }
+
+    /// Whether objects without a specific `Content-Type` get one guessed from
+    /// the extension of their key.
+    pub fn guess_content_type(&self) -> bool {
+        self.guess_content_type
+    }
+
+    /// Returns the key looked up by `/readyz`, which lists the bucket otherwise.
+    pub fn readiness_probe_key(&self) -> Option<&str> {
+        self.readiness_probe_key.as_deref()
+    }
+
+    /// Returns how long a bucket has to answer a probe before it's considered
+    /// unreachable.
+    pub fn readiness_probe_timeout(&self) -> Duration {
+        Duration::from_secs(self.readiness_probe_timeout)
+    }
+
+    /// Returns how often the buckets are checked in the background for
+    /// `/healthz/buckets`, which is only served if this is set.
+    pub fn bucket_health_interval(&self) -> Option<Duration> {
+        self.bucket_health_interval.map(Duration::from_secs)
+    }
+
+    /// Returns the maximum number of segments in a request path.
+    pub fn max_path_depth(&self) -> Option<usize> {
+        self.max_path_depth
+    }
+
+    /// Returns how plaintext requests are answered, if only HTTPS is allowed.
+    pub fn https_only(&self) -> Option<HttpsOnly> {
+        self.https_only
+    }
+
+    pub fn is_trusted_proxy(&self, addr: IpAddr) -> bool {
+        self.trusted_proxies.contains(&addr)
+    }
+
+    pub fn compression(&self) -> &Compression {
+        &self.compression
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn upstream(&self) -> &Upstream {
+        &self.upstream
+    }
+
+    pub fn rate_limit(&self) -> Option<&RateLimit> {
+        self.rate_limit.as_ref()
+    }
+
+    /// Returns the registry requests are recorded in, if metrics are enabled.
+    pub fn metrics_registry(&self) -> Option<&Registry> {
+        self.metrics.enabled.then_some(&self.metrics_registry)
+    }
+
+    /// Returns the registry regardless of whether metrics are enabled, as it
+    /// always counts the requests in flight.
+    pub fn registry(&self) -> &Registry {
+        &self.metrics_registry
+    }
+
+    /// Keeps recording requests in the registry of `previous`, so reloading
+    /// the configuration doesn't reset the metrics.
+    pub fn keep_metrics(&mut self, previous: &Configuration) {
+        self.metrics_registry = previous.metrics_registry.clone();
+    }
+
+    /// Takes over the concurrency limits of buckets and endpoints unchanged
+    /// since `previous`, so requests still holding permits count against them
+    /// after a reload. Changed limits start out with all permits available.
+    pub fn keep_permits(&mut self, previous: &Configuration) {
+        if let (Some(bucket), Some(previous)) = (&mut self.bucket, &previous.bucket) {
+            bucket.keep_permits(previous);
+        }
+        for endpoint in self.endpoints.0.iter_mut() {
+            if let Some(previous) = previous
+                .endpoints
+                .iter()
+                .find(|previous| previous.path() == endpoint.path())
+            {
+                endpoint.keep_permits(previous);
+            }
+        }
+    }
+
+    /// Returns the coalescer for upstream requests, if enabled.
+    pub fn coalescer(&self) -> Option<&Coalescer> {
+        self.coalesce_requests.then_some(&self.coalescer)
+    }
+
+    /// Returns the largest response in bytes shared by coalesced requests.
+    pub fn coalesce_max_size(&self) -> u64 {
+        self.coalesce_max_size
+    }
+
+    pub fn log_paths(&self) -> LogPaths {
+        self.log_paths
+    }
+
+    pub fn log_format(&self) -> LogFormat {
+        self.log_format
+    }
+
+    pub fn log_probes(&self) -> bool {
+        self.log_probes
+    }
+
+    pub fn min_upstream_tls_version(&self) -> TlsVersion {
+        self.min_upstream_tls_version
+    }
+
+    /// Returns how long to wait for the response headers of the bucket before
+    /// answering with `504 Gateway Timeout`, 30 seconds by default.
+    pub fn upstream_connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.upstream_connect_timeout)
+    }
+
+    /// Returns how long to wait for the next chunk of a response body before
+    /// aborting the download. Unlike the connect timeout, this doesn't limit
+    /// the total duration of a download.
+    pub fn upstream_idle_timeout(&self) -> Option<Duration> {
+        self.upstream_idle_timeout.map(Duration::from_secs)
+    }
+
+    /// Returns the upstream headers which are removed before responding.
+    pub fn strip_response_headers(&self) -> impl Iterator<Item = HeaderName> + '_ {
+        self.strip_response_headers
+            .iter()
+            .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok())
+    }
+
+    /// Whether upstream redirects are forwarded, with absolute `Location`s
+    /// pointing at the bucket rewritten to the endpoint serving the key.
+    pub fn rewrite_location(&self) -> bool {
+        self.rewrite_location
+    }
+
+    /// Whether every endpoint's `bucket_path` should be checked for objects on startup.
+    pub fn startup_probe_endpoints(&self) -> bool {
+        self.startup_probe_endpoints
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_configuration_with_custom_endpoint() {
+        let conf = Bucket {
+            endpoint: Some("https://s3.fr-par.scw.cloud".to_owned()),
+            region: "fr-par".to_owned(),
+            bucket_name: "test".to_owned(),
+            access_key: None,
+            secret_key: None,
+            session_token: None,
+            anonymous: false,
+            credential_sources: None,
+            path_style: None,
+            max_upstream_concurrency: None,
+            upstream_queue_timeout: 0,
+            upstream_permits: None,
+        };
+
+        assert_eq!(conf.endpoint().unwrap(), "https://s3.fr-par.scw.cloud");
+        assert_eq!(conf.region(), "fr-par");
+        assert_eq!(conf.bucket_name(), "test");
+
+        assert!(matches!(
+            conf.make_s3_region().unwrap(),
+            s3::region::Region::Custom { region, endpoint }
+            if &region == "fr-par" && &endpoint == "https://s3.fr-par.scw.cloud"
+        ));
+    }
+
+    #[test]
+    fn test_bucket_configuration_without_custom_endpoint() {
+        let conf = Bucket {
+            endpoint: None,
+            region: "eu-west-1".to_owned(),
+            bucket_name: "test".to_owned(),
+            access_key: None,
+            secret_key: None,
+            session_token: None,
+            anonymous: false,
+            credential_sources: None,
+            path_style: None,
+            max_upstream_concurrency: None,
+            upstream_queue_timeout: 0,
+            upstream_permits: None,
+        };
+
+        assert!(conf.endpoint().is_none());
+        assert_eq!(conf.region(), "eu-west-1");
+        assert_eq!(conf.bucket_name(), "test");
+
+        assert!(matches!(
+            conf.make_s3_region().unwrap(),
+            s3::region::Region::EuWest1
+        ));
+    }
+
+    const MINIMAL_CONFIG: &str = r#"
+bucket:
+  region: "eu-west-1"
+  bucket_name: "test"
+http:
+  bind: "127.0.0.1"
+  port: 8000
+endpoints: []
+"#;
+
+    #[test]
+    fn test_anonymous_bucket() {
+        let config = Configuration::from_yaml(&MINIMAL_CONFIG.replace(
+            "bucket_name: \"test\"",
+            "bucket_name: \"test\"\n  access_key: \"ignored\"\n  anonymous: true",
+        ))
+        .unwrap();
+
+        assert!(config.bucket().unwrap().anonymous());
+
+        let bucket = config.bucket().unwrap().make_s3_bucket().unwrap();
+
+        assert!(bucket.access_key().is_none());
+        assert!(bucket.secret_key().is_none());
+
+        let config = Configuration::from_yaml(&MINIMAL_CONFIG.replace(
+            "bucket_name: \"test\"",
+            "bucket_name: \"test\"\n  access_key: \"ignored\"\n  secret_key: \"ignored\"\n  public: true",
+        ))
+        .unwrap();
+
+        assert!(config.bucket().unwrap().anonymous());
+
+        let bucket = config.bucket().unwrap().make_s3_bucket().unwrap();
+
+        assert!(bucket.access_key().is_none());
+        assert!(bucket.secret_key().is_none());
+    }
+
+    #[test]
+    fn test_first_credentials() {
+        let profile =
+            s3::creds::Credentials::new(Some("profile"), Some("secret"), None, None, None).unwrap();
+        let mut tried = Vec::new();
+        let credentials = first_credentials(
+            &[
+                CredentialSource::Static,
+                CredentialSource::Profile,
+                CredentialSource::InstanceMetadata,
+            ],
+            |source| {
+                tried.push(source);
+                match source {
+                    CredentialSource::Profile => Ok(profile.clone()),
+                    _ => Err(format!("{}: unavailable", source.name())),
+                }
+            },
+        )
+        .unwrap();
+
+        assert_eq!(credentials.access_key.as_deref(), Some("profile"));
+        // sources after the first usable one aren't tried
+        assert_eq!(tried, [CredentialSource::Static, CredentialSource::Profile]);
+
+        let errors = first_credentials(
+            &[CredentialSource::Env, CredentialSource::Static],
+            |source| Err(format!("{}: unavailable", source.name())),
+        )
+        .unwrap_err();
+        assert_eq!(errors, ["env: unavailable", "static: unavailable"]);
+    }
+
+    #[test]
+    fn test_credential_sources() {
+        let with_bucket_options = |options: &str| {
+            Configuration::from_yaml(&MINIMAL_CONFIG.replace(
+                "bucket_name: \"test\"",
+                &format!("bucket_name: \"test\"\n{options}"),
+            ))
+            .unwrap()
+        };
+
+        let config = with_bucket_options(
+            "  access_key: \"key\"\n  secret_key: \"secret\"\n  credential_sources: [static, env]",
+        );
+        let bucket = config.bucket().unwrap();
+        assert_eq!(
+            bucket.credential_sources(),
+            Some(&[CredentialSource::Static, CredentialSource::Env][..])
+        );
+        let s3_bucket = bucket.make_s3_bucket().unwrap();
+        assert_eq!(s3_bucket.access_key().as_deref(), Some("key"));
+        assert_eq!(s3_bucket.secret_key().as_deref(), Some("secret"));
+
+        let config = with_bucket_options("  access_key: \"key\"\n  credential_sources: [static]");
+        let err = config.bucket().unwrap().make_s3_bucket().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "No usable credentials for bucket test: static: no access_key and secret_key configured"
+        );
+    }
+
+    #[test]
+    fn test_session_token() {
+        let with_bucket_options = |options: &str| {
+            Configuration::from_yaml(&MINIMAL_CONFIG.replace(
+                "bucket_name: \"test\"",
+                &format!("bucket_name: \"test\"\n  access_key: \"key\"\n  secret_key: \"secret\"\n{options}"),
+            ))
+            .unwrap()
+        };
+        let session_token = |config: &Configuration| {
+            let s3_bucket = config.bucket().unwrap().make_s3_bucket().unwrap();
+            s3_bucket.session_token().map(str::to_owned)
+        };
+
+        // the only test touching AWS_SESSION_TOKEN, so it runs sequentially
+        std::env::remove_var("AWS_SESSION_TOKEN");
+        assert_eq!(session_token(&with_bucket_options("")), None);
+
+        std::env::set_var("AWS_SESSION_TOKEN", "env-token");
+        assert_eq!(
+            session_token(&with_bucket_options("")).as_deref(),
+            Some("env-token")
+        );
+        // configured tokens take precedence over the environment
+        let config = with_bucket_options("  session_token: \"token\"");
+        assert_eq!(session_token(&config).as_deref(), Some("token"));
+        let config =
+            with_bucket_options("  session_token: \"token\"\n  credential_sources: [static]");
+        assert_eq!(session_token(&config).as_deref(), Some("token"));
+        std::env::remove_var("AWS_SESSION_TOKEN");
+    }
+
+    #[test]
+    fn test_path_style() {
+        let bucket_yaml = |options: &str| {
+            MINIMAL_CONFIG.replace(
+                "bucket_name: \"test\"",
+                &format!("bucket_name: \"test\"\n  anonymous: true\n{options}"),
+            )
+        };
+
+        let config = Configuration::from_yaml(&bucket_yaml("")).unwrap();
+        assert!(config
+            .bucket()
+            .unwrap()
+            .make_s3_bucket()
+            .unwrap()
+            .is_path_style());
+
+        let config = Configuration::from_yaml(&bucket_yaml("  path_style: false")).unwrap();
+        assert!(!config.bucket().unwrap().path_style());
+
+        let bucket = config.bucket().unwrap().make_s3_bucket().unwrap();
+        assert!(bucket.is_subdomain_style());
+        assert!(bucket.host().starts_with("test."));
+    }
+
+    #[test]
+    fn test_endpoint_bucket() {
+        let endpoints = r#"
+endpoints:
+  - path: "/"
+  - path: "/other/"
+    bucket:
+      region: "eu-west-1"
+      bucket_name: "other"
+"#;
+        let config =
+            Configuration::from_yaml(&MINIMAL_CONFIG.replace("endpoints: []\n", endpoints))
+                .unwrap();
+
+        let default = config.endpoints().find("/a.txt").unwrap();
+        let other = config.endpoints().find("/other/a.txt").unwrap();
+        assert_eq!(
+            config.endpoint_bucket(default).unwrap().bucket_name(),
+            "test"
+        );
+        assert_eq!(
+            config.endpoint_bucket(other).unwrap().bucket_name(),
+            "other"
+        );
+
+        // without a global bucket, every endpoint needs its own
+        let without_global = MINIMAL_CONFIG
+            .replace("endpoints: []\n", endpoints)
+            .replace(
+                "bucket:\n  region: \"eu-west-1\"\n  bucket_name: \"test\"\n",
+                "",
+            );
+        let err = Configuration::from_yaml(&without_global).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ConfigError>(),
+            Some(ConfigError::MissingBucket(path)) if path == "/"
+        ));
+
+        let err = Configuration::from_yaml(
+            &format!("{MINIMAL_CONFIG}allowed_buckets: [\"test\"]\n")
+                .replace("endpoints: []\n", endpoints),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ConfigError>(),
+            Some(ConfigError::BucketNotAllowed(bucket)) if bucket == "other"
+        ));
+    }
+
+    #[test]
+    fn test_allowed_buckets() {
+        let config = Configuration::from_yaml(&format!(
+            "{MINIMAL_CONFIG}allowed_buckets: [\"a\", \"test\"]\n"
+        ));
+        assert!(config.is_ok());
+
+        let err = Configuration::from_yaml(&format!("{MINIMAL_CONFIG}allowed_buckets: [\"a\"]\n"))
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ConfigError>(),
+            Some(ConfigError::BucketNotAllowed(bucket)) if bucket == "test"
+        ));
+    }
+
+    #[test]
+    fn test_min_upstream_tls_version() {
+        let config = Configuration::from_yaml(MINIMAL_CONFIG).unwrap();
+        assert_eq!(config.min_upstream_tls_version(), TlsVersion::Tls12);
+
+        let config =
+            Configuration::from_yaml(&format!("{MINIMAL_CONFIG}min_upstream_tls_version: 1.1\n"))
+                .unwrap();
+        assert_eq!(config.min_upstream_tls_version(), TlsVersion::Tls11);
+
+        assert!(Configuration::from_yaml(&format!(
+            "{MINIMAL_CONFIG}min_upstream_tls_version: 1.5\n"
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_strip_response_headers_validation() {
+        let config = Configuration::from_yaml(&format!(
+            "{MINIMAL_CONFIG}strip_response_headers: [\"X-Amz-Request-Id\"]\n"
+        ))
+        .unwrap();
+
+        assert_eq!(
+            config.strip_response_headers().collect::<Vec<_>>(),
+            vec![HeaderName::from_static("x-amz-request-id")]
+        );
+
+        let err = Configuration::from_yaml(&format!(
+            "{MINIMAL_CONFIG}strip_response_headers: [\"not a header\"]\n"
+        ))
+        .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<ConfigError>(),
+            Some(ConfigError::InvalidHeaderName(name)) if name == "not a header"
+        ));
+    }
+
+    #[test]
+    fn test_endpoint_referer_allowed() {
+        let mut endpoint = Endpoint::new("/".to_owned(), "/".to_owned());
+
+        assert!(endpoint.referer_allowed(Some("evil.example.org")));
+
+        endpoint.allowed_referers = Some(vec!["example.com".to_owned()]);
+
+        assert!(endpoint.referer_allowed(Some("example.com")));
+        assert!(endpoint.referer_allowed(Some("EXAMPLE.com")));
+        assert!(!endpoint.referer_allowed(Some("evil.example.org")));
+        assert!(endpoint.referer_allowed(None));
+
+        endpoint.allow_empty_referer = false;
+
+        assert!(!endpoint.referer_allowed(None));
+    }
+
+    #[test]
+    fn test_cors_allow_origin() {
+        let cors = Cors {
+            allowed_origins: vec!["https://example.com".to_owned()],
+            ..Cors::default()
+        };
+
+        assert_eq!(
+            cors.allow_origin("https://example.com"),
+            Some("https://example.com")
+        );
+        assert_eq!(cors.allow_origin("https://example.org"), None);
+
+        let cors = Cors {
+            allowed_origins: vec!["*".to_owned()],
+            ..Cors::default()
+        };
+
+        assert_eq!(cors.allow_origin("https://example.org"), Some("*"));
+    }
+
+    /// Writes `files` into a fresh directory below the system's temp dir.
+    fn write_config_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("s3-proxy-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("conf.d")).unwrap();
+
+        for (file, contents) in files {
+            std::fs::write(dir.join(file), contents).unwrap();
+        }
+
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_endpoints_file() {
+        let main = MINIMAL_CONFIG.replace(
+            "endpoints: []",
+            "endpoints:\n  - path: \"/media/\"\n    bucket_path: \"/media/\"\nendpoints_file: \"conf.d/endpoints.yaml\"",
+        );
+        let dir = write_config_dir(
+            "endpoints-file",
+            &[
+                ("s3-proxy.yaml", &main),
+                (
+                    "conf.d/endpoints.yaml",
+                    "- path: \"/media/pdfs/\"\n  bucket_path: \"/pdfs/\"\n",
+                ),
+            ],
+        );
+
+        let config = Configuration::from_file(dir.join("s3-proxy.yaml"))
+            .await
+            .unwrap();
+
+        let paths = config
+            .endpoints()
+            .iter()
+            .map(|endpoint| endpoint.path())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, vec!["/media/pdfs/", "/media/"]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_endpoints_file_duplicate_path() {
+        let main = MINIMAL_CONFIG.replace(
+            "endpoints: []",
+            "endpoints:\n  - path: \"/media/\"\n    bucket_path: \"/media/\"\nendpoints_file: \"conf.d/endpoints.yaml\"",
+        );
+        let dir = write_config_dir(
+            "endpoints-file-duplicate",
+            &[
+                ("s3-proxy.yaml", &main),
+                (
+                    "conf.d/endpoints.yaml",
+                    "- path: \"/media/\"\n  bucket_path: \"/other/\"\n",
+                ),
+            ],
+        );
+
+        let err = Configuration::from_file(dir.join("s3-proxy.yaml"))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<ConfigError>(),
+            Some(ConfigError::DuplicateEndpoint(path)) if path == "/media/"
+        ));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_incomplete_tls() {
+        let with_tls = |tls: &str| {
+            Configuration::from_yaml(
+                &MINIMAL_CONFIG.replace("  port: 8000", &format!("  port: 8000\n  tls:\n{tls}")),
+            )
+        };
+
+        let config = with_tls("    cert_path: \"cert.pem\"\n    key_path: \"key.pem\"").unwrap();
+        let tls = config.http().tls().unwrap();
+        assert_eq!(tls.cert_path(), Path::new("cert.pem"));
+        assert_eq!(tls.key_path(), Path::new("key.pem"));
+
+        let err = with_tls("    cert_path: \"cert.pem\"").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "TLS requires both cert_path and key_path, key_path is missing"
+        );
+        let err = with_tls("    key_path: \"key.pem\"").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ConfigError>(),
+            Some(ConfigError::IncompleteTls("cert_path"))
+        ));
+    }
+
+    #[test]
+    fn test_unix_socket() {
+        let http = |http: &str| {
+            Configuration::from_yaml(
+                &MINIMAL_CONFIG.replace("  bind: \"127.0.0.1\"\n  port: 8000", http),
+            )
+        };
+
+        let config = http("  bind: \"unix:/run/s3-proxy.sock\"").unwrap();
+        assert_eq!(
+            config.http().listen_addrs().unwrap(),
+            [ListenAddr::Unix(PathBuf::from("/run/s3-proxy.sock"))]
+        );
+
+        let err = http(
+            "  bind: \"unix:/run/s3-proxy.sock\"\n  tls:\n    cert_path: \"cert.pem\"\n    key_path: \"key.pem\"",
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ConfigError>(),
+            Some(ConfigError::UnixSocketTls)
+        ));
+    }
+
+    #[test]
+    fn test_listen_addrs() {
+        let listen_addrs = |http: &str| {
+            Configuration::from_yaml(
+                &MINIMAL_CONFIG.replace("  bind: \"127.0.0.1\"\n  port: 8000", http),
+            )
+            .and_then(|config| Ok(config.http().listen_addrs()?))
+        };
+        let tcp = |addr: &str| ListenAddr::Tcp(addr.parse().unwrap());
+
+        assert_eq!(
+            listen_addrs("  bind: \"127.0.0.1\"\n  port: 8000").unwrap(),
+            [tcp("127.0.0.1:8000")]
+        );
+        assert_eq!(
+            listen_addrs("  bind: \"::1\"\n  port: 8000").unwrap(),
+            [tcp("[::1]:8000")]
+        );
+        assert_eq!(
+            listen_addrs("  bind: \"[::]\"\n  port: 8000").unwrap(),
+            [tcp("[::]:8000")]
+        );
+        assert_eq!(
+            listen_addrs("  bind: [\"0.0.0.0\", \"::\", \"[::1]:9000\", \"unix:/run/s3-proxy.sock\"]\n  port: 8000")
+                .unwrap(),
+            [
+                tcp("0.0.0.0:8000"),
+                tcp("[::]:8000"),
+                tcp("[::1]:9000"),
+                ListenAddr::Unix(PathBuf::from("/run/s3-proxy.sock"))
+            ]
+        );
+
+        let err = listen_addrs("  bind: \"[::1\"").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ConfigError>(),
+            Some(ConfigError::AddrParseError(_))
+        ));
+        let err = listen_addrs("  bind: []").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ConfigError>(),
+            Some(ConfigError::MissingBind)
+        ));
+    }
+
+    #[test]
+    fn test_fingerprint_pattern() {
+        let with_pattern = |pattern: &str| {
+            Configuration::from_yaml(&MINIMAL_CONFIG.replace(
+                "endpoints: []",
+                &format!("endpoints:\n  - path: \"/\"\n    fingerprint_pattern: '{pattern}'"),
+            ))
+        };
+
+        let config = with_pattern(r"\.[0-9a-f]{4,}\.(js|css)$").unwrap();
+        let endpoint = config.endpoints().find("/").unwrap();
+        assert!(endpoint.is_fingerprinted("/app.3f9a.js"));
+        assert!(!endpoint.is_fingerprinted("/app.js"));
+
+        let err = with_pattern("app.(js").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ConfigError>(),
+            Some(ConfigError::InvalidFingerprintPattern(pattern, _)) if pattern == "app.(js"
+        ));
+    }
+
+    #[test]
+    fn test_invalid_basic_auth() {
+        let with_basic_auth = |basic_auth: &str| {
+            Configuration::from_yaml(&MINIMAL_CONFIG.replace(
+                "endpoints: []",
+                &format!("endpoints:\n  - path: \"/\"\n    basic_auth:\n{basic_auth}"),
+            ))
+        };
+
+        assert!(with_basic_auth("      username: \"a\"\n      password: \"b\"").is_ok());
+
+        for (basic_auth, reason) in [
+            (
+                "      username: \"a\"",
+                "password or password_hash is required",
+            ),
+            (
+                "      username: \"a\"\n      password: \"b\"\n      password_hash: \"c\"",
+                "both password and password_hash are set",
+            ),
+            (
+                "      username: \"a\"\n      password_hash: \"b\"",
+                "password_hash isn't a bcrypt hash",
+            ),
+            (
+                "      username: \"a\"\n      password: \"b\"\n      realm: 'a \"b\"'",
+                "the realm must be a valid header value without quotes",
+            ),
+        ] {
+            let err = with_basic_auth(basic_auth).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("Invalid basic_auth of endpoint /: {reason}")
+            );
+        }
+    }
+
+    #[test]
+    fn test_basic_auth_verify() {
+        let basic_auth: BasicAuth =
+            serde_yaml::from_str("username: \"user\"\npassword: \"secret\"").unwrap();
+
+        assert!(basic_auth.verify("user", "secret"));
+        assert!(!basic_auth.verify("user", "secreT"));
+        assert!(!basic_auth.verify("user", "secret2"));
+        assert!(!basic_auth.verify("other", "secret"));
+        assert!(!basic_auth.verify("", ""));
+    }
+
+    #[test]
+    fn test_upstream_connect_timeout() {
+        let config = Configuration::from_yaml(MINIMAL_CONFIG).unwrap();
+        assert_eq!(config.upstream_connect_timeout(), Duration::from_secs(30));
+
+        let config =
+            Configuration::from_yaml(&format!("upstream_connect_timeout: 5\n{MINIMAL_CONFIG}"))
+                .unwrap();
+        assert_eq!(config.upstream_connect_timeout(), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_keep_permits() {
+        let config = |bucket_limit: usize, endpoint_limit: usize| {
+            Configuration::from_yaml(&format!(
+                r#"
+bucket:
+  region: "eu-west-1"
+  bucket_name: "test"
+  max_upstream_concurrency: {bucket_limit}
+http:
+  bind: "127.0.0.1"
+  port: 8000
+endpoints:
+  - path: "/"
+    bucket_path: "/"
+    max_concurrency: {endpoint_limit}
+"#
+            ))
+            .unwrap()
+        };
+        let bucket_permits =
+            |config: &Configuration| config.bucket().unwrap().upstream_permits().unwrap().clone();
+        let endpoint_permits = |config: &Configuration| {
+            config
+                .endpoints()
+                .find("/")
+                .unwrap()
+                .permits()
+                .unwrap()
+                .clone()
+        };
+
+        let previous = config(4, 2);
+
+        let mut unchanged = config(4, 2);
+        unchanged.keep_permits(&previous);
+        assert!(Arc::ptr_eq(
+            &bucket_permits(&unchanged),
+            &bucket_permits(&previous)
+        ));
+        assert!(Arc::ptr_eq(
+            &endpoint_permits(&unchanged),
+            &endpoint_permits(&previous)
+        ));
+
+        let mut changed = config(8, 2);
+        changed.keep_permits(&previous);
+        assert!(!Arc::ptr_eq(
+            &bucket_permits(&changed),
+            &bucket_permits(&previous)
+        ));
+        assert_eq!(bucket_permits(&changed).available_permits(), 8);
+        assert!(Arc::ptr_eq(
+            &endpoint_permits(&changed),
+            &endpoint_permits(&previous)
+        ));
+    }
+
+    #[test]
+    fn test_interpolate_env() {
+        let lookup = |name: &str| match name {
+            "BUCKET" => Some("media".to_owned()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+
+        assert_eq!(
+            interpolate_env("bucket_name: \"${BUCKET}-${BUCKET}\"", lookup).unwrap(),
+            "bucket_name: \"media-media\""
+        );
+        assert_eq!(
+            interpolate_env("region: ${REGION:-eu-west-1}", lookup).unwrap(),
+            "region: eu-west-1"
+        );
+        assert_eq!(
+            interpolate_env("x: ${BUCKET:-other} ${EMPTY:-other}", lookup).unwrap(),
+            "x: media other"
+        );
+        assert_eq!(interpolate_env("x: ${EMPTY}", lookup).unwrap(), "x: ");
+        assert_eq!(
+            interpolate_env("price: $5, ${unterminated", lookup).unwrap(),
+            "price: $5, ${unterminated"
+        );
+        assert!(matches!(
+            interpolate_env("port: ${PORT}", lookup),
+            Err(ConfigError::MissingEnvVar(name)) if name == "PORT"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_from_file_interpolates_env() {
+        let main = MINIMAL_CONFIG.replace(
+            "bucket_name: \"test\"",
+            "bucket_name: \"${S3PROXY_TEST_UNSET_BUCKET:-from-default}\"",
+        );
+        let dir = write_config_dir("interpolate-env", &[("s3-proxy.yaml", &main)]);
+
+        let config = Configuration::from_file(dir.join("s3-proxy.yaml"))
+            .await
+            .unwrap();
+        assert_eq!(config.bucket().unwrap().bucket_name(), "from-default");
+
+        let main = MINIMAL_CONFIG.replace("\"test\"", "\"${S3PROXY_TEST_UNSET_BUCKET}\"");
+        std::fs::write(dir.join("s3-proxy.yaml"), main).unwrap();
+
+        let err = Configuration::from_file(dir.join("s3-proxy.yaml"))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Environment variable S3PROXY_TEST_UNSET_BUCKET is not set"
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
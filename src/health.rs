@@ -0,0 +1,236 @@
+//! Liveness and readiness probes, e.g. for Kubernetes.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    extract::Extension,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use s3::{command::Command, request::Reqwest, request_trait::Request, Bucket};
+use serde::Serialize;
+
+use crate::{buckets::Buckets, config::Configuration, reload::LiveConfig};
+
+/// Answers as long as the server is running, without asking the bucket.
+pub async fn healthz() -> Response {
+    (StatusCode::OK, "OK").into_response()
+}
+
+/// Checks that `bucket` answers within `timeout`, by looking up `probe_key` if
+/// configured or listing a single object otherwise.
+async fn bucket_reachable(bucket: &Bucket, probe_key: Option<&str>, timeout: Duration) -> bool {
+    let request = match probe_key {
+        Some(probe_key) => Reqwest::new(bucket, probe_key, Command::HeadObject),
+        None => Reqwest::new(
+            bucket,
+            "/",
+            Command::ListObjectsV2 {
+                prefix: String::new(),
+                delimiter: None,
+                continuation_token: None,
+                start_after: None,
+                max_keys: Some(1),
+            },
+        ),
+    };
+
+    match tokio::time::timeout(timeout, request.response()).await {
+        Ok(Ok(response)) => response.status().is_success(),
+        Ok(Err(err)) => {
+            tracing::warn!("Readiness probe failed: {err}");
+            false
+        }
+        Err(_) => {
+            tracing::warn!("Readiness probe got no response within {timeout:?}");
+            false
+        }
+    }
+}
+
+/// Identifies `bucket` by its URL, as buckets of different endpoints can
+/// have the same name. The URL is only used internally, the results are
+/// served by the labels of [`Buckets::labeled`].
+fn health_key(bucket: &Bucket) -> String {
+    bucket.url()
+}
+
+/// The outcome of the last background check of a bucket.
+#[derive(Clone, Debug, Serialize)]
+struct BucketStatus {
+    /// `ok` or `error`.
+    status: &'static str,
+    /// Seconds since the Unix epoch.
+    last_check: u64,
+}
+
+/// The status of every bucket of the last check, keyed by [`health_key`].
+#[derive(Clone, Default)]
+pub struct BucketHealth(Arc<Mutex<BTreeMap<String, BucketStatus>>>);
+
+impl BucketHealth {
+    fn record(&self, key: &str, reachable: bool, checked_at: SystemTime) {
+        let status = BucketStatus {
+            status: if reachable { "ok" } else { "error" },
+            last_check: checked_at
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+        };
+
+        self.0.lock().unwrap().insert(key.to_owned(), status);
+    }
+
+    /// Drops the status of buckets not in `keys`, e.g. removed by a reload.
+    fn retain(&self, keys: &BTreeSet<String>) {
+        self.0.lock().unwrap().retain(|key, _| keys.contains(key));
+    }
+}
+
+/// Checks every bucket of the current configuration like [`readyz`] each
+/// `interval`, recording the results in `health`.
+pub async fn check_buckets(live: LiveConfig, health: BucketHealth, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        let loaded = live.load();
+        let config = &loaded.config;
+        let mut checked = BTreeSet::new();
+
+        for bucket in loaded.buckets.iter() {
+            let reachable = bucket_reachable(
+                bucket,
+                config.readiness_probe_key(),
+                config.readiness_probe_timeout(),
+            )
+            .await;
+            let key = health_key(bucket);
+            health.record(&key, reachable, SystemTime::now());
+            checked.insert(key);
+        }
+        health.retain(&checked);
+    }
+}
+
+/// Answers with the status of each bucket as of its last background check,
+/// keyed by its label. Buckets that weren't checked yet are left out.
+pub async fn bucket_health(
+    Extension(health): Extension<BucketHealth>,
+    Extension(buckets): Extension<Buckets>,
+) -> Response {
+    let statuses = health.0.lock().unwrap();
+    let statuses = buckets
+        .labeled()
+        .filter_map(|(label, bucket)| Some((label, statuses.get(&health_key(bucket))?.clone())))
+        .collect::<BTreeMap<_, _>>();
+
+    Json(statuses).into_response()
+}
+
+/// Answers with `200 OK` if all buckets are reachable, `503` otherwise.
+pub async fn readyz(
+    Extension(buckets): Extension<Buckets>,
+    Extension(config): Extension<Configuration>,
+) -> Response {
+    for bucket in buckets.iter() {
+        if !bucket_reachable(
+            bucket,
+            config.readiness_probe_key(),
+            config.readiness_probe_timeout(),
+        )
+        .await
+        {
+            return (StatusCode::SERVICE_UNAVAILABLE, "Bucket unreachable").into_response();
+        }
+    }
+
+    (StatusCode::OK, "OK").into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_health_key() {
+        let bucket = |endpoint: &str| {
+            Bucket::new(
+                "media",
+                s3::Region::Custom {
+                    region: "us-east-1".to_owned(),
+                    endpoint: endpoint.to_owned(),
+                },
+                s3::creds::Credentials::anonymous().unwrap(),
+            )
+            .unwrap()
+            .with_path_style()
+        };
+
+        assert_ne!(
+            health_key(&bucket("https://s3.example.com")),
+            health_key(&bucket("https://s3.example.org"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bucket_health() {
+        let config = Configuration::from_yaml(
+            r#"
+bucket:
+  endpoint: "https://s3.example.com"
+  region: "us-east-1"
+  bucket_name: "media"
+  anonymous: true
+http:
+  bind: "127.0.0.1"
+  port: 8000
+endpoints:
+  - path: "/"
+  - path: "/assets/"
+    bucket:
+      endpoint: "https://s3.example.com"
+      region: "us-east-1"
+      bucket_name: "assets"
+      anonymous: true
+"#,
+        )
+        .unwrap();
+        let buckets = Buckets::new(&config).unwrap();
+        let key = |label: &str| {
+            let (_, bucket) = buckets.labeled().find(|(l, _)| *l == label).unwrap();
+            health_key(bucket)
+        };
+
+        let health = BucketHealth::default();
+        let checked_at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        health.record(&key("default"), true, checked_at);
+        health.record(&key("/assets/"), false, checked_at);
+        // only the last check counts
+        health.record(&key("default"), false, checked_at);
+        health.record(&key("default"), true, checked_at + Duration::from_secs(30));
+        health.record("removed", true, checked_at);
+        health.retain(&[key("default"), key("/assets/")].into());
+        assert_eq!(health.0.lock().unwrap().len(), 2);
+
+        let response = bucket_health(Extension(health), Extension(buckets)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // keyed by label, without revealing the bucket URLs
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "/assets/": {"status": "error", "last_check": 1_700_000_000},
+                "default": {"status": "ok", "last_check": 1_700_000_030},
+            })
+        );
+    }
+}
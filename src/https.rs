@@ -0,0 +1,118 @@
+//! Enforcement of HTTPS for requests arriving through a TLS-terminating proxy.
+
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{connect_info::Connected, ConnectInfo},
+    http::{header, HeaderMap, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::config::{Configuration, HttpsOnly};
+
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+
+/// Marks requests of connections the proxy terminated TLS for itself.
+#[derive(Clone, Copy)]
+pub struct DirectTls;
+
+/// Connection info of requests over a unix socket. Only local processes the
+/// socket's permissions allow can connect, so they're trusted like
+/// `trusted_proxies`.
+#[cfg(unix)]
+#[derive(Clone, Copy)]
+pub struct UnixSocket;
+
+#[cfg(unix)]
+impl Connected<&tokio::net::UnixStream> for UnixSocket {
+    fn connect_info(_target: &tokio::net::UnixStream) -> Self {
+        Self
+    }
+}
+
+/// Whether a proxy the client connected to claims `https` in
+/// `X-Forwarded-Proto`, if it's `trusted`. Of a list of protocols the first one
+/// is used, as it was added by the proxy closest to the client.
+fn is_forwarded_https(trusted: bool, headers: &HeaderMap) -> bool {
+    trusted
+        && headers
+            .get(X_FORWARDED_PROTO)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .is_some_and(|proto| proto.trim().eq_ignore_ascii_case("https"))
+}
+
+/// Whether `request` arrived over a unix socket, see [`UnixSocket`].
+#[cfg(unix)]
+pub fn is_unix_socket<B>(request: &Request<B>) -> bool {
+    request
+        .extensions()
+        .get::<ConnectInfo<UnixSocket>>()
+        .is_some()
+}
+
+#[cfg(not(unix))]
+pub fn is_unix_socket<B>(_request: &Request<B>) -> bool {
+    false
+}
+
+fn make_https_redirect_response<B>(request: &Request<B>) -> Response {
+    let location = request
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| {
+            let path = request
+                .uri()
+                .path_and_query()
+                .map_or("/", |path| path.as_str());
+
+            HeaderValue::from_str(&format!("https://{host}{path}")).ok()
+        });
+
+    match location {
+        Some(location) => (
+            StatusCode::MOVED_PERMANENTLY,
+            [(header::LOCATION, location)],
+        )
+            .into_response(),
+        // without a host there's nothing to redirect to
+        None => (StatusCode::FORBIDDEN, "HTTPS required").into_response(),
+    }
+}
+
+/// Answers requests that didn't arrive over HTTPS according to `https_only`.
+///
+/// The health probes are exempt, as they're usually polled directly. Expects
+/// the [`Configuration`] in the request's extensions, so it has to be layered
+/// inside of it.
+pub async fn require_https<B>(request: Request<B>, next: Next<B>) -> Response {
+    let Some(config) = request.extensions().get::<Configuration>() else {
+        return next.run(request).await;
+    };
+    let Some(action) = config.https_only() else {
+        return next.run(request).await;
+    };
+
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| *addr);
+    let trusted =
+        peer.is_some_and(|peer| config.is_trusted_proxy(peer.ip())) || is_unix_socket(&request);
+
+    if matches!(
+        request.uri().path(),
+        "/healthz" | "/healthz/buckets" | "/readyz"
+    ) || request.extensions().get::<DirectTls>().is_some()
+        || is_forwarded_https(trusted, request.headers())
+    {
+        return next.run(request).await;
+    }
+
+    match action {
+        HttpsOnly::Redirect => make_https_redirect_response(&request),
+        HttpsOnly::Forbid => (StatusCode::FORBIDDEN, "HTTPS required").into_response(),
+    }
+}
@@ -0,0 +1,87 @@
+//! Negotiation of localized object variants, e.g. `page.fr.html` for
+//! `page.html` requested with `Accept-Language: fr`.
+
+/// Returns the locale of `locales` best matching `accept_language`.
+///
+/// A language range matches a locale if either is a prefix of the other at a
+/// subtag boundary, so `fr-CA` matches `fr` and `fr` matches `fr-CA`. Ranges
+/// are tried by quality, in order of appearance for equal ones.
+pub fn negotiate_locale<'a>(accept_language: &str, locales: &'a [String]) -> Option<&'a str> {
+    let mut ranges = accept_language
+        .split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';').map(str::trim);
+            let tag = params.next().filter(|tag| !tag.is_empty() && *tag != "*")?;
+            let quality = params
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())?;
+
+            (quality > 0.0).then_some((tag, quality))
+        })
+        .collect::<Vec<_>>();
+    // stable, so ranges of equal quality keep their order
+    ranges.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+    ranges.into_iter().find_map(|(tag, _)| {
+        locales
+            .iter()
+            .find(|locale| is_subtag_prefix(locale, tag) || is_subtag_prefix(tag, locale))
+            .map(String::as_str)
+    })
+}
+
+/// Whether `prefix` equals `tag` or is followed by a `-` in it, ignoring case.
+fn is_subtag_prefix(prefix: &str, tag: &str) -> bool {
+    tag.len() >= prefix.len()
+        && tag.is_char_boundary(prefix.len())
+        && tag[..prefix.len()].eq_ignore_ascii_case(prefix)
+        && matches!(tag.as_bytes().get(prefix.len()), None | Some(b'-'))
+}
+
+/// Inserts `locale` before the extension of `path`'s last segment, e.g.
+/// `/docs/page.fr.html` for `/docs/page.html`.
+pub fn localized_path(path: &str, locale: &str) -> String {
+    let segment_start = path.rfind('/').map_or(0, |i| i + 1);
+
+    match path[segment_start..].rfind('.') {
+        Some(dot) if dot > 0 => {
+            let (stem, extension) = path.split_at(segment_start + dot);
+            format!("{stem}.{locale}{extension}")
+        }
+        _ => format!("{path}.{locale}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_locale() {
+        let locales = vec!["de".to_owned(), "fr".to_owned(), "pt-BR".to_owned()];
+
+        assert_eq!(negotiate_locale("fr", &locales), Some("fr"));
+        assert_eq!(negotiate_locale("fr-CA, en;q=0.8", &locales), Some("fr"));
+        assert_eq!(
+            negotiate_locale("en, de;q=0.5, fr;q=0.9", &locales),
+            Some("fr")
+        );
+        assert_eq!(negotiate_locale("pt", &locales), Some("pt-BR"));
+        assert_eq!(negotiate_locale("FR", &locales), Some("fr"));
+        assert_eq!(negotiate_locale("de;q=0, en", &locales), None);
+        assert_eq!(negotiate_locale("frx, *", &locales), None);
+    }
+
+    #[test]
+    fn test_localized_path() {
+        assert_eq!(
+            localized_path("/docs/page.html", "fr"),
+            "/docs/page.fr.html"
+        );
+        assert_eq!(
+            localized_path("/docs.v2/README", "fr"),
+            "/docs.v2/README.fr"
+        );
+        assert_eq!(localized_path("/.profile", "de"), "/.profile.de");
+    }
+}
@@ -1,234 +1,5939 @@
-use std::ops::Bound;
+use std::{
+    borrow::Cow,
+    ops::Bound,
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
 
+use access_log::BucketPath;
+use admin::{MaintenanceMode, StartTime};
 use axum::{
-    body::StreamBody,
-    extract::{Extension, Path},
-    headers::{HeaderName, Range},
-    http::{header, HeaderMap, StatusCode},
-    response::IntoResponse,
-    routing::get,
-    Router, TypedHeader,
+    body::{self, Body, Full, StreamBody},
+    extract::{Extension, Path, RawBody},
+    handler::Handler,
+    headers::{authorization::Basic, Authorization, HeaderMapExt, HeaderName, Range},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode, Uri},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{any, get, put},
+    BoxError, Router, TypedHeader,
 };
-use config::{Configuration, Endpoints};
+use axum_server::{tls_rustls::RustlsConfig, AddrIncomingConfig};
+use buckets::Buckets;
+use cache_bust::EtagCache;
+use config::{
+    BasicAuth, Configuration, Cors, DirectoryMarkers, Endpoint, EndpointMethod, EndpointMode,
+    Endpoints, HashAlgorithm, HeadRange, Http, ImplicitDirectory, ListenAddr, LogFormat, Sharding,
+    TlsVersion, UnsupportedRangeUnit,
+};
+use health::BucketHealth;
+use hyper::{body::HttpBody, server::conn::AddrIncoming};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use rate_limit::RateLimiter;
+use reload::LiveConfig;
 use s3::{command::Command, request::Reqwest, request_trait::Request, Bucket};
+use sha2::Digest;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_native_tls::native_tls;
+use tokio_stream::{Stream, StreamExt};
 
+mod access_log;
+mod admin;
+mod buckets;
+mod cache_bust;
+mod coalesce;
+mod compression;
 mod config;
+mod health;
+mod https;
+mod i18n;
+mod metrics;
+#[cfg(test)]
+mod mock_s3;
+mod rate_limit;
+mod reload;
+mod shutdown;
 
-#[tracing::instrument]
-fn get_bucket_path(request_path: &str, endpoints: &Endpoints) -> Option<String> {
-    let endpoint = endpoints
-        .iter()
-        .find(|endpoint| request_path.starts_with(endpoint.path()));
+/// Computes the shard prefix for `key`, e.g. `ab/cd/` for a depth of 2.
+fn shard_prefix(key: &str, sharding: &Sharding) -> String {
+    let hash = match sharding.algorithm() {
+        HashAlgorithm::Md5 => format!("{:x}", md5::compute(key)),
+        HashAlgorithm::Sha256 => format!("{:x}", sha2::Sha256::digest(key)),
+    };
 
-    tracing::trace!("Found endpoint for request path: {:?}", endpoint);
+    hash.as_bytes()
+        .chunks(2)
+        .take(sharding.depth())
+        .map(|chunk| format!("{}/", String::from_utf8_lossy(chunk)))
+        .collect()
+}
 
-    if let Some(sub_path) = request_path.strip_prefix(endpoint?.path()) {
-        Some(format!(
-            "{}/{}",
-            endpoint?.bucket_path().trim_end_matches('/'),
-            sub_path.trim_start_matches('/')
-        ))
+/// Lowercases the extension of the last segment of `key`, e.g. `a/Photo.JPG`
+/// becomes `a/Photo.jpg`. Dotfiles like `.Profile` have no extension.
+fn lowercase_extension(key: &str) -> String {
+    let segment_start = key.rfind('/').map_or(0, |i| i + 1);
+
+    match key[segment_start..].rfind('.') {
+        Some(dot) if dot > 0 => {
+            let extension_start = segment_start + dot + 1;
+            format!(
+                "{}{}",
+                &key[..extension_start],
+                key[extension_start..].to_lowercase()
+            )
+        }
+        _ => key.to_owned(),
+    }
+}
+
+/// Extracts the tenant from the leftmost label of `host`, e.g. `tenant1` for
+/// `tenant1.example.com:8000`. Only labels of ASCII alphanumerics and dashes
+/// are accepted, so a tenant can't escape its prefix.
+fn tenant_from_host(host: &str) -> Option<String> {
+    let (label, _domain) = host.split_once('.')?;
+
+    let valid = !label.is_empty()
+        && label
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-');
+
+    valid.then(|| label.to_ascii_lowercase())
+}
+
+/// Builds the raw object key for `request_path`, which has to be percent-decoded
+/// exactly once. The key is encoded by rust-s3 for the upstream request, so
+/// characters like `+` or `&` are passed through as they are.
+///
+/// Returns [`None`] for paths with `..` segments, which the upstream request's
+/// URL would resolve, escaping the endpoint's `bucket_path`.
+// the request path and host are part of the request's span, if configured
+#[tracing::instrument(skip(request_path, host))]
+fn get_bucket_path(request_path: &str, host: Option<&str>, endpoint: &Endpoint) -> Option<String> {
+    let sub_path = request_path.strip_prefix(endpoint.path())?;
+
+    let tenant = if endpoint.tenant_from_subdomain() {
+        format!("{}/", tenant_from_host(host?)?)
     } else {
-        None
+        String::new()
+    };
+
+    let key = sub_path.trim_start_matches('/');
+    if key.split('/').any(|segment| segment == "..") {
+        return None;
     }
+
+    let key = if endpoint.lowercase_extension() {
+        lowercase_extension(key)
+    } else {
+        key.to_owned()
+    };
+    let prefix = endpoint
+        .sharding()
+        .map(|sharding| shard_prefix(&key, sharding))
+        .unwrap_or_default();
+
+    Some(format!(
+        "{}/{tenant}{prefix}{key}",
+        endpoint.bucket_path().trim_end_matches('/'),
+    ))
+}
+
+const TIMING_ALLOW_ORIGIN: HeaderName = HeaderName::from_static("timing-allow-origin");
+
+/// Connection-specific headers which must never be forwarded by a proxy
+/// ([RFC 7230, section 6.1](https://www.rfc-editor.org/rfc/rfc7230#section-6.1)).
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+fn is_hop_by_hop(header: &HeaderName, source: &HeaderMap) -> bool {
+    // headers listed in `Connection` are hop-by-hop as well
+    HOP_BY_HOP_HEADERS.contains(&header.as_str())
+        || source
+            .get_all(header::CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|name| name.trim().eq_ignore_ascii_case(header.as_str()))
 }
 
 fn copy_headers(destination: &mut HeaderMap, source: &HeaderMap, headers: &[HeaderName]) {
     for header in headers {
+        if is_hop_by_hop(header, source) {
+            continue;
+        }
+
         source
             .get(header)
             .and_then(|value| destination.insert(header, value.to_owned()));
     }
 }
 
-fn s3_range_for_header(range: Range) -> Option<(u64, Option<u64>)> {
+/// Returns the unit of a raw `Range` header value. [`Range`] only parses
+/// `bytes`, other units never reach [`s3_range_for_header`].
+fn range_unit(value: &HeaderValue) -> Option<&str> {
+    let (unit, _ranges) = value.to_str().ok()?.split_once('=')?;
+
+    Some(unit.trim())
+}
+
+/// Checks that a raw `Range` header value is a syntactically valid set of byte
+/// ranges, since [`Range`] silently skips specs it can't parse.
+fn range_is_well_formed(value: &str) -> bool {
+    let specs = match value.strip_prefix("bytes=") {
+        Some(specs) => specs,
+        None => return false,
+    };
+
+    specs.split(',').all(|spec| {
+        let bound = |s: &str| s.is_empty() || s.bytes().all(|b| b.is_ascii_digit());
+
+        match spec.trim().split_once('-') {
+            Some(("", "")) | None => false,
+            Some((start, end)) if bound(start) && bound(end) => {
+                match (start.parse::<u64>(), end.parse::<u64>()) {
+                    (Ok(start), Ok(end)) => start <= end,
+                    _ => true,
+                }
+            }
+            Some(_) => false,
+        }
+    })
+}
+
+/// A single byte range requested by a client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ByteRange {
+    /// `bytes=start-end`, both inclusive.
+    FromTo(u64, u64),
+    /// `bytes=start-`, up to the end of the object.
+    From(u64),
+    /// `bytes=-length`, the last `length` bytes of the object.
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// Returns the inclusive start and end within an object of `length`
+    /// bytes, or [`None`] if the range lies entirely outside the object or
+    /// ends before it starts.
+    fn resolve(self, length: u64) -> Option<(u64, u64)> {
+        let last = length.checked_sub(1)?;
+
+        match self {
+            Self::FromTo(start, end) => {
+                (start <= end && start <= last).then(|| (start, end.min(last)))
+            }
+            Self::From(start) => (start <= last).then_some((start, last)),
+            Self::Suffix(0) => None,
+            Self::Suffix(suffix) => Some((length.saturating_sub(suffix), last)),
+        }
+    }
+}
+
+fn s3_range_for_header(range: Range) -> Option<ByteRange> {
     if range.iter().count() > 1 {
         // AWS S3 only supports one range per request
         None
     } else {
-        if let Some((start, end)) = range.iter().next() {
-            Some((
-                match start {
-                    Bound::Unbounded => 0,
-                    Bound::Included(start) => start,
-                    _ => unreachable!(), // Range never returns Excluded
-                },
-                match end {
-                    Bound::Unbounded => None,
-                    Bound::Included(end) => Some(end),
-                    _ => unreachable!(),
-                },
-            ))
-        } else {
-            None
+        match range.iter().next()? {
+            // a range ending before it starts is invalid, so the header is ignored
+            (Bound::Included(start), Bound::Included(end)) => {
+                (start <= end).then_some(ByteRange::FromTo(start, end))
+            }
+            (Bound::Included(start), Bound::Unbounded) => Some(ByteRange::From(start)),
+            (Bound::Unbounded, Bound::Included(suffix)) => Some(ByteRange::Suffix(suffix)),
+            // `bytes=-` has two unbounded ends, Range never returns Excluded
+            _ => None,
+        }
+    }
+}
+
+fn make_range_not_satisfiable_response(length: u64) -> Response {
+    (
+        StatusCode::RANGE_NOT_SATISFIABLE,
+        [(header::CONTENT_RANGE, format!("bytes */{length}"))],
+    )
+        .into_response()
+}
+
+/// Asks the bucket for the length of an object with a HEAD request.
+async fn object_length(bucket: &Bucket, bucket_path: &str) -> Option<u64> {
+    Reqwest::new(bucket, bucket_path, Command::HeadObject)
+        .response()
+        .await
+        .ok()
+        .filter(|response| response.status().is_success())
+        .and_then(|response| content_length(response.headers()))
+}
+
+/// Answers a range rejected by the bucket with 416 and the object's length, so
+/// clients can retry with a satisfiable range. S3 includes the length in its
+/// error, otherwise it's asked for with a HEAD request.
+async fn make_range_not_satisfied_response(
+    bucket: &Bucket,
+    bucket_path: &str,
+    error_body: &str,
+) -> Response {
+    let length = match s3_error_element(error_body, "ActualObjectSize") {
+        Some(size) => size.parse().ok(),
+        None => object_length(bucket, bucket_path).await,
+    };
+
+    match length {
+        Some(length) => make_range_not_satisfiable_response(length),
+        None => StatusCode::RANGE_NOT_SATISFIABLE.into_response(),
+    }
+}
+
+/// Adds the `Access-Control-Allow-Origin` header if the request's origin is
+/// allowed by `cors`.
+fn apply_cors(headers: &mut HeaderMap, cors: &Cors, request_headers: &HeaderMap) {
+    headers.append(header::VARY, HeaderValue::from_static("Origin"));
+
+    if let Some(allow_origin) = request_headers
+        .get(header::ORIGIN)
+        .and_then(|origin| origin.to_str().ok())
+        .and_then(|origin| cors.allow_origin(origin))
+    {
+        if let Ok(value) = HeaderValue::from_str(allow_origin) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, value);
         }
     }
 }
 
+/// Adds the headers answering a CORS preflight request to `headers`, if the
+/// request's origin is allowed by `cors`. Methods default to `methods`.
+fn apply_cors_preflight(
+    headers: &mut HeaderMap,
+    cors: &Cors,
+    request_headers: &HeaderMap,
+    methods: &[EndpointMethod],
+) {
+    apply_cors(headers, cors, request_headers);
+
+    if !headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+        || !request_headers.contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+    {
+        return;
+    }
+
+    let methods = cors
+        .allowed_methods()
+        .unwrap_or(methods)
+        .iter()
+        .map(|method| method.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    if let Ok(value) = HeaderValue::from_str(&methods) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, value);
+    }
+
+    let allow_headers = if cors.allowed_headers().iter().any(|name| name == "*") {
+        // echoed back, as `*` isn't a wildcard for credentialed requests
+        headers.append(
+            header::VARY,
+            HeaderValue::from_static("Access-Control-Request-Headers"),
+        );
+        request_headers
+            .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+            .cloned()
+    } else if !cors.allowed_headers().is_empty() {
+        HeaderValue::from_str(&cors.allowed_headers().join(", ")).ok()
+    } else {
+        None
+    };
+    if let Some(value) = allow_headers {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, value);
+    }
+
+    if let Some(max_age) = cors.max_age() {
+        headers.insert(header::ACCESS_CONTROL_MAX_AGE, max_age.as_secs().into());
+    }
+}
+
+fn request_host(request_headers: &HeaderMap) -> Option<&str> {
+    request_headers.get(header::HOST)?.to_str().ok()
+}
+
+/// Extracts the host from the request's `Referer`, or `Origin` if there is none.
+fn referer_host(request_headers: &HeaderMap) -> Option<&str> {
+    let referer = request_headers
+        .get(header::REFERER)
+        .or_else(|| request_headers.get(header::ORIGIN))?
+        .to_str()
+        .ok()?;
+
+    let authority = referer
+        .split_once("://")
+        .map_or(referer, |(_scheme, rest)| rest)
+        .split(['/', '?', '#'])
+        .next()?;
+    let host = authority.rsplit('@').next()?;
+    let host = match host.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => host,
+    };
+
+    (!host.is_empty() && host != "null").then_some(host)
+}
+
+fn make_maintenance_response(maintenance: &config::Maintenance) -> Response {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("text/html; charset=utf-8"),
+            ),
+            (header::RETRY_AFTER, maintenance.retry_after().into()),
+        ],
+        maintenance.page().to_owned(),
+    )
+        .into_response()
+}
+
+/// Answers with `503 Service Unavailable` for a failed upstream request.
+///
+/// The error is only included with `verbose_errors`, as it may contain the
+/// bucket's host and name.
+fn make_upstream_error_response(config: &Configuration, err: &dyn std::fmt::Display) -> Response {
+    tracing::warn!("Upstream error: {err}");
+
+    let body = if config.verbose_errors() {
+        format!("Upstream error: {err}")
+    } else {
+        "Upstream error".to_owned()
+    };
+
+    let mut response = (StatusCode::SERVICE_UNAVAILABLE, body).into_response();
+    if let Some(retry_after) = config.service_unavailable_retry_after() {
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, retry_after.into());
+    }
+
+    response
+}
+
 fn make_not_found_response() -> impl IntoResponse {
     (StatusCode::NOT_FOUND, "File not found")
 }
 
+/// Characters encoded in request paths appended to redirect URLs, the path
+/// percent-encode set of the URL standard.
+const REDIRECT_PATH: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// Redirects a request for a missing object at `path` to `url`, with the
+/// path appended if the endpoint asks for it.
+fn make_not_found_redirect_response(endpoint: &Endpoint, url: &str, path: &str) -> Response {
+    let location = if endpoint.not_found_redirect_append_path() {
+        format!("{url}{}", utf8_percent_encode(path, REDIRECT_PATH))
+    } else {
+        url.to_owned()
+    };
+    let status = if endpoint.not_found_redirect_permanent() {
+        StatusCode::MOVED_PERMANENTLY
+    } else {
+        StatusCode::FOUND
+    };
+
+    match HeaderValue::from_str(&location) {
+        Ok(location) => (status, [(header::LOCATION, location)]).into_response(),
+        Err(_) => make_not_found_response().into_response(),
+    }
+}
+
+/// Detects "folder" markers, which S3-compatible systems store as objects with
+/// the `application/x-directory` content type or as zero-byte objects with a
+/// trailing slash.
+fn is_directory_marker(
+    bucket_path: &str,
+    content_type: Option<&HeaderValue>,
+    content_length: Option<u64>,
+) -> bool {
+    let directory_type = content_type
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/x-directory"));
+
+    directory_type || (bucket_path.ends_with('/') && content_length == Some(0))
+}
+
+fn make_directory_marker_response(
+    request_path: &str,
+    directory_markers: DirectoryMarkers,
+) -> Option<Response> {
+    match directory_markers {
+        DirectoryMarkers::Serve => None,
+        DirectoryMarkers::Redirect if !request_path.ends_with('/') => Some(
+            (
+                StatusCode::FOUND,
+                [(header::LOCATION, format!("{request_path}/"))],
+            )
+                .into_response(),
+        ),
+        DirectoryMarkers::Redirect | DirectoryMarkers::NotFound => {
+            Some(make_not_found_response().into_response())
+        }
+    }
+}
+
+/// Rewrites a `Location` pointing at `bucket`, which serves `endpoint`, to the
+/// proxy path serving the same key. Only endpoints served by the same bucket
+/// are considered.
+///
+/// Relative locations are kept as they are, absolute locations pointing
+/// elsewhere or at the bucket without a matching endpoint are dropped to not
+/// leak the bucket host.
+fn rewrite_location(
+    location: &str,
+    bucket: &Bucket,
+    config: &Configuration,
+    endpoint: &Endpoint,
+) -> Option<String> {
+    let strip_scheme = |url: &str| url.split_once("://").map(|(_scheme, rest)| rest.to_owned());
+
+    let location_without_scheme = match strip_scheme(location) {
+        Some(location) => location,
+        None => return Some(location.to_owned()),
+    };
+    let bucket_url = strip_scheme(&bucket.url())?;
+
+    let key = location_without_scheme.strip_prefix(&bucket_url)?;
+    let key = match key.strip_prefix('/') {
+        Some(key) => key,
+        None if key.is_empty() => key,
+        // another bucket whose name starts with this one's
+        None => return None,
+    };
+
+    let served_by = config.endpoint_bucket(endpoint)?;
+    let mut endpoints = config
+        .endpoints()
+        .iter()
+        .filter(|other| {
+            config
+                .endpoint_bucket(other)
+                .is_some_and(|other| other.is_same_bucket(served_by))
+        })
+        .collect::<Vec<_>>();
+    // prefer the most specific bucket path
+    endpoints.sort_by_key(|endpoint| std::cmp::Reverse(endpoint.bucket_path().len()));
+
+    endpoints.into_iter().find_map(|endpoint| {
+        let bucket_prefix = endpoint.bucket_path().trim_matches('/');
+        let sub_path = if bucket_prefix.is_empty() {
+            key
+        } else {
+            key.strip_prefix(bucket_prefix)?.strip_prefix('/')?
+        };
+
+        Some(format!(
+            "{}/{sub_path}",
+            endpoint.path().trim_end_matches('/')
+        ))
+    })
+}
+
+/// Waits up to `timeout` for one of `permits`, answering with `503 Service
+/// Unavailable` and `message` if none becomes available.
+async fn acquire_permit(
+    permits: Option<&Arc<Semaphore>>,
+    timeout: Duration,
+    config: &Configuration,
+    message: &'static str,
+) -> Result<Option<OwnedSemaphorePermit>, Response> {
+    let Some(permits) = permits else {
+        return Ok(None);
+    };
+
+    match tokio::time::timeout(timeout, permits.clone().acquire_owned()).await {
+        Ok(permit) => Ok(Some(permit.expect("upstream semaphore is never closed"))),
+        Err(_) => Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(
+                header::RETRY_AFTER,
+                HeaderValue::from(config.service_unavailable_retry_after().unwrap_or(1)),
+            )],
+            message,
+        )
+            .into_response()),
+    }
+}
+
 async fn make_proxy_response(
     bucket: &Bucket,
+    config: &Configuration,
+    endpoint: &Endpoint,
+    request_path: &str,
     bucket_path: &str,
     command: Command<'_>,
 ) -> Result<impl IntoResponse, s3::error::S3Error> {
-    let request = Reqwest::new(&bucket, &bucket_path, command);
+    let upstream = config
+        .endpoint_bucket(endpoint)
+        .expect("every endpoint has a bucket");
+    let timeout = upstream.upstream_queue_timeout();
+
+    // the endpoint's permit comes first, so requests waiting for a busy
+    // endpoint don't hold permits of the bucket other endpoints could use
+    let permits = match acquire_permit(
+        endpoint.permits(),
+        timeout,
+        config,
+        "Too many concurrent requests for this endpoint",
+    )
+    .await
+    {
+        Ok(endpoint_permit) => match acquire_permit(
+            upstream.upstream_permits(),
+            timeout,
+            config,
+            "Too many concurrent upstream requests",
+        )
+        .await
+        {
+            Ok(upstream_permit) => (endpoint_permit, upstream_permit),
+            Err(response) => return Ok(response),
+        },
+        Err(response) => return Ok(response),
+    };
+
+    let full_object = matches!(command, Command::GetObject);
+    let is_head = matches!(command, Command::HeadObject);
+    let connect_timeout = endpoint
+        .upstream_connect_timeout()
+        .unwrap_or_else(|| config.upstream_connect_timeout());
+    let upstream_config = config.upstream();
+    let started = Instant::now();
+    let mut attempt = 0;
+    let response = loop {
+        let request = Reqwest::new(bucket, bucket_path, command.clone());
+        let result = match tokio::time::timeout(connect_timeout, request.response()).await {
+            Ok(result) => result,
+            Err(_) => {
+                tracing::warn!(
+                    path = config.log_paths().format(request_path),
+                    "No response from bucket within {:?}",
+                    connect_timeout
+                );
+                return Ok(StatusCode::GATEWAY_TIMEOUT.into_response());
+            }
+        };
+
+        let failure = match &result {
+            Ok(response) if response.status().is_server_error() => response.status().to_string(),
+            Ok(_) => break result?,
+            Err(err) => err.to_string(),
+        };
+        if attempt >= upstream_config.retries() {
+            break result?;
+        }
+
+        let delay = retry_delay(upstream_config.retry_base_delay(), attempt);
+        attempt += 1;
+        tracing::info!(
+            path = config.log_paths().format(request_path),
+            "Retrying request to bucket after {}, attempt {} of {} in {:?}",
+            failure,
+            attempt,
+            upstream_config.retries(),
+            delay
+        );
+        tokio::time::sleep(delay).await;
+    };
+
+    let registry = config.metrics_registry().cloned();
+    if let Some(registry) = &registry {
+        registry.observe_upstream_latency(started.elapsed());
+    }
+
+    // rust-s3 is built without `fail-on-err` to keep the headers of `304 Not
+    // Modified` responses, other errors are turned into errors like it would
+    if response.status() == StatusCode::NOT_MODIFIED {
+        let mut response = make_not_modified_response(response.headers());
+        apply_default_cache_control(response.headers_mut(), endpoint, bucket_path);
+
+        return Ok(response);
+    } else if response.status().is_redirection() && config.rewrite_location() {
+        // reqwest follows 301, 302, 303, 307 and 308 on its own, so only other
+        // redirects or those it can't follow get here. Without a Location
+        // that can be rewritten they're answered as errors.
+        if let Some(location) = response
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| rewrite_location(location, bucket, config, endpoint))
+            .and_then(|location| HeaderValue::from_str(&location).ok())
+        {
+            return Ok((response.status(), [(header::LOCATION, location)]).into_response());
+        }
+
+        let status = response.status().as_u16();
+        return Err(s3::error::S3Error::Http(status, response.text().await?));
+    } else if !response.status().is_success() {
+        let status = response.status().as_u16();
+        return Err(s3::error::S3Error::Http(status, response.text().await?));
+    }
+
+    if is_directory_marker(
+        bucket_path,
+        response.headers().get(header::CONTENT_TYPE),
+        content_length(response.headers()).or_else(|| response.content_length()),
+    ) {
+        if let Some(response) =
+            make_directory_marker_response(request_path, endpoint.directory_markers())
+        {
+            return Ok(response);
+        }
+    }
+
+    if endpoint.treat_empty_as_missing()
+        && response.status() == StatusCode::OK
+        && content_length(response.headers()).or_else(|| response.content_length()) == Some(0)
+    {
+        return Ok(make_not_found_response().into_response());
+    }
 
-    let response = request.response().await?;
+    let mut length = content_length(response.headers()).or_else(|| response.content_length());
+    if length.is_none()
+        && full_object
+        && response.status() == StatusCode::OK
+        && config.ensure_content_length()
+    {
+        length = object_length(bucket, bucket_path).await;
+    }
 
     let mut headers = HeaderMap::new();
-    response
-        .content_length()
-        .and_then(|len| headers.insert(header::CONTENT_LENGTH, len.into()));
+    length.and_then(|len| headers.insert(header::CONTENT_LENGTH, len.into()));
 
     copy_headers(
         &mut headers,
         response.headers(),
-        &[header::CONTENT_TYPE, header::CONTENT_RANGE, header::ETAG],
+        &[
+            header::CONTENT_TYPE,
+            header::CONTENT_RANGE,
+            header::ETAG,
+            header::CACHE_CONTROL,
+            header::EXPIRES,
+            header::LAST_MODIFIED,
+        ],
     );
+    apply_default_cache_control(&mut headers, endpoint, bucket_path);
+    if config.guess_content_type() && !has_specific_content_type(&headers) {
+        if let Some(content_type) = mime_guess::from_path(bucket_path)
+            .first()
+            .and_then(|mime| HeaderValue::from_str(mime.as_ref()).ok())
+        {
+            headers.insert(header::CONTENT_TYPE, content_type);
+        }
+    }
+    // whether ranges are supported is up to the bucket, so it's passed on
+    // as is instead of being assumed
+    if is_head {
+        copy_headers(&mut headers, response.headers(), &[header::ACCEPT_RANGES]);
+    }
+
+    if let Some(timing_allow_origin) = config.timing_allow_origin() {
+        headers.insert(TIMING_ALLOW_ORIGIN, timing_allow_origin);
+    }
+
+    for name in config.strip_response_headers() {
+        headers.remove(name);
+    }
 
+    let idle_timeout = endpoint
+        .upstream_idle_timeout()
+        .or_else(|| config.upstream_idle_timeout());
     let status_code = response.status();
-    let body = StreamBody::new(response.bytes_stream());
+    // the permits are held until the body is fully streamed
+    let stream = response.bytes_stream().map(move |chunk| {
+        let _permits = &permits;
+        if let (Some(registry), Ok(chunk)) = (&registry, &chunk) {
+            registry.add_streamed_bytes(chunk.len());
+        }
+        chunk
+    });
+    let stream: Pin<Box<dyn Stream<Item = Result<_, BoxError>> + Send>> = match idle_timeout {
+        // an error aborts the response, as the length was already sent
+        Some(timeout) => Box::pin(stream.timeout(timeout).map(|chunk| match chunk {
+            Ok(chunk) => chunk.map_err(BoxError::from),
+            Err(elapsed) => Err(elapsed.into()),
+        })),
+        None => Box::pin(stream.map(|chunk| chunk.map_err(BoxError::from))),
+    };
+    let body = StreamBody::new(stream);
 
     Ok((status_code, headers, body).into_response())
 }
 
-async fn proxy_request(
+/// Parses the `Content-Length` header, which for HEAD requests is the only
+/// place the object's length is found as the body itself is empty.
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+/// Looks up the ETag of the object served at `path`.
+async fn asset_etag(
     bucket: &Bucket,
     config: &Configuration,
     path: &str,
-    command: Command<'_>,
-) -> impl IntoResponse {
-    let bucket_path = get_bucket_path(path, &config.endpoints());
+    host: Option<&str>,
+    etags: &EtagCache,
+) -> Option<String> {
+    let endpoint = config.endpoints().find(path)?;
+    let bucket_path = get_bucket_path(path, host, endpoint)?;
 
-    if let Some(bucket_path) = bucket_path {
-        make_proxy_response(bucket, &bucket_path, command)
-            .await
-            .map(|r| r.into_response())
-            .unwrap_or_else(|err| match err {
-                s3::error::S3Error::Http(404, _response) => {
-                    make_not_found_response().into_response()
-                }
-                _ => (
-                    StatusCode::SERVICE_UNAVAILABLE,
-                    format!("Upstream error: {err}"),
-                )
-                    .into_response(),
-            })
-            .into_response()
-    } else {
-        make_not_found_response().into_response()
-    }
+    etags
+        .get_or_fetch(&bucket_path, || async {
+            let response = Reqwest::new(bucket, &bucket_path, Command::HeadObject)
+                .response()
+                .await
+                .map_err(|err| tracing::debug!("Couldn't get ETag of {bucket_path}: {err}"))
+                .ok()?;
+
+            Some(
+                response
+                    .headers()
+                    .get(header::ETAG)?
+                    .to_str()
+                    .ok()?
+                    .to_owned(),
+            )
+        })
+        .await
 }
-#[tracing::instrument(skip(bucket))]
-async fn get_file(
-    Path(path): Path<String>,
-    range: Option<TypedHeader<Range>>,
-    Extension(bucket): Extension<Bucket>,
-    Extension(config): Extension<Configuration>,
-) -> impl IntoResponse {
-    tracing::info!("GET {}", path);
 
-    let command = if let Some(TypedHeader(range)) = range {
-        if let Some((start, end)) = s3_range_for_header(range) {
-            Command::GetObjectRange { start, end }
-        } else {
-            Command::GetObject
-        }
-    } else {
-        Command::GetObject
+/// Buffers an HTML response and appends ETag-derived versions to its asset links.
+async fn bust_asset_caches(
+    response: Response,
+    bucket: &Bucket,
+    config: &Configuration,
+    document_path: &str,
+    host: Option<&str>,
+    etags: &EtagCache,
+) -> Response {
+    let (mut parts, body) = response.into_parts();
+
+    let html = match hyper::body::to_bytes(body).await {
+        Ok(bytes) => match String::from_utf8(bytes.to_vec()) {
+            Ok(html) => html,
+            Err(_) => return Response::from_parts(parts, body::boxed(Full::from(bytes))),
+        },
+        Err(err) => return make_upstream_error_response(config, &err),
     };
 
-    proxy_request(&bucket, &config, path.as_str(), command).await
-}
+    let links = cache_bust::find_asset_links(&html);
+    let mut versions = Vec::with_capacity(links.len());
+    for link in &links {
+        let version = match cache_bust::resolve_asset_path(document_path, &link.url) {
+            Some(path) => asset_etag(bucket, config, &path, host, etags)
+                .await
+                .map(|etag| cache_bust::etag_version(&etag)),
+            None => None,
+        };
+        versions.push(version);
+    }
 
-#[tracing::instrument(skip(bucket))]
-async fn head_file(
-    Path(path): Path<String>,
-    Extension(bucket): Extension<Bucket>,
-    Extension(config): Extension<Configuration>,
-) -> impl IntoResponse {
-    tracing::info!("HEAD {}", path);
+    let html = cache_bust::rewrite_asset_links(&html, &links, &versions);
 
-    let command = Command::HeadObject;
+    parts
+        .headers
+        .insert(header::CONTENT_LENGTH, html.len().into());
 
-    proxy_request(&bucket, &config, path.as_str(), command).await
+    Response::from_parts(parts, body::boxed(Full::from(html)))
 }
 
-async fn start_server(config: &Configuration) -> anyhow::Result<()> {
-    let bucket = config.bucket().make_s3_bucket()?;
-
-    let router = Router::new()
-        .route("/*path", get(get_file).head(head_file))
-        .layer(Extension(bucket))
-        .layer(Extension(config.clone()));
+fn is_html(response: &Response) -> bool {
+    response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/html"))
+}
 
-    let bind = config.http().make_socketaddr()?;
+/// Checks the request's `Expect` header, of which only `100-continue` is
+/// defined ([RFC 7231, section 5.1.1](https://www.rfc-editor.org/rfc/rfc7231#section-5.1.1)).
+/// Sending the `100 Continue` is done by hyper once a body is read.
+fn expectation_supported(request_headers: &HeaderMap) -> bool {
+    request_headers.get_all(header::EXPECT).iter().all(|value| {
+        value
+            .to_str()
+            .is_ok_and(|value| value.trim().eq_ignore_ascii_case("100-continue"))
+    })
+}
 
-    tracing::info!("Listening on http://{bind}/");
+/// Image formats which are tried in order of preference for images on
+/// endpoints with `image_variants`, along with their file extension.
+const IMAGE_VARIANTS: &[(&str, &str)] = &[("image/avif", "avif"), ("image/webp", "webp")];
 
-    axum::Server::bind(&bind)
-        .serve(router.into_make_service())
-        .await?;
+/// Whether variants in [`IMAGE_VARIANTS`] are looked up for `path`.
+fn has_image_variants(path: &str) -> bool {
+    let extension = match path.rsplit_once('.') {
+        Some((_, extension)) if !extension.contains('/') => extension.to_ascii_lowercase(),
+        _ => return false,
+    };
 
-    Ok(())
+    matches!(extension.as_str(), "jpg" | "jpeg" | "png" | "gif")
 }
 
-async fn load_configuration() -> anyhow::Result<Configuration> {
-    let config_file =
-        std::env::var("S3PROXY_CONFIG").unwrap_or_else(|_| "s3-proxy.yaml".to_owned());
+/// Checks whether `accept` contains `media_type` with a non-zero quality.
+fn accepts(accept: &str, media_type: &str) -> bool {
+    accept.split(',').any(|range| {
+        let mut params = range.split(';').map(str::trim);
 
-    Configuration::from_file(config_file).await
+        params
+            .next()
+            .is_some_and(|range| range.eq_ignore_ascii_case(media_type))
+            && params.all(|param| {
+                !param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            })
+    })
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    dotenv::dotenv().ok();
+/// Serves the preferred image variant of `path` accepted by the client.
+///
+/// Returns [`None`] if the client doesn't accept any variant or none of the
+/// accepted ones exist, in which case the original should be served.
+async fn proxy_image_variant(
+    bucket: &Bucket,
+    config: &Configuration,
+    endpoint: &Endpoint,
+    path: &str,
+    request_headers: &HeaderMap,
+    command: Command<'_>,
+) -> Option<Response> {
+    if !has_image_variants(path) {
+        return None;
+    }
 
-    tracing_subscriber::fmt::init();
+    let accept = request_headers.get(header::ACCEPT)?.to_str().ok()?;
 
-    let config = load_configuration().await?;
+    for (content_type, extension) in IMAGE_VARIANTS {
+        if !accepts(accept, content_type) {
+            continue;
+        }
 
-    start_server(&config).await?;
+        let variant_path = format!("{path}.{extension}");
+        let mut response = proxy_endpoint_request(
+            bucket,
+            config,
+            endpoint,
+            &variant_path,
+            request_host(request_headers),
+            command.clone(),
+        )
+        .await
+        .into_response();
 
-    Ok(())
+        if response.status() == StatusCode::NOT_FOUND {
+            continue;
+        }
+
+        if response.status().is_success() {
+            let headers = response.headers_mut();
+            headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+
+            if config.content_location() {
+                if let Ok(location) = HeaderValue::from_str(&variant_path) {
+                    headers.insert(header::CONTENT_LOCATION, location);
+                }
+            }
+        }
+
+        return Some(response);
+    }
+
+    None
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::config::Endpoint;
+/// Whether the `Content-Type` in `headers` is that of a compressed file.
+fn has_encoding_content_type(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|essence| {
+            matches!(
+                essence.trim().to_ascii_lowercase().as_str(),
+                "application/gzip" | "application/x-gzip" | "application/x-brotli"
+            )
+        })
+}
 
-    use super::*;
+/// Serves the pre-compressed variant of `path` the client prefers, like
+/// `app.js.br` with `Content-Encoding: br`. Variants stored without a
+/// specific `Content-Type`, or with that of their encoding, get the one
+/// guessed for `path`.
+///
+/// Returns [`None`] if `path` isn't text, the client doesn't accept any
+/// encoding of [`compression::PRECOMPRESSED_VARIANTS`] or none of the accepted
+/// variants exist, in which case the original should be served. Range requests
+/// always get the original, as their ranges refer to it.
+async fn proxy_precompressed_variant(
+    bucket: &Bucket,
+    config: &Configuration,
+    endpoint: &Endpoint,
+    path: &str,
+    request_headers: &HeaderMap,
+    command: Command<'_>,
+) -> Option<Response> {
+    if !matches!(command, Command::GetObject | Command::HeadObject) {
+        return None;
+    }
 
-    #[test]
-    fn test_get_bucket_path() {
-        let endpoints = Endpoints::from_vec(vec![Endpoint::new(
-            "/media/".to_owned(),
-            "/app/files".to_owned(),
-        )]);
+    let content_type = mime_guess::from_path(path)
+        .first()
+        .filter(|mime| compression::is_compressible_type(mime.as_ref()))?;
+    let accept_encoding = request_headers
+        .get(header::ACCEPT_ENCODING)?
+        .to_str()
+        .ok()?;
 
-        let bucket_path = get_bucket_path("/media/foo/bar", &endpoints);
+    for (encoding, extension) in compression::negotiate_encodings(accept_encoding) {
+        let variant_path = format!("{path}.{extension}");
+        let mut response = proxy_endpoint_request(
+            bucket,
+            config,
+            endpoint,
+            &variant_path,
+            request_host(request_headers),
+            command.clone(),
+        )
+        .await
+        .into_response();
 
-        assert_eq!(bucket_path.as_deref(), Some("/app/files/foo/bar"));
+        if response.status() == StatusCode::NOT_FOUND {
+            continue;
+        }
+
+        if response.status().is_success() {
+            let headers = response.headers_mut();
+            headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding));
+            // variants are often stored with the type of their encoding
+            if !has_specific_content_type(headers) || has_encoding_content_type(headers) {
+                if let Ok(content_type) = HeaderValue::from_str(content_type.as_ref()) {
+                    headers.insert(header::CONTENT_TYPE, content_type);
+                }
+            }
+
+            if config.content_location() {
+                if let Ok(location) = HeaderValue::from_str(&variant_path) {
+                    headers.insert(header::CONTENT_LOCATION, location);
+                }
+            }
+        }
+
+        return Some(response);
     }
 
-    #[test]
-    fn test_s3_range_header() {
-        use axum::headers::{Header, HeaderValue};
+    None
+}
 
-        assert_eq!(
+/// Serves the localized variant of `path` in the locale the client prefers.
+///
+/// Returns [`None`] if the client prefers none of `locales` or the variant
+/// doesn't exist, in which case the original should be served.
+async fn proxy_i18n_variant(
+    bucket: &Bucket,
+    config: &Configuration,
+    endpoint: &Endpoint,
+    locales: &[String],
+    path: &str,
+    request_headers: &HeaderMap,
+    command: Command<'_>,
+) -> Option<Response> {
+    let accept_language = request_headers
+        .get(header::ACCEPT_LANGUAGE)?
+        .to_str()
+        .ok()?;
+    let locale = i18n::negotiate_locale(accept_language, locales)?;
+
+    let variant_path = i18n::localized_path(path, locale);
+    let mut response = proxy_endpoint_request(
+        bucket,
+        config,
+        endpoint,
+        &variant_path,
+        request_host(request_headers),
+        command,
+    )
+    .await
+    .into_response();
+
+    if response.status() == StatusCode::NOT_FOUND {
+        return None;
+    }
+
+    if response.status().is_success() {
+        let headers = response.headers_mut();
+        if let Ok(locale) = HeaderValue::from_str(locale) {
+            headers.insert(header::CONTENT_LANGUAGE, locale);
+        }
+
+        if config.content_location() {
+            if let Ok(location) = HeaderValue::from_str(&variant_path) {
+                headers.insert(header::CONTENT_LOCATION, location);
+            }
+        }
+    }
+
+    Some(response)
+}
+
+/// Returns the request's conditional headers, which are forwarded to the
+/// bucket so it answers with either `304 Not Modified` or the object in a
+/// single request.
+fn conditional_headers(request_headers: &HeaderMap) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    copy_headers(&mut headers, request_headers, &[header::IF_MODIFIED_SINCE]);
+
+    if let Some(if_none_match) = request_headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| HeaderValue::from_str(&strip_weak_etags(value)).ok())
+    {
+        headers.insert(header::IF_NONE_MATCH, if_none_match);
+    }
+
+    headers
+}
+
+/// Removes the `W/` prefix from all entity tags in an `If-None-Match` list.
+///
+/// `If-None-Match` uses the weak comparison, for which only the opaque tags
+/// have to match. S3 only ever generates strong tags, so a client's weak tag
+/// would otherwise never match.
+fn strip_weak_etags(if_none_match: &str) -> String {
+    if_none_match
+        .split(',')
+        .map(|etag| {
+            let etag = etag.trim();
+            etag.strip_prefix("W/").unwrap_or(etag)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Answers with `304 Not Modified`, keeping the headers a cache has to update
+/// ([RFC 7232, section 4.1](https://www.rfc-editor.org/rfc/rfc7232#section-4.1)).
+fn make_not_modified_response(upstream_headers: &HeaderMap) -> Response {
+    let mut headers = HeaderMap::new();
+    copy_headers(
+        &mut headers,
+        upstream_headers,
+        &[
+            header::ETAG,
+            header::CACHE_CONTROL,
+            header::EXPIRES,
+            header::LAST_MODIFIED,
+        ],
+    );
+
+    (StatusCode::NOT_MODIFIED, headers).into_response()
+}
+
+/// Whether `headers` have a `Content-Type` other than the generic
+/// `application/octet-stream` objects uploaded without one get.
+fn has_specific_content_type(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|essence| {
+            !essence
+                .trim()
+                .eq_ignore_ascii_case("application/octet-stream")
+        })
+}
+
+/// `Cache-Control` of objects with a content hash in their key, which never
+/// change and can be cached for a year.
+const IMMUTABLE_CACHE_CONTROL: HeaderValue =
+    HeaderValue::from_static("public, max-age=31536000, immutable");
+
+/// Adds the endpoint's default `Cache-Control` unless the bucket sent one.
+/// Fingerprinted objects are cached for a year instead.
+fn apply_default_cache_control(headers: &mut HeaderMap, endpoint: &Endpoint, bucket_path: &str) {
+    let cache_control = if endpoint.is_fingerprinted(bucket_path) {
+        Some(IMMUTABLE_CACHE_CONTROL)
+    } else {
+        endpoint.default_cache_control()
+    };
+
+    if let Some(cache_control) = cache_control {
+        headers
+            .entry(header::CACHE_CONTROL)
+            .or_insert(cache_control);
+    }
+}
+
+/// Returns `bucket` sending `headers` with every request.
+fn with_extra_headers(bucket: &Bucket, headers: HeaderMap) -> Cow<'_, Bucket> {
+    if headers.is_empty() {
+        Cow::Borrowed(bucket)
+    } else {
+        Cow::Owned(bucket.with_extra_headers(headers))
+    }
+}
+
+/// Translates the request's `If-Range` into a precondition for the bucket,
+/// which fails if the object changed.
+///
+/// Weak entity tags are ignored, as they must not be used in `If-Range`.
+fn if_range_preconditions(request_headers: &HeaderMap) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+
+    if let Some(if_range) = request_headers.get(header::IF_RANGE) {
+        if if_range.as_bytes().starts_with(b"\"") {
+            headers.insert(header::IF_MATCH, if_range.clone());
+        } else if !if_range.as_bytes().starts_with(b"W/") {
+            headers.insert(header::IF_UNMODIFIED_SINCE, if_range.clone());
+        }
+    }
+
+    headers
+}
+
+/// Fetches `path` from the bucket, preferring a localized, image or
+/// pre-compressed variant if enabled.
+async fn fetch_object(
+    bucket: &Bucket,
+    config: &Configuration,
+    endpoint: &Endpoint,
+    path: &str,
+    request_headers: &HeaderMap,
+    command: Command<'_>,
+) -> Response {
+    if let Some(locales) = endpoint.i18n_locales() {
+        if let Some(response) = proxy_i18n_variant(
+            bucket,
+            config,
+            endpoint,
+            locales,
+            path,
+            request_headers,
+            command.clone(),
+        )
+        .await
+        {
+            return response;
+        }
+    }
+
+    if endpoint.image_variants() {
+        if let Some(response) = proxy_image_variant(
+            bucket,
+            config,
+            endpoint,
+            path,
+            request_headers,
+            command.clone(),
+        )
+        .await
+        {
+            return response;
+        }
+    }
+
+    if endpoint.precompressed() {
+        if let Some(response) = proxy_precompressed_variant(
+            bucket,
+            config,
+            endpoint,
+            path,
+            request_headers,
+            command.clone(),
+        )
+        .await
+        {
+            return response;
+        }
+    }
+
+    let host = request_host(request_headers);
+
+    proxy_endpoint_request(bucket, config, endpoint, path, host, command)
+        .await
+        .into_response()
+}
+
+async fn proxy_request(
+    buckets: &Buckets,
+    config: &Configuration,
+    path: &str,
+    request_headers: &HeaderMap,
+    command: Command<'_>,
+    etags: &EtagCache,
+    maintenance: &MaintenanceMode,
+) -> impl IntoResponse {
+    if maintenance.is_enabled() {
+        return make_maintenance_response(config.maintenance());
+    }
+
+    let endpoint = config.endpoints().find(path);
+    let host = request_host(request_headers);
+
+    tracing::trace!("Found endpoint for request path: {:?}", endpoint);
+
+    let mut response = match endpoint {
+        Some(endpoint) if !endpoint.referer_allowed(referer_host(request_headers)) => {
+            (StatusCode::FORBIDDEN, "Forbidden").into_response()
+        }
+        Some(endpoint) => {
+            let bucket = buckets.get(endpoint);
+            let bust_caches = endpoint.cache_bust_assets() && matches!(command, Command::GetObject);
+
+            // the rewritten links depend on more than the document's ETag,
+            // so cache busted documents are never answered with 304
+            let mut extra_headers = if bust_caches {
+                HeaderMap::new()
+            } else {
+                conditional_headers(request_headers)
+            };
+            let request_bucket = with_extra_headers(bucket, extra_headers.clone());
+
+            let range_preconditions = match command {
+                Command::GetObjectRange { .. } if config.validate_if_range() => {
+                    if_range_preconditions(request_headers)
+                }
+                _ => HeaderMap::new(),
+            };
+
+            let mut response = if range_preconditions.is_empty() {
+                fetch_object(
+                    &request_bucket,
+                    config,
+                    endpoint,
+                    path,
+                    request_headers,
+                    command.clone(),
+                )
+                .await
+            } else {
+                extra_headers.extend(range_preconditions);
+                let range_bucket = bucket.with_extra_headers(extra_headers);
+
+                let response = fetch_object(
+                    &range_bucket,
+                    config,
+                    endpoint,
+                    path,
+                    request_headers,
+                    command.clone(),
+                )
+                .await;
+
+                if response.status() == StatusCode::PRECONDITION_FAILED {
+                    // the object changed since the client got its other parts
+                    fetch_object(
+                        &request_bucket,
+                        config,
+                        endpoint,
+                        path,
+                        request_headers,
+                        Command::GetObject,
+                    )
+                    .await
+                } else {
+                    response
+                }
+            };
+
+            if response.status() == StatusCode::NOT_FOUND
+                && endpoint.implicit_directory() != ImplicitDirectory::Off
+                && !path.ends_with('/')
+                && is_implicit_directory(bucket, endpoint, path, host).await
+            {
+                response = match endpoint.implicit_directory() {
+                    ImplicitDirectory::Off => response,
+                    ImplicitDirectory::Redirect => {
+                        (StatusCode::FOUND, [(header::LOCATION, format!("{path}/"))])
+                            .into_response()
+                    }
+                    ImplicitDirectory::ServeIndex => {
+                        let index_path = format!("{path}/{}", endpoint.implicit_index_document());
+
+                        fetch_object(
+                            &request_bucket,
+                            config,
+                            endpoint,
+                            &index_path,
+                            request_headers,
+                            command.clone(),
+                        )
+                        .await
+                    }
+                };
+            }
+
+            if response.status() == StatusCode::NOT_FOUND {
+                if let Some(fallback) = endpoint.spa_fallback() {
+                    if request_headers
+                        .get(header::ACCEPT)
+                        .and_then(|accept| accept.to_str().ok())
+                        .is_some_and(|accept| accepts(accept, "text/html"))
+                    {
+                        let fallback_path = format!(
+                            "{}/{}",
+                            endpoint.path().trim_end_matches('/'),
+                            fallback.trim_start_matches('/')
+                        );
+
+                        response = fetch_object(
+                            &request_bucket,
+                            config,
+                            endpoint,
+                            &fallback_path,
+                            request_headers,
+                            command,
+                        )
+                        .await;
+                    }
+
+                    // missing assets are answered with 404 for other clients
+                    response
+                        .headers_mut()
+                        .append(header::VARY, HeaderValue::from_static("Accept"));
+                }
+            }
+
+            if response.status() == StatusCode::NOT_FOUND {
+                if let Some(url) = endpoint.not_found_redirect() {
+                    response = make_not_found_redirect_response(endpoint, url, path);
+                }
+            }
+
+            if endpoint.image_variants() && has_image_variants(path) {
+                response
+                    .headers_mut()
+                    .append(header::VARY, HeaderValue::from_static("Accept"));
+            }
+
+            if endpoint.i18n_locales().is_some() {
+                response
+                    .headers_mut()
+                    .append(header::VARY, HeaderValue::from_static("Accept-Language"));
+            }
+
+            if endpoint.precompressed() {
+                response
+                    .headers_mut()
+                    .append(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+            }
+
+            if bust_caches && response.status() == StatusCode::OK && is_html(&response) {
+                bust_asset_caches(response, bucket, config, path, host, etags).await
+            } else {
+                response
+            }
+        }
+        None => make_not_found_response().into_response(),
+    };
+
+    if let Some(cors) = endpoint.and_then(|e| e.cors()).or_else(|| config.cors()) {
+        apply_cors(response.headers_mut(), cors, request_headers);
+    }
+
+    response
+}
+
+/// Extracts the text of element `name` from the XML body of an S3 error
+/// response.
+fn s3_error_element<'a>(body: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{name}>");
+    let start = body.find(&open)? + open.len();
+    let end = start + body[start..].find(&format!("</{name}>"))?;
+
+    Some(&body[start..end])
+}
+
+/// Extracts the `Code` from the XML body of an S3 error response.
+fn s3_error_code(body: &str) -> Option<&str> {
+    s3_error_element(body, "Code")
+}
+
+async fn proxy_endpoint_request(
+    bucket: &Bucket,
+    config: &Configuration,
+    endpoint: &Endpoint,
+    path: &str,
+    host: Option<&str>,
+    command: Command<'_>,
+) -> impl IntoResponse {
+    let bucket_path = get_bucket_path(path, host, endpoint);
+
+    let Some(mut bucket_path) = bucket_path else {
+        return make_not_found_response().into_response();
+    };
+    if let Some(index_document) = endpoint.index_document() {
+        if bucket_path.ends_with('/') {
+            bucket_path.push_str(index_document);
+        }
+    }
+
+    // presigned URLs are only valid for GET, so HEAD requests are proxied
+    if endpoint.mode() == EndpointMode::Redirect
+        && matches!(command, Command::GetObject | Command::GetObjectRange { .. })
+    {
+        return make_presigned_redirect_response(bucket, config, endpoint, &bucket_path);
+    }
+
+    // conditional requests are answered differently per client, ranges are
+    // usually requested by a single client
+    let coalescer = config
+        .coalescer()
+        .filter(|_| matches!(command, Command::GetObject) && bucket.extra_headers.is_empty());
+
+    let mut response = match coalescer {
+        Some(coalescer) => {
+            let key = coalesce::Key {
+                bucket: bucket.url(),
+                endpoint: endpoint.path().to_owned(),
+                path: path.to_owned(),
+                bucket_path: bucket_path.clone(),
+            };
+
+            coalescer
+                .run(key, config.coalesce_max_size(), || {
+                    make_endpoint_response(bucket, config, endpoint, path, &bucket_path, command)
+                })
+                .await
+        }
+        None => make_endpoint_response(bucket, config, endpoint, path, &bucket_path, command)
+            .await
+            .into_response(),
+    };
+    response.extensions_mut().insert(BucketPath(bucket_path));
+
+    response
+}
+
+/// Returns the exponential backoff before retry number `attempt` (counting
+/// from zero), with a random jitter of up to half of it so retries of
+/// concurrent requests spread out.
+fn retry_delay(base_delay: Duration, attempt: u32) -> Duration {
+    use std::hash::{BuildHasher, Hasher};
+
+    let delay = base_delay.saturating_mul(2u32.saturating_pow(attempt));
+    // randomly keyed for every call, which is good enough for jitter
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+
+    delay / 2 + delay.mul_f64((random % 1000) as f64 / 2000.0)
+}
+
+/// Redirects to a presigned URL of the object at `bucket_path`. The client
+/// sends its `Range` and conditional headers along to the bucket.
+fn make_presigned_redirect_response(
+    bucket: &Bucket,
+    config: &Configuration,
+    endpoint: &Endpoint,
+    bucket_path: &str,
+) -> Response {
+    let expiry = endpoint.presign_expiry().as_secs() as u32;
+
+    match bucket.presign_get(bucket_path, expiry, None) {
+        Ok(url) => match HeaderValue::from_str(&url) {
+            Ok(location) => (
+                StatusCode::FOUND,
+                [
+                    (header::LOCATION, location),
+                    // the URL expires, so the redirect mustn't outlive it
+                    (header::CACHE_CONTROL, HeaderValue::from_static("no-store")),
+                ],
+            )
+                .into_response(),
+            Err(err) => make_upstream_error_response(config, &err),
+        },
+        Err(err) => make_upstream_error_response(config, &err),
+    }
+}
+
+async fn make_endpoint_response(
+    bucket: &Bucket,
+    config: &Configuration,
+    endpoint: &Endpoint,
+    path: &str,
+    bucket_path: &str,
+    command: Command<'_>,
+) -> Response {
+    let err = match make_proxy_response(bucket, config, endpoint, path, bucket_path, command).await
+    {
+        Ok(response) => return response.into_response(),
+        Err(err) => err,
+    };
+
+    match err {
+        s3::error::S3Error::Http(404, _response) => make_not_found_response().into_response(),
+        s3::error::S3Error::Http(412, _response) => StatusCode::PRECONDITION_FAILED.into_response(),
+        s3::error::S3Error::Http(416, response) => {
+            make_range_not_satisfied_response(bucket, bucket_path, &response).await
+        }
+        // HEAD responses carry no error body to tell the code from
+        s3::error::S3Error::Http(403, ref response)
+            if response.is_empty() || s3_error_code(response) == Some("AccessDenied") =>
+        {
+            if config.access_denied_as_not_found() {
+                make_not_found_response().into_response()
+            } else {
+                (StatusCode::FORBIDDEN, "Forbidden").into_response()
+            }
+        }
+        _ => make_upstream_error_response(config, &err),
+    }
+}
+
+/// Checks whether a request announces a body, either by a non-zero
+/// `Content-Length` or by `Transfer-Encoding`.
+fn has_body(request_headers: &HeaderMap) -> bool {
+    request_headers.contains_key(header::TRANSFER_ENCODING)
+        || content_length(request_headers).is_some_and(|length| length > 0)
+}
+
+#[tracing::instrument(skip_all, fields(path = config.log_paths().format(&path)))]
+async fn get_file(
+    Path(path): Path<String>,
+    range: Option<TypedHeader<Range>>,
+    headers: HeaderMap,
+    Extension(buckets): Extension<Buckets>,
+    Extension(config): Extension<Configuration>,
+    Extension(etags): Extension<EtagCache>,
+    Extension(maintenance): Extension<MaintenanceMode>,
+) -> impl IntoResponse {
+    tracing::info!("GET");
+
+    if config.reject_get_body() && has_body(&headers) {
+        return (StatusCode::BAD_REQUEST, "GET requests must not have a body").into_response();
+    }
+
+    if config.unsupported_range_unit() == UnsupportedRangeUnit::Reject {
+        if let Some(value) = headers.get(header::RANGE) {
+            if range_unit(value) != Some("bytes") {
+                return (StatusCode::BAD_REQUEST, "Unsupported range unit").into_response();
+            }
+        }
+    }
+
+    let range = range.and_then(|TypedHeader(range)| s3_range_for_header(range));
+
+    if config.strict_range() {
+        if let Some(value) = headers.get(header::RANGE) {
+            let well_formed = value.to_str().is_ok_and(range_is_well_formed);
+
+            if !well_formed || range.is_none() {
+                return (StatusCode::BAD_REQUEST, "Invalid or unsupported range").into_response();
+            }
+        }
+    }
+
+    let command = match range {
+        Some(ByteRange::FromTo(start, end)) => Command::GetObjectRange {
+            start,
+            end: Some(end),
+        },
+        // a start beyond the object is answered with 416 by the bucket
+        Some(ByteRange::From(start)) => Command::GetObjectRange { start, end: None },
+        Some(range @ ByteRange::Suffix(_)) => {
+            // S3 ranges can't start from the end, so the range is resolved
+            // against the object's length first. Ranges refer to the
+            // original, so no pre-compressed variant may be measured.
+            let mut head_headers = headers.clone();
+            head_headers.remove(header::ACCEPT_ENCODING);
+
+            let response = proxy_request(
+                &buckets,
+                &config,
+                path.as_str(),
+                &head_headers,
+                Command::HeadObject,
+                &etags,
+                &maintenance,
+            )
+            .await
+            .into_response();
+
+            let length = match content_length(response.headers()) {
+                Some(length) if response.status() == StatusCode::OK => length,
+                _ => return response,
+            };
+
+            match range.resolve(length) {
+                Some((start, end)) => Command::GetObjectRange {
+                    start,
+                    end: Some(end),
+                },
+                None => return make_range_not_satisfiable_response(length),
+            }
+        }
+        None => Command::GetObject,
+    };
+
+    proxy_request(
+        &buckets,
+        &config,
+        path.as_str(),
+        &headers,
+        command,
+        &etags,
+        &maintenance,
+    )
+    .await
+    .into_response()
+}
+
+#[tracing::instrument(skip_all, fields(path = config.log_paths().format(&path)))]
+async fn head_file(
+    Path(path): Path<String>,
+    range: Option<TypedHeader<Range>>,
+    headers: HeaderMap,
+    Extension(buckets): Extension<Buckets>,
+    Extension(config): Extension<Configuration>,
+    Extension(etags): Extension<EtagCache>,
+    Extension(maintenance): Extension<MaintenanceMode>,
+) -> impl IntoResponse {
+    tracing::info!("HEAD");
+
+    let command = Command::HeadObject;
+
+    let response = proxy_request(
+        &buckets,
+        &config,
+        path.as_str(),
+        &headers,
+        command,
+        &etags,
+        &maintenance,
+    )
+    .await
+    .into_response();
+
+    match (config.head_range(), range) {
+        (HeadRange::Partial, Some(TypedHeader(range))) if response.status() == StatusCode::OK => {
+            match s3_range_for_header(range) {
+                Some(range) => make_partial_head_response(response, range),
+                None => response,
+            }
+        }
+        _ => response,
+    }
+}
+
+/// Formats the `Allow` header for an endpoint enabled for `methods`.
+fn allow_header(methods: &[EndpointMethod]) -> HeaderValue {
+    let methods = methods
+        .iter()
+        .map(|method| method.as_str())
+        .chain(["OPTIONS"])
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    HeaderValue::from_str(&methods).expect("method names are valid header values")
+}
+
+/// Returns the methods enabled for `endpoint`, which never include PUT in
+/// read-only mode and DELETE without `allow_delete`. Paths without an
+/// endpoint get all methods that could be enabled.
+fn enabled_methods(config: &Configuration, endpoint: Option<&Endpoint>) -> Vec<EndpointMethod> {
+    let mut methods = match endpoint {
+        Some(endpoint) => endpoint.methods().to_vec(),
+        None => vec![
+            EndpointMethod::Get,
+            EndpointMethod::Head,
+            EndpointMethod::Put,
+            EndpointMethod::Delete,
+        ],
+    };
+
+    methods.retain(|method| match method {
+        EndpointMethod::Put => !config.read_only(),
+        EndpointMethod::Delete => config.allow_delete(),
+        _ => true,
+    });
+
+    methods
+}
+
+/// Dispatches requests by method, if the matched endpoint is enabled for it.
+/// Requests for paths without an endpoint are answered with 404 by the
+/// method's handler.
+#[allow(clippy::too_many_arguments)]
+async fn handle_request(
+    method: Method,
+    path: Path<String>,
+    range: Option<TypedHeader<Range>>,
+    headers: HeaderMap,
+    buckets: Extension<Buckets>,
+    config: Extension<Configuration>,
+    etags: Extension<EtagCache>,
+    maintenance: Extension<MaintenanceMode>,
+    RawBody(body): RawBody,
+) -> Response {
+    if !expectation_supported(&headers) {
+        return (StatusCode::EXPECTATION_FAILED, "Unsupported expectation").into_response();
+    }
+
+    if let Some(max_path_depth) = config.max_path_depth() {
+        // every segment follows a slash, e.g. 3 for `/a/b/c.txt`
+        if path.matches('/').count() > max_path_depth {
+            return (StatusCode::BAD_REQUEST, "Path too deep").into_response();
+        }
+    }
+
+    let endpoint = config.endpoints().find(&path);
+    let methods = enabled_methods(&config, endpoint);
+
+    // preflight requests never carry credentials
+    if let Some(basic_auth) = endpoint.and_then(Endpoint::basic_auth) {
+        if method != Method::OPTIONS && !is_basic_authorized(basic_auth, &headers) {
+            return make_basic_auth_response(basic_auth);
+        }
+    }
+
+    match method {
+        Method::GET if methods.contains(&EndpointMethod::Get) => {
+            get_file(path, range, headers, buckets, config, etags, maintenance)
+                .await
+                .into_response()
+        }
+        Method::HEAD if methods.contains(&EndpointMethod::Head) => {
+            head_file(path, range, headers, buckets, config, etags, maintenance)
+                .await
+                .into_response()
+        }
+        Method::PUT if methods.contains(&EndpointMethod::Put) => {
+            put_file(path, headers, buckets, config, maintenance, body).await
+        }
+        Method::DELETE if methods.contains(&EndpointMethod::Delete) => {
+            delete_file(path, headers, buckets, config, maintenance).await
+        }
+        Method::OPTIONS => match endpoint {
+            Some(endpoint) => {
+                let mut response_headers = HeaderMap::new();
+                response_headers.insert(header::ALLOW, allow_header(&methods));
+
+                if let Some(cors) = endpoint.cors().or_else(|| config.cors()) {
+                    apply_cors_preflight(&mut response_headers, cors, &headers, &methods);
+                }
+
+                (StatusCode::NO_CONTENT, response_headers).into_response()
+            }
+            None => make_not_found_response().into_response(),
+        },
+        _ => (
+            StatusCode::METHOD_NOT_ALLOWED,
+            [(header::ALLOW, allow_header(&methods))],
+        )
+            .into_response(),
+    }
+}
+
+fn is_basic_authorized(basic_auth: &BasicAuth, headers: &HeaderMap) -> bool {
+    headers
+        .typed_get::<Authorization<Basic>>()
+        .is_some_and(|Authorization(basic)| basic_auth.verify(basic.username(), basic.password()))
+}
+
+fn make_basic_auth_response(basic_auth: &BasicAuth) -> Response {
+    let challenge = format!("Basic realm=\"{}\", charset=\"UTF-8\"", basic_auth.realm());
+
+    (
+        StatusCode::UNAUTHORIZED,
+        [(
+            header::WWW_AUTHENTICATE,
+            HeaderValue::from_str(&challenge).expect("the realm is validated"),
+        )],
+        "Unauthorized",
+    )
+        .into_response()
+}
+
+/// Maps the path of an upload or deletion to its key in the bucket.
+fn write_bucket_path<'a>(
+    buckets: &'a Buckets,
+    config: &Configuration,
+    path: &str,
+    headers: &HeaderMap,
+) -> Option<(&'a Bucket, String)> {
+    let endpoint = config.endpoints().find(path)?;
+    let bucket_path = get_bucket_path(path, request_host(headers), endpoint)?;
+
+    Some((buckets.get(endpoint), bucket_path))
+}
+
+/// Uploads the request body to the object the path is mapped to.
+///
+/// The body is buffered, as the S3 client can't stream uploads of unknown
+/// length in a single request.
+#[tracing::instrument(skip_all, fields(path = config.log_paths().format(&path)))]
+async fn put_file(
+    Path(path): Path<String>,
+    headers: HeaderMap,
+    Extension(buckets): Extension<Buckets>,
+    Extension(config): Extension<Configuration>,
+    Extension(maintenance): Extension<MaintenanceMode>,
+    body: Body,
+) -> Response {
+    tracing::info!("PUT");
+
+    if maintenance.is_enabled() {
+        return make_maintenance_response(config.maintenance());
+    }
+
+    let (bucket, bucket_path) = match write_bucket_path(&buckets, &config, &path, &headers) {
+        Some(target) => target,
+        None => return make_not_found_response().into_response(),
+    };
+
+    let content = match read_upload(body, &headers, config.max_upload_size()).await {
+        Ok(content) => content,
+        Err(response) => return response,
+    };
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or("application/octet-stream");
+
+    let command = Command::PutObject {
+        content: &content,
+        content_type,
+        multipart: None,
+    };
+
+    match Reqwest::new(bucket, &bucket_path, command).response().await {
+        Ok(response) => {
+            let mut response_headers = HeaderMap::new();
+            copy_headers(&mut response_headers, response.headers(), &[header::ETAG]);
+
+            (response.status(), response_headers).into_response()
+        }
+        Err(err) => make_upstream_error_response(&config, &err),
+    }
+}
+
+/// Reads an upload of at most `max_size` bytes, answering larger ones with 413
+/// as soon as they exceed it, without reading the rest of the body.
+async fn read_upload(
+    mut body: Body,
+    headers: &HeaderMap,
+    max_size: u64,
+) -> Result<Vec<u8>, Response> {
+    let too_large = || {
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("Uploads are limited to {max_size} bytes"),
+        )
+            .into_response()
+    };
+
+    let length = content_length(headers);
+    if length.is_some_and(|length| length > max_size) {
+        return Err(too_large());
+    }
+
+    let mut content = Vec::with_capacity(length.unwrap_or_default() as usize);
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| {
+            tracing::warn!("Couldn't read request body: {}", err);
+            StatusCode::BAD_REQUEST.into_response()
+        })?;
+
+        if (content.len() + chunk.len()) as u64 > max_size {
+            return Err(too_large());
+        }
+        content.extend_from_slice(&chunk);
+    }
+
+    Ok(content)
+}
+
+/// Deletes the object the path is mapped to.
+#[tracing::instrument(skip_all, fields(path = config.log_paths().format(&path)))]
+async fn delete_file(
+    Path(path): Path<String>,
+    headers: HeaderMap,
+    Extension(buckets): Extension<Buckets>,
+    Extension(config): Extension<Configuration>,
+    Extension(maintenance): Extension<MaintenanceMode>,
+) -> Response {
+    tracing::info!("DELETE");
+
+    if maintenance.is_enabled() {
+        return make_maintenance_response(config.maintenance());
+    }
+
+    let (bucket, bucket_path) = match write_bucket_path(&buckets, &config, &path, &headers) {
+        Some(target) => target,
+        None => return make_not_found_response().into_response(),
+    };
+
+    match Reqwest::new(bucket, &bucket_path, Command::DeleteObject)
+        .response()
+        .await
+    {
+        Ok(response) => response.status().into_response(),
+        Err(err) => make_upstream_error_response(&config, &err),
+    }
+}
+
+/// Turns a full HEAD response into the `206 Partial Content` response a ranged
+/// GET would produce, computed from the object's length. Responses for objects
+/// the bucket doesn't support ranges for are kept as they are.
+fn make_partial_head_response(mut response: Response, range: ByteRange) -> Response {
+    if response
+        .headers()
+        .get(header::ACCEPT_RANGES)
+        .is_some_and(|value| value == "none")
+    {
+        return response;
+    }
+
+    let length = match content_length(response.headers()) {
+        Some(length) => length,
+        None => return response,
+    };
+
+    let (start, end) = match range.resolve(length) {
+        Some(range) => range,
+        None => return make_range_not_satisfiable_response(length),
+    };
+
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_LENGTH, (end - start + 1).into());
+    if let Ok(content_range) = HeaderValue::from_str(&format!("bytes {start}-{end}/{length}")) {
+        headers.insert(header::CONTENT_RANGE, content_range);
+    }
+    *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+
+    response
+}
+
+/// Checks whether there is at least one object below the endpoint's `bucket_path`.
+async fn probe_endpoint(bucket: &Bucket, endpoint: &Endpoint) -> Result<bool, s3::error::S3Error> {
+    let prefix = endpoint.bucket_path().trim_start_matches('/').to_owned();
+
+    let (result, _status) = bucket.list_page(prefix, None, None, None, Some(1)).await?;
+
+    Ok(!result.contents.is_empty())
+}
+
+/// Checks whether there is at least one object below `path/`.
+///
+/// Sharded keys don't share prefixes, so they're never directories.
+async fn is_implicit_directory(
+    bucket: &Bucket,
+    endpoint: &Endpoint,
+    path: &str,
+    host: Option<&str>,
+) -> bool {
+    if endpoint.sharding().is_some() {
+        return false;
+    }
+    let Some(bucket_path) = get_bucket_path(&format!("{path}/"), host, endpoint) else {
+        return false;
+    };
+    let prefix = bucket_path.trim_start_matches('/').to_owned();
+
+    match bucket.list_page(prefix, None, None, None, Some(1)).await {
+        Ok((result, _status)) => !result.contents.is_empty(),
+        Err(err) => {
+            tracing::debug!("Couldn't list objects below {bucket_path}: {err}");
+            false
+        }
+    }
+}
+
+/// Probes all endpoints and logs the result, warning about empty prefixes
+/// which are likely a misconfiguration.
+async fn probe_endpoints(buckets: &Buckets, endpoints: &Endpoints) {
+    for endpoint in endpoints.iter() {
+        match probe_endpoint(buckets.get(endpoint), endpoint).await {
+            Ok(true) => tracing::info!(
+                "Endpoint {} has objects under {}",
+                endpoint.path(),
+                endpoint.bucket_path()
+            ),
+            Ok(false) => tracing::warn!(
+                "Endpoint {} has no objects under {}, is the bucket_path correct?",
+                endpoint.path(),
+                endpoint.bucket_path()
+            ),
+            Err(err) => tracing::warn!("Couldn't probe endpoint {}: {err}", endpoint.path()),
+        }
+    }
+}
+
+/// Answers requests not matching any route, notably `OPTIONS *` which asks
+/// for the server's capabilities instead of those of a resource.
+async fn fallback(
+    method: Method,
+    uri: Uri,
+    Extension(config): Extension<Configuration>,
+) -> Response {
+    if method == Method::OPTIONS && uri == "*" {
+        (
+            StatusCode::NO_CONTENT,
+            [(header::ALLOW, allow_header(&enabled_methods(&config, None)))],
+        )
+            .into_response()
+    } else {
+        make_not_found_response().into_response()
+    }
+}
+
+fn make_router(live: LiveConfig) -> Router {
+    let loaded = live.load();
+    let config = &loaded.config;
+
+    let proxy = Router::new()
+        .route("/*path", any(handle_request))
+        .fallback(fallback.into_service());
+
+    // matchit doesn't allow static routes next to the catch-all, so any
+    // request not handled by the probes or admin routes falls through to the
+    // proxy. Neither are logged, as they're polled frequently.
+    let mut router = Router::new()
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz));
+
+    let bucket_health = BucketHealth::default();
+    if let Some(interval) = config.bucket_health_interval() {
+        router = router.route("/healthz/buckets", get(health::bucket_health));
+
+        tokio::spawn(health::check_buckets(
+            live.clone(),
+            bucket_health.clone(),
+            interval,
+        ));
+    }
+
+    if config.admin().is_some() {
+        router = router
+            .route("/admin/debug", get(admin::debug))
+            .route("/admin/maintenance", put(admin::set_maintenance));
+    }
+
+    if config.metrics().enabled() {
+        router = router.route(config.metrics().path(), get(metrics::metrics));
+    }
+
+    let mut router = router.fallback(proxy);
+
+    if config.compression().enabled() {
+        router = compression::compress(router, config.compression().min_size());
+    }
+
+    router
+        .layer(middleware::from_fn(https::require_https))
+        .layer(middleware::from_fn(rate_limit::limit_requests))
+        .layer(middleware::from_fn(metrics::track_requests))
+        .layer(middleware::from_fn(access_log::log_requests))
+        .layer(Extension(StartTime(Instant::now())))
+        .layer(Extension(MaintenanceMode::new(
+            config.maintenance().enabled(),
+        )))
+        .layer(Extension(bucket_health))
+        .layer(middleware::from_fn(move |request, next| {
+            live.clone().provide(request, next)
+        }))
+        .layer(Extension(EtagCache::default()))
+        .layer(Extension(RateLimiter::default()))
+}
+
+/// Binds the listener to `addr` and applies the configured socket options.
+fn bind_listener(http: &Http, addr: std::net::SocketAddr) -> anyhow::Result<AddrIncoming> {
+    let mut incoming = AddrIncoming::bind(&addr)?;
+    incoming
+        .set_nodelay(http.tcp_nodelay())
+        .set_keepalive(http.tcp_keepalive());
+
+    Ok(incoming)
+}
+
+/// Checks that the bucket's endpoint negotiates at least `min_version`.
+///
+/// `rust-s3` builds a new HTTP client for every request without a way to
+/// configure it, so the version can't be enforced on the requests themselves.
+/// Instead this does a handshake limited to `min_version` and newer at
+/// startup. As both sides pick the highest version they support, an endpoint
+/// passing this check is talked to with at least that version.
+async fn check_upstream_tls(bucket: &Bucket, min_version: TlsVersion) -> anyhow::Result<()> {
+    let url: Uri = bucket.url().parse()?;
+
+    if url.scheme_str() != Some("https") {
+        tracing::warn!("Bucket endpoint {} doesn't use TLS", url);
+        return Ok(());
+    }
+
+    let host = url.host().context("Bucket endpoint has no host")?;
+    let port = url.port_u16().unwrap_or(443);
+    let protocol = match min_version {
+        TlsVersion::Tls10 => native_tls::Protocol::Tlsv10,
+        TlsVersion::Tls11 => native_tls::Protocol::Tlsv11,
+        TlsVersion::Tls12 => native_tls::Protocol::Tlsv12,
+    };
+
+    let connector = native_tls::TlsConnector::builder()
+        .min_protocol_version(Some(protocol))
+        .build()?;
+    let stream = tokio::net::TcpStream::connect((host, port)).await?;
+
+    tokio_native_tls::TlsConnector::from(connector)
+        .connect(host, stream)
+        .await
+        .with_context(|| {
+            format!("Bucket endpoint {host}:{port} doesn't support TLS {min_version:?} or newer")
+        })?;
+
+    Ok(())
+}
+
+/// Checks that the endpoints of all `buckets` negotiate at least the
+/// configured `min_upstream_tls_version`.
+async fn check_buckets_tls(buckets: &Buckets, config: &Configuration) -> anyhow::Result<()> {
+    for bucket in buckets.iter() {
+        check_upstream_tls(bucket, config.min_upstream_tls_version()).await?;
+    }
+
+    Ok(())
+}
+
+async fn start_server(config: &Configuration, config_file: &std::path::Path) -> anyhow::Result<()> {
+    let buckets = Buckets::new(config)?;
+    check_buckets_tls(&buckets, config).await?;
+
+    if config.startup_probe_endpoints() {
+        probe_endpoints(&buckets, config.endpoints()).await;
+    }
+
+    let live = LiveConfig::new(config.clone(), buckets);
+    tokio::spawn(reload::reload_on_sighup(
+        live.clone(),
+        config_file.to_owned(),
+    ));
+    tokio::spawn(reload::refresh_credentials(live.clone()));
+
+    let router = make_router(live);
+    let http = config.http();
+
+    let rustls = match http.tls() {
+        Some(tls) => Some(
+            RustlsConfig::from_pem_file(tls.cert_path(), tls.key_path())
+                .await
+                .with_context(|| {
+                    format!(
+                        "Couldn't load TLS certificate {} or key {}",
+                        tls.cert_path().display(),
+                        tls.key_path().display()
+                    )
+                })?,
+        ),
+        None => None,
+    };
+
+    // stops all servers when the first one fails, e.g. as it couldn't bind
+    let mut servers = tokio::task::JoinSet::new();
+    for addr in http.listen_addrs()? {
+        servers.spawn(serve_listen_addr(
+            addr,
+            http.clone(),
+            router.clone(),
+            rustls.clone(),
+        ));
+    }
+
+    while let Some(result) = servers.join_next().await {
+        result??;
+    }
+
+    Ok(())
+}
+
+/// Serves `router` on `addr` until shutdown, over HTTPS if `rustls` is set.
+async fn serve_listen_addr(
+    addr: ListenAddr,
+    http: Http,
+    router: Router,
+    rustls: Option<RustlsConfig>,
+) -> anyhow::Result<()> {
+    let addr = match addr {
+        ListenAddr::Tcp(addr) => addr,
+        #[cfg(unix)]
+        ListenAddr::Unix(path) => {
+            tracing::info!("Listening on unix:{}", path.display());
+
+            return shutdown::serve_unix(
+                &path,
+                router,
+                shutdown::signal(),
+                http.shutdown_timeout(),
+            )
+            .await;
+        }
+        #[cfg(not(unix))]
+        ListenAddr::Unix(path) => anyhow::bail!(
+            "Unix sockets aren't supported on this platform: {}",
+            path.display()
+        ),
+    };
+
+    if let Some(rustls) = rustls {
+        let server = axum_server::bind_rustls(addr, rustls).addr_incoming_config(
+            AddrIncomingConfig::new()
+                .tcp_nodelay(http.tcp_nodelay())
+                .tcp_keepalive(http.tcp_keepalive())
+                .build(),
+        );
+
+        tracing::info!("Listening on https://{addr}/");
+
+        return shutdown::serve_tls(server, router, shutdown::signal(), http.shutdown_timeout())
+            .await;
+    }
+
+    let incoming = bind_listener(&http, addr)?;
+
+    tracing::info!("Listening on http://{}/", incoming.local_addr());
+
+    shutdown::serve(
+        incoming,
+        router,
+        shutdown::signal(),
+        http.shutdown_timeout(),
+    )
+    .await
+}
+
+fn config_file() -> PathBuf {
+    std::env::var("S3PROXY_CONFIG")
+        .unwrap_or_else(|_| "s3-proxy.yaml".to_owned())
+        .into()
+}
+
+type LogLayer = Box<dyn tracing_subscriber::Layer<tracing_subscriber::Registry> + Send + Sync>;
+
+/// Installs a subscriber logging in text, whose format can be switched once
+/// the configuration is loaded, so messages logged while loading it aren't
+/// dropped.
+fn init_logging() -> tracing_subscriber::reload::Handle<LogLayer, tracing_subscriber::Registry> {
+    use tracing_subscriber::{filter::LevelFilter, layer::SubscriberExt, util::SubscriberInitExt};
+
+    let (format, handle) = tracing_subscriber::reload::Layer::new(Box::new(
+        tracing_subscriber::fmt::layer(),
+    ) as LogLayer);
+    tracing_subscriber::registry()
+        .with(format)
+        .with(LevelFilter::INFO)
+        .init();
+
+    handle
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    dotenv::dotenv().ok();
+
+    let log_format = init_logging();
+
+    let config_file = config_file();
+    let config = Configuration::from_file(&config_file).await?;
+
+    if config.log_format() == LogFormat::Json {
+        log_format.reload(
+            Box::new(tracing_subscriber::fmt::layer().json().flatten_event(true)) as LogLayer,
+        )?;
+    }
+
+    start_server(&config, &config_file).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+
+    use crate::mock_s3::{MockObject, MockS3};
+
+    use super::*;
+
+    const ROOT_ENDPOINT: &str = r#"
+endpoints:
+  - path: "/"
+    bucket_path: "/"
+"#;
+
+    fn make_test_config(mock: &MockS3, yaml: &str) -> Configuration {
+        Configuration::from_yaml(&format!(
+            "{}http:\n  bind: \"127.0.0.1\"\n  port: 8000\n{yaml}",
+            mock.bucket_yaml()
+        ))
+        .unwrap()
+    }
+
+    fn make_test_router(mock: &MockS3, yaml: &str) -> Router {
+        let config = make_test_config(mock, yaml);
+        let buckets = Buckets::new(&config).unwrap();
+
+        make_router(LiveConfig::new(config, buckets))
+    }
+
+    /// Binds a listener for `http_yaml` and accepts a single connection.
+    async fn accept_connection(http_yaml: &str) -> tokio::net::TcpStream {
+        use hyper::server::accept::Accept;
+
+        let http: Http = serde_yaml::from_str(http_yaml).unwrap();
+
+        let [ListenAddr::Tcp(addr)] = http.listen_addrs().unwrap()[..] else {
+            panic!("{http_yaml} doesn't bind a single address");
+        };
+        let mut incoming = bind_listener(&http, addr).unwrap();
+        let client = tokio::net::TcpStream::connect(incoming.local_addr());
+
+        let (_client, stream) = tokio::join!(
+            client,
+            std::future::poll_fn(|cx| std::pin::Pin::new(&mut incoming).poll_accept(cx))
+        );
+
+        stream.unwrap().unwrap().into_inner()
+    }
+
+    #[tokio::test]
+    async fn test_bind_listener_socket_options() {
+        let stream = accept_connection(
+            "bind: \"127.0.0.1\"\nport: 0\ntcp_nodelay: true\ntcp_keepalive: 30\n",
+        )
+        .await;
+
+        assert!(stream.nodelay().unwrap());
+        assert!(socket2::SockRef::from(&stream).keepalive().unwrap());
+
+        let stream = accept_connection("bind: \"127.0.0.1\"\nport: 0\n").await;
+
+        assert!(!stream.nodelay().unwrap());
+        assert!(!socket2::SockRef::from(&stream).keepalive().unwrap());
+    }
+
+    #[test]
+    fn test_get_bucket_path() {
+        let endpoint = Endpoint::new("/media/".to_owned(), "/app/files".to_owned());
+
+        let bucket_path = get_bucket_path("/media/foo/bar", None, &endpoint);
+
+        assert_eq!(bucket_path.as_deref(), Some("/app/files/foo/bar"));
+    }
+
+    #[test]
+    fn test_get_bucket_path_traversal() {
+        let endpoint = Endpoint::new("/media/".to_owned(), "/app/files".to_owned());
+
+        for path in [
+            "/media/../../secret",
+            "/media/a/../b",
+            "/media/..",
+            "/media//..",
+        ] {
+            assert_eq!(get_bucket_path(path, None, &endpoint), None, "{path}");
+        }
+        assert_eq!(
+            get_bucket_path("/media/a..b/...", None, &endpoint).as_deref(),
+            Some("/app/files/a..b/...")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_path_traversal() {
+        let mock = MockS3::start().await;
+        mock.put_object("secret.txt", b"secret", "text/plain");
+
+        let router = make_test_router(
+            &mock,
+            r#"
+endpoints:
+  - path: "/media/"
+    bucket_path: "/public/"
+"#,
+        );
+
+        for path in [
+            "/media/../secret.txt",
+            "/media/%2e%2e/secret.txt",
+            "/media/%2E%2E%2Fsecret.txt",
+            "/media/.%2e/secret.txt",
+        ] {
+            let response = router
+                .clone()
+                .oneshot(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{path}");
+        }
+        assert!(mock.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_check_upstream_tls() {
+        let mock = MockS3::start().await;
+        let bucket = make_test_config(&mock, "endpoints: []\n")
+            .bucket()
+            .unwrap()
+            .make_s3_bucket()
+            .unwrap();
+
+        // plain HTTP is only warned about
+        assert!(check_upstream_tls(&bucket, TlsVersion::Tls12).await.is_ok());
+
+        let bucket = Configuration::from_yaml(&format!(
+            "{}http:\n  bind: \"127.0.0.1\"\n  port: 8000\nendpoints: []\n",
+            mock.bucket_yaml().replace("http://", "https://")
+        ))
+        .unwrap()
+        .bucket()
+        .unwrap()
+        .make_s3_bucket()
+        .unwrap();
+
+        // the mock doesn't speak TLS at all
+        assert!(check_upstream_tls(&bucket, TlsVersion::Tls12)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_probe_endpoint() {
+        let mock = MockS3::start().await;
+        mock.put_object("app/media/a.jpg", b"a", "image/jpeg");
+
+        let bucket = make_test_config(&mock, "endpoints: []\n")
+            .bucket()
+            .unwrap()
+            .make_s3_bucket()
+            .unwrap();
+
+        let populated = Endpoint::new("/media/".to_owned(), "/app/media/".to_owned());
+        let typo = Endpoint::new("/pdfs/".to_owned(), "/app/pfds/".to_owned());
+
+        assert!(probe_endpoint(&bucket, &populated).await.unwrap());
+        assert!(!probe_endpoint(&bucket, &typo).await.unwrap());
+    }
+
+    #[test]
+    fn test_default_bucket_path() {
+        let endpoints: Endpoints = serde_yaml::from_str(
+            r#"
+- path: "/media/"
+- path: "/pdfs/"
+  bucket_path: "/documents/pdfs/"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            get_bucket_path("/media/a/b.jpg", None, endpoints.find("/media/").unwrap()).as_deref(),
+            Some("/media/a/b.jpg")
+        );
+        assert_eq!(
+            get_bucket_path("/pdfs/c.pdf", None, endpoints.find("/pdfs/").unwrap()).as_deref(),
+            Some("/documents/pdfs/c.pdf")
+        );
+    }
+
+    #[test]
+    fn test_get_sharded_bucket_path() {
+        let endpoints: Endpoints = serde_yaml::from_str(
+            r#"
+- path: "/md5/"
+  bucket_path: "/files"
+  sharding:
+    algorithm: md5
+- path: "/sha256/"
+  bucket_path: "/files"
+  sharding:
+    algorithm: sha256
+    depth: 1
+"#,
+        )
+        .unwrap();
+
+        // md5("photo.jpg") = 72acded3acd45e4c8b6ed680854b8ab1
+        assert_eq!(
+            get_bucket_path("/md5/photo.jpg", None, endpoints.find("/md5/").unwrap()).as_deref(),
+            Some("/files/72/ac/photo.jpg")
+        );
+        // sha256("photo.jpg") = aff6100bd4df0ea6...
+        assert_eq!(
+            get_bucket_path(
+                "/sha256/photo.jpg",
+                None,
+                endpoints.find("/sha256/").unwrap()
+            )
+            .as_deref(),
+            Some("/files/af/photo.jpg")
+        );
+    }
+
+    #[test]
+    fn test_tenant_from_subdomain() {
+        let endpoints: Endpoints = serde_yaml::from_str(
+            r#"
+- path: "/media/"
+  bucket_path: "/tenants"
+  tenant_from_subdomain: true
+"#,
+        )
+        .unwrap();
+        let endpoint = endpoints.find("/media/").unwrap();
+
+        assert_eq!(
+            get_bucket_path("/media/x", Some("tenant1.example.com"), endpoint).as_deref(),
+            Some("/tenants/tenant1/x")
+        );
+        assert_eq!(
+            get_bucket_path("/media/x", Some("Tenant-2.example.com:8000"), endpoint).as_deref(),
+            Some("/tenants/tenant-2/x")
+        );
+        assert_eq!(get_bucket_path("/media/x", None, endpoint), None);
+        assert_eq!(
+            get_bucket_path("/media/x", Some("localhost"), endpoint),
+            None
+        );
+        assert_eq!(
+            get_bucket_path("/media/x", Some("..%2F.example.com"), endpoint),
+            None
+        );
+        assert_eq!(
+            get_bucket_path("/media/x", Some(".example.com"), endpoint),
+            None
+        );
+    }
+
+    #[test]
+    fn test_lowercase_extension() {
+        assert_eq!(lowercase_extension("Photo.JPG"), "Photo.jpg");
+        assert_eq!(
+            lowercase_extension("Album.V2/Photo.tar.GZ"),
+            "Album.V2/Photo.tar.gz"
+        );
+        assert_eq!(lowercase_extension("Album.V2/README"), "Album.V2/README");
+        assert_eq!(lowercase_extension("Album/.Profile"), "Album/.Profile");
+        assert_eq!(lowercase_extension("Photo."), "Photo.");
+
+        let endpoints: Endpoints = serde_yaml::from_str(
+            r#"
+- path: "/media/"
+  bucket_path: "/files"
+  lowercase_extension: true
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            get_bucket_path("/media/Photo.JPG", None, endpoints.find("/media/").unwrap())
+                .as_deref(),
+            Some("/files/Photo.jpg")
+        );
+    }
+
+    #[test]
+    fn test_range_is_well_formed() {
+        assert!(range_is_well_formed("bytes=0-100"));
+        assert!(range_is_well_formed("bytes=100-"));
+        assert!(range_is_well_formed("bytes=-100"));
+        assert!(range_is_well_formed("bytes=0-1, 4-5"));
+        assert!(!range_is_well_formed("bytes=abc"));
+        assert!(!range_is_well_formed("bytes=-"));
+        assert!(!range_is_well_formed("bytes=10-5"));
+        assert!(!range_is_well_formed("bytes=0-1,x-5"));
+        assert!(!range_is_well_formed("items=0-5"));
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_range_unit() {
+        let mock = MockS3::start().await;
+        mock.put_object("file.txt", b"0123456789", "text/plain");
+
+        let get = |router: Router, range: &'static str| async move {
+            router
+                .oneshot(
+                    Request::get("/file.txt")
+                        .header(header::RANGE, range)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap()
+        };
+
+        // ignored by default, like RFC 7233 requires
+        let router = make_test_router(&mock, ROOT_ENDPOINT);
+        let response = get(router.clone(), "items=0-4").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            "0123456789"
+        );
+        assert!(mock
+            .requests()
+            .last()
+            .unwrap()
+            .headers
+            .get(header::RANGE)
+            .is_none());
+
+        let router = make_test_router(
+            &mock,
+            &format!("unsupported_range_unit: reject\n{ROOT_ENDPOINT}"),
+        );
+        assert_eq!(
+            get(router.clone(), "items=0-4").await.status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            get(router.clone(), "0-4").await.status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            get(router, "bytes=0-4").await.status(),
+            StatusCode::PARTIAL_CONTENT
+        );
+    }
+
+    #[tokio::test]
+    async fn test_strict_range() {
+        let mock = MockS3::start().await;
+        mock.put_object("file.txt", b"0123456789", "text/plain");
+
+        let lenient = make_test_router(&mock, ROOT_ENDPOINT);
+        let strict = make_test_router(&mock, &format!("strict_range: true\n{ROOT_ENDPOINT}"));
+
+        let status = |router: &Router, range: &'static str| {
+            let router = router.clone();
+            async move {
+                router
+                    .oneshot(
+                        Request::get("/file.txt")
+                            .header(header::RANGE, range)
+                            .body(Body::empty())
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap()
+                    .status()
+            }
+        };
+
+        assert_eq!(status(&lenient, "bytes=abc").await, StatusCode::OK);
+        assert_eq!(status(&lenient, "bytes=0-1,4-5").await, StatusCode::OK);
+        assert_eq!(status(&lenient, "bytes=-").await, StatusCode::OK);
+        assert_eq!(
+            status(&lenient, "bytes=0-1").await,
+            StatusCode::PARTIAL_CONTENT
+        );
+
+        assert_eq!(status(&strict, "bytes=abc").await, StatusCode::BAD_REQUEST);
+        assert_eq!(status(&strict, "bytes=-").await, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            status(&strict, "bytes=0-1,4-5").await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(&strict, "bytes=0-1").await,
+            StatusCode::PARTIAL_CONTENT
+        );
+    }
+
+    #[tokio::test]
+    async fn test_directory_markers() {
+        let mock = MockS3::start().await;
+        mock.put_object("docs", b"", "application/x-directory");
+        mock.put_object("docs/", b"", "binary/octet-stream");
+        mock.put_object("docs/empty.txt", b"", "text/plain");
+
+        let router = make_test_router(
+            &mock,
+            r#"
+endpoints:
+  - path: "/serve/"
+    bucket_path: "/"
+  - path: "/missing/"
+    bucket_path: "/"
+    directory_markers: not_found
+  - path: "/redirect/"
+    bucket_path: "/"
+    directory_markers: redirect
+"#,
+        );
+
+        let get = |path: &'static str| {
+            let router = router.clone();
+            async move {
+                router
+                    .oneshot(Request::get(path).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+            }
+        };
+
+        assert_eq!(get("/serve/docs").await.status(), StatusCode::OK);
+        assert_eq!(get("/missing/docs").await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(get("/missing/docs/").await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            get("/missing/docs/empty.txt").await.status(),
+            StatusCode::OK
+        );
+
+        let response = get("/redirect/docs").await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(response.headers()[header::LOCATION], "/redirect/docs/");
+        assert_eq!(get("/redirect/docs/").await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_implicit_directory() {
+        let mock = MockS3::start().await;
+        mock.put_object("docs/index.html", b"<h1>Docs</h1>", "text/html");
+        mock.put_object("docs/guide.html", b"<h1>Guide</h1>", "text/html");
+        mock.put_object("readme", b"an object", "text/plain");
+        mock.put_object("docsx/index.html", b"<h1>Other</h1>", "text/html");
+
+        let router = make_test_router(
+            &mock,
+            r#"
+endpoints:
+  - path: "/off/"
+    bucket_path: "/"
+  - path: "/redirect/"
+    bucket_path: "/"
+    implicit_directory: redirect
+  - path: "/serve/"
+    bucket_path: "/"
+    implicit_directory: serve_index
+"#,
+        );
+
+        let get = |path: &'static str| {
+            let router = router.clone();
+            async move {
+                router
+                    .oneshot(Request::get(path).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+            }
+        };
+
+        assert_eq!(get("/off/docs").await.status(), StatusCode::NOT_FOUND);
+
+        let response = get("/redirect/docs").await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(response.headers()[header::LOCATION], "/redirect/docs/");
+
+        let response = get("/serve/docs").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            "<h1>Docs</h1>"
+        );
+
+        // objects are served as they are, without looking for a prefix
+        let requests = mock.requests().len();
+        for path in ["/redirect/readme", "/serve/readme"] {
+            let response = get(path).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                hyper::body::to_bytes(response.into_body()).await.unwrap(),
+                "an object"
+            );
+        }
+        assert_eq!(mock.requests().len(), requests + 2);
+
+        // only whole segments are prefixes
+        for path in ["/redirect/doc", "/serve/doc", "/redirect/missing"] {
+            assert_eq!(get(path).await.status(), StatusCode::NOT_FOUND, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_index_document() {
+        let mock = MockS3::start().await;
+        mock.put_object("app/files/index.html", b"<h1>Files</h1>", "text/html");
+        mock.put_object("app/files/sub/other.html", b"<h1>Other</h1>", "text/html");
+
+        let router = make_test_router(
+            &mock,
+            r#"
+endpoints:
+  - path: "/media/"
+    bucket_path: "/app/files/"
+    index_document: "index.html"
+  - path: "/plain/"
+    bucket_path: "/app/files/"
+"#,
+        );
+
+        let get = |path: &'static str| {
+            let router = router.clone();
+            async move {
+                router
+                    .oneshot(Request::get(path).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let response = get("/media/").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            mock.requests().last().unwrap().uri.path(),
+            "/test-bucket/app/files/index.html"
+        );
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            "<h1>Files</h1>"
+        );
+
+        // missing index documents are answered like missing objects
+        assert_eq!(get("/media/sub/").await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            mock.requests().last().unwrap().uri.path(),
+            "/test-bucket/app/files/sub/index.html"
+        );
+
+        get("/plain/").await;
+        assert_eq!(
+            mock.requests().last().unwrap().uri.path(),
+            "/test-bucket/app/files/"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_spa_fallback() {
+        let mock = MockS3::start().await;
+        mock.put_object("app/index.html", b"<div id=\"app\">", "text/html");
+        mock.put_object("app/main.js", b"render()", "text/javascript");
+
+        let router = make_test_router(
+            &mock,
+            r#"
+endpoints:
+  - path: "/app/"
+    spa_fallback: "index.html"
+"#,
+        );
+
+        let get = |path: &'static str, accept: &'static str| {
+            let router = router.clone();
+            async move {
+                router
+                    .oneshot(
+                        Request::get(path)
+                            .header(header::ACCEPT, accept)
+                            .body(Body::empty())
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let navigation = "text/html,application/xhtml+xml,*/*;q=0.8";
+        let response = get("/app/settings/profile", navigation).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::VARY], "Accept");
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            "<div id=\"app\">"
+        );
+
+        // existing objects are served as they are
+        let response = get("/app/main.js", navigation).await;
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            "render()"
+        );
+
+        for accept in ["*/*", "text/html;q=0"] {
+            let response = get("/app/missing.js", accept).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{accept}");
+            assert_eq!(response.headers()[header::VARY], "Accept");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_not_found_redirect() {
+        let mock = MockS3::start().await;
+        mock.put_object("media/a.jpg", b"a", "image/jpeg");
+
+        let router = make_test_router(
+            &mock,
+            r#"
+endpoints:
+  - path: "/media/"
+    not_found_redirect: "https://search.example.com/"
+  - path: "/assets/"
+    not_found_redirect: "https://cdn.example.com"
+    not_found_redirect_permanent: true
+    not_found_redirect_append_path: true
+"#,
+        );
+
+        let get = |path: &'static str| {
+            let router = router.clone();
+            async move {
+                router
+                    .oneshot(Request::get(path).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+            }
+        };
+
+        assert_eq!(get("/media/a.jpg").await.status(), StatusCode::OK);
+
+        let response = get("/media/missing.jpg").await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            "https://search.example.com/"
+        );
+
+        let response = get("/assets/app%20v2.js").await;
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            "https://cdn.example.com/assets/app%20v2.js"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_basic_auth() {
+        let mock = MockS3::start().await;
+        mock.put_object("private/a.txt", b"a", "text/plain");
+        mock.put_object("hashed/b.txt", b"b", "text/plain");
+
+        let hash = bcrypt::hash("hunter2", 4).unwrap();
+        let router = make_test_router(
+            &mock,
+            &format!(
+                r#"
+endpoints:
+  - path: "/private/"
+    basic_auth:
+      username: "admin"
+      password: "secret"
+      realm: "Private files"
+  - path: "/hashed/"
+    basic_auth:
+      username: "admin"
+      password_hash: "{hash}"
+"#
+            ),
+        );
+
+        let get = |path: &'static str, credentials: Option<(&'static str, &'static str)>| {
+            let router = router.clone();
+            async move {
+                let mut request = Request::get(path).body(Body::empty()).unwrap();
+                if let Some((username, password)) = credentials {
+                    request
+                        .headers_mut()
+                        .typed_insert(Authorization::basic(username, password));
+                }
+
+                router.oneshot(request).await.unwrap()
+            }
+        };
+
+        let response = get("/private/a.txt", None).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers()[header::WWW_AUTHENTICATE],
+            "Basic realm=\"Private files\", charset=\"UTF-8\""
+        );
+
+        for credentials in [("admin", "wrong"), ("root", "secret")] {
+            let response = get("/private/a.txt", Some(credentials)).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+        let response = get("/hashed/b.txt", Some(("admin", "secret"))).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers()[header::WWW_AUTHENTICATE],
+            "Basic realm=\"s3-proxy\", charset=\"UTF-8\""
+        );
+        // rejected before asking the bucket
+        assert!(mock.requests().is_empty());
+
+        let response = get("/private/a.txt", Some(("admin", "secret"))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = get("/hashed/b.txt", Some(("admin", "hunter2"))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_redirect_mode() {
+        let mock = MockS3::start().await;
+        mock.put_object("downloads/big.iso", b"iso", "application/octet-stream");
+
+        let router = make_test_router(
+            &mock,
+            r#"
+endpoints:
+  - path: "/downloads/"
+    mode: redirect
+    presign_expiry: 60
+"#,
+        );
+
+        let send = |method: Method| {
+            router.clone().oneshot(
+                Request::builder()
+                    .method(method)
+                    .uri("/downloads/big.iso")
+                    .header(header::RANGE, "bytes=0-1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = send(Method::GET).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "no-store");
+        let location: Uri = response.headers()[header::LOCATION]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(location.path(), "/test-bucket/downloads/big.iso");
+        let query = location.query().unwrap();
+        assert!(query.contains("X-Amz-Expires=60"), "{query}");
+        assert!(query.contains("X-Amz-Signature="), "{query}");
+        // the bytes aren't fetched by the proxy
+        assert!(mock.requests().is_empty());
+
+        let response = send(Method::HEAD).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_retries() {
+        let mock = MockS3::start().await;
+        mock.put_object("a.txt", b"a", "text/plain");
+        mock.put_object("b.txt", b"b", "text/plain");
+
+        let router = make_test_router(
+            &mock,
+            &format!("upstream:\n  retries: 2\n  retry_base_delay: 10\n{ROOT_ENDPOINT}"),
+        );
+        let get = |path: &'static str| {
+            router
+                .clone()
+                .oneshot(Request::get(path).body(Body::empty()).unwrap())
+        };
+
+        mock.fail_next("a.txt", 2);
+        let response = get("/a.txt").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(mock.requests().len(), 3);
+
+        mock.fail_next("b.txt", 3);
+        let response = get("/b.txt").await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(mock.requests().len(), 6);
+
+        // missing objects aren't retried
+        let response = get("/missing.txt").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(mock.requests().len(), 7);
+
+        // without retries, errors are answered right away
+        let router = make_test_router(&mock, ROOT_ENDPOINT);
+        mock.fail_next("a.txt", 1);
+        let response = router
+            .oneshot(Request::get("/a.txt").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(mock.requests().len(), 8);
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let base = Duration::from_millis(100);
+
+        for attempt in 0..4 {
+            let delay = retry_delay(base, attempt);
+            let max = base * 2u32.pow(attempt);
+            assert!(delay >= max / 2 && delay <= max, "{attempt}: {delay:?}");
+        }
+    }
+
+    #[test]
+    fn test_rewrite_location() {
+        let config = Configuration::from_yaml(
+            r#"
+bucket:
+  endpoint: "https://s3.fr-par.scw.cloud"
+  region: "fr-par"
+  bucket_name: "my-bucket"
+  access_key: "test"
+  secret_key: "test"
+http:
+  bind: "127.0.0.1"
+  port: 8000
+endpoints:
+  - path: "/"
+    bucket_path: "/"
+  - path: "/media/"
+    bucket_path: "/app/media/"
+  - path: "/other/"
+    bucket_path: "/app/"
+    bucket:
+      endpoint: "https://s3.fr-par.scw.cloud"
+      region: "fr-par"
+      bucket_name: "other-bucket"
+      access_key: "test"
+      secret_key: "test"
+"#,
+        )
+        .unwrap();
+        let bucket = config.bucket().unwrap().make_s3_bucket().unwrap();
+        let endpoint = config.endpoints().find("/").unwrap();
+        let rewrite = |location| rewrite_location(location, &bucket, &config, endpoint);
+
+        assert_eq!(
+            rewrite("https://s3.fr-par.scw.cloud/my-bucket/app/media/a.jpg").as_deref(),
+            Some("/media/a.jpg")
+        );
+        // /other/ is served by another bucket
+        assert_eq!(
+            rewrite("http://s3.fr-par.scw.cloud/my-bucket/app/b.jpg").as_deref(),
+            Some("/app/b.jpg")
+        );
+        assert_eq!(
+            rewrite("https://s3.fr-par.scw.cloud/my-bucket-2/a.jpg"),
+            None
+        );
+        assert_eq!(rewrite("https://example.com/a.jpg"), None);
+        assert_eq!(
+            rewrite("/relative/a.jpg").as_deref(),
+            Some("/relative/a.jpg")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upstream_redirect() {
+        let mock = MockS3::start().await;
+        mock.put_object("moved.txt", b"moved", "text/plain");
+        mock.redirect(
+            "old.txt",
+            StatusCode::MULTIPLE_CHOICES,
+            &format!("{}/{}/new/file.txt", mock.endpoint(), mock_s3::BUCKET_NAME),
+        );
+        mock.redirect(
+            "external.txt",
+            StatusCode::MULTIPLE_CHOICES,
+            "https://example.com/file.txt",
+        );
+        // followed by the client, so the target is served
+        mock.redirect(
+            "followed.txt",
+            StatusCode::TEMPORARY_REDIRECT,
+            &format!("{}/{}/moved.txt", mock.endpoint(), mock_s3::BUCKET_NAME),
+        );
+        let endpoints = r#"
+endpoints:
+  - path: "/files/"
+    bucket_path: "/"
+  - path: "/new/"
+    bucket_path: "/new/"
+"#;
+        let get = |router: Router, path: &'static str| {
+            router.oneshot(Request::get(path).body(Body::empty()).unwrap())
+        };
+
+        let router = make_test_router(&mock, &format!("rewrite_location: true\n{endpoints}"));
+
+        let response = get(router.clone(), "/files/old.txt").await.unwrap();
+        assert_eq!(response.status(), StatusCode::MULTIPLE_CHOICES);
+        assert_eq!(response.headers()[header::LOCATION], "/new/file.txt");
+
+        let response = get(router.clone(), "/files/external.txt").await.unwrap();
+        assert!(response.status().is_server_error());
+        assert!(!response.headers().contains_key(header::LOCATION));
+
+        let response = get(router, "/files/followed.txt").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            "moved"
+        );
+
+        // redirects aren't passed on without rewrite_location
+        let router = make_test_router(&mock, endpoints);
+        let response = get(router, "/files/old.txt").await.unwrap();
+        assert!(response.status().is_server_error());
+        assert!(!response.headers().contains_key(header::LOCATION));
+    }
+
+    #[tokio::test]
+    async fn test_strip_response_headers() {
+        let mock = MockS3::start().await;
+        mock.put_object("a.txt", b"a", "text/plain");
+
+        let get_etag = |yaml: String| {
+            let router = make_test_router(&mock, &yaml);
+            async move {
+                router
+                    .oneshot(Request::get("/a.txt").body(Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+                    .headers()
+                    .get(header::ETAG)
+                    .cloned()
+            }
+        };
+
+        assert!(get_etag(ROOT_ENDPOINT.to_owned()).await.is_some());
+        assert!(get_etag(format!(
+            "strip_response_headers: [\"ETag\"]\n{ROOT_ENDPOINT}"
+        ))
+        .await
+        .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_requests_are_unsigned() {
+        let mock = MockS3::start().await;
+        mock.put_object("public.txt", b"public", "text/plain");
+
+        let config = Configuration::from_yaml(&format!(
+            "{}http:\n  bind: \"127.0.0.1\"\n  port: 8000\n{ROOT_ENDPOINT}",
+            mock.anonymous_bucket_yaml()
+        ))
+        .unwrap();
+        let router = make_router(LiveConfig::new(
+            config.clone(),
+            Buckets::new(&config).unwrap(),
+        ));
+
+        let response = router
+            .oneshot(Request::get("/public.txt").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, axum::http::Method::GET);
+        assert_eq!(requests[0].uri.path(), "/test-bucket/public.txt");
+        assert!(!requests[0].headers.contains_key(header::AUTHORIZATION));
+    }
+
+    #[tokio::test]
+    async fn test_ranged_head() {
+        let mock = MockS3::start().await;
+        mock.put_object("file.bin", &[0; 1000], "application/octet-stream");
+
+        let head = |router: Router, range: &'static str| async move {
+            router
+                .oneshot(
+                    Request::head("/file.bin")
+                        .header(header::RANGE, range)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap()
+        };
+
+        let full = make_test_router(&mock, ROOT_ENDPOINT);
+
+        let response = head(full, "bytes=0-99").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "1000");
+        assert!(!response.headers().contains_key(header::CONTENT_RANGE));
+
+        let partial = make_test_router(&mock, &format!("head_range: partial\n{ROOT_ENDPOINT}"));
+
+        let response = head(partial.clone(), "bytes=0-99").await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "100");
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 0-99/1000");
+
+        let response = head(partial.clone(), "bytes=900-").await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "100");
+        assert_eq!(
+            response.headers()[header::CONTENT_RANGE],
+            "bytes 900-999/1000"
+        );
+
+        let response = head(partial.clone(), "bytes=500-100").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "1000");
+        assert!(!response.headers().contains_key(header::CONTENT_RANGE));
+
+        let response = head(partial.clone(), "bytes=-").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "1000");
+
+        let response = head(partial, "bytes=2000-").await;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */1000");
+    }
+
+    #[tokio::test]
+    async fn test_head_accept_ranges() {
+        let mock = MockS3::start().await;
+        for (key, accept_ranges) in [("none.bin", "none"), ("bytes.bin", "bytes")] {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT_RANGES, accept_ranges.parse().unwrap());
+            mock.put_object_with_headers(key, &[0; 1000], headers);
+        }
+        mock.put_object("plain.bin", &[0; 1000], "application/octet-stream");
+
+        let router = make_test_router(&mock, &format!("head_range: partial\n{ROOT_ENDPOINT}"));
+        let head = |path: &'static str, range: Option<&'static str>| {
+            let mut request = Request::head(path);
+            if let Some(range) = range {
+                request = request.header(header::RANGE, range);
+            }
+
+            router.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let response = head("/none.bin", None).await.unwrap();
+        assert_eq!(response.headers()[header::ACCEPT_RANGES], "none");
+
+        // no ranges are made up for objects the bucket can't serve them for
+        let response = head("/none.bin", Some("bytes=0-99")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ACCEPT_RANGES], "none");
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "1000");
+
+        let response = head("/bytes.bin", Some("bytes=0-99")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::ACCEPT_RANGES], "bytes");
+
+        let response = head("/plain.bin", None).await.unwrap();
+        assert!(!response.headers().contains_key(header::ACCEPT_RANGES));
+    }
+
+    #[tokio::test]
+    async fn test_guess_content_type() {
+        let mock = MockS3::start().await;
+        mock.put_object("untyped.css", b"body {}", "application/octet-stream");
+        mock.put_object("typed.css", b"body {}", "text/plain");
+        mock.put_object_with_headers("missing.html", b"<p>", HeaderMap::new());
+        mock.put_object("no-extension", b"?", "application/octet-stream");
+
+        let content_type = |router: Router, path: &'static str| async move {
+            router
+                .oneshot(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .map(|value| value.to_str().unwrap().to_owned())
+        };
+
+        let router = make_test_router(&mock, &format!("guess_content_type: true\n{ROOT_ENDPOINT}"));
+        assert_eq!(
+            content_type(router.clone(), "/untyped.css")
+                .await
+                .as_deref(),
+            Some("text/css")
+        );
+        assert_eq!(
+            content_type(router.clone(), "/missing.html")
+                .await
+                .as_deref(),
+            Some("text/html")
+        );
+        // the bucket's type wins if it's specific
+        assert_eq!(
+            content_type(router.clone(), "/typed.css").await.as_deref(),
+            Some("text/plain")
+        );
+        assert_eq!(
+            content_type(router, "/no-extension").await.as_deref(),
+            Some("application/octet-stream")
+        );
+
+        let router = make_test_router(&mock, ROOT_ENDPOINT);
+        assert_eq!(
+            content_type(router, "/untyped.css").await.as_deref(),
+            Some("application/octet-stream")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_suffix_and_open_ranges() {
+        let mock = MockS3::start().await;
+        mock.put_object("file.txt", b"0123456789", "text/plain");
+
+        let router = make_test_router(&mock, ROOT_ENDPOINT);
+        let get = |range: &'static str| {
+            router.clone().oneshot(
+                Request::get("/file.txt")
+                    .header(header::RANGE, range)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = get("bytes=-3").await.unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 7-9/10");
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            "789"
+        );
+        let requests = mock.requests();
+        assert_eq!(requests[0].method, Method::HEAD);
+        assert_eq!(requests[1].headers[header::RANGE], "bytes=7-9");
+
+        // a suffix longer than the object selects all of it
+        let response = get("bytes=-20").await.unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 0-9/10");
+
+        let response = get("bytes=-0").await.unwrap();
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */10");
+
+        let response = get("bytes=5-").await.unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            "56789"
+        );
+
+        let response = get("bytes=20-").await.unwrap();
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */10");
+    }
+
+    #[tokio::test]
+    async fn test_upstream_timeouts() {
+        use std::time::Duration;
+
+        let mock = MockS3::start().await;
+        mock.insert_object(
+            "slow-start.txt",
+            MockObject {
+                body: b"slow".to_vec(),
+                response_delay: Duration::from_secs(3),
+                ..Default::default()
+            },
+        );
+        mock.insert_object(
+            "slow-stream.txt",
+            MockObject {
+                body: b"abcdef".to_vec(),
+                byte_delay: Some(Duration::from_millis(300)),
+                ..Default::default()
+            },
+        );
+        mock.insert_object(
+            "stalled.txt",
+            MockObject {
+                body: b"ab".to_vec(),
+                byte_delay: Some(Duration::from_secs(3)),
+                ..Default::default()
+            },
+        );
+
+        let router = make_test_router(
+            &mock,
+            &format!("upstream_connect_timeout: 1\nupstream_idle_timeout: 1\n{ROOT_ENDPOINT}"),
+        );
+        let get = |path: &'static str| {
+            router
+                .clone()
+                .oneshot(Request::get(path).body(Body::empty()).unwrap())
+        };
+
+        let response = get("/slow-start.txt").await.unwrap();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+
+        // takes longer than the connect timeout in total, but is never idle
+        let response = get("/slow-stream.txt").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            "abcdef"
+        );
+
+        let response = get("/stalled.txt").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(hyper::body::to_bytes(response.into_body()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_conditional_get_single_request() {
+        let mock = MockS3::start().await;
+        mock.put_object("index.html", b"<html></html>", "text/html");
+        let etag = format!("\"{:x}\"", md5::compute(b"<html></html>"));
+
+        let router = make_test_router(&mock, ROOT_ENDPOINT);
+        let get = |if_none_match: &str| {
+            router.clone().oneshot(
+                Request::get("/index.html")
+                    .header(header::IF_NONE_MATCH, if_none_match)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = get(&etag).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, axum::http::Method::GET);
+        assert_eq!(requests[0].headers[header::IF_NONE_MATCH], etag.as_str());
+
+        let response = get("\"outdated\"").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(mock.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_expect_header() {
+        let mock = MockS3::start().await;
+        mock.put_object("file.txt", b"file", "text/plain");
+
+        let router = make_test_router(&mock, ROOT_ENDPOINT);
+        let get = |expect: &'static str| {
+            router.clone().oneshot(
+                Request::get("/file.txt")
+                    .header(header::EXPECT, expect)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = get("100-continue").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = get("fast-response").await.unwrap();
+        assert_eq!(response.status(), StatusCode::EXPECTATION_FAILED);
+        assert_eq!(mock.requests().len(), 1);
+
+        // writes are checked as well
+        let router = make_test_router(
+            &mock,
+            &format!("read_only: false\nallow_delete: true\n{ROOT_ENDPOINT}").replace(
+                "bucket_path: \"/\"",
+                "bucket_path: \"/\"\n    methods: [\"GET\", \"PUT\", \"DELETE\"]",
+            ),
+        );
+        for method in [Method::PUT, Method::DELETE] {
+            let response = router
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method(method)
+                        .uri("/file.txt")
+                        .header(header::EXPECT, "fast-response")
+                        .body(Body::from("new"))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::EXPECTATION_FAILED);
+        }
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_content_location() {
+        let mock = MockS3::start().await;
+        mock.put_object("photo.jpg", b"jpeg", "image/jpeg");
+        mock.put_object("photo.jpg.avif", b"avif", "image/avif");
+
+        let endpoints = r#"
+endpoints:
+  - path: "/"
+    bucket_path: "/"
+    image_variants: true
+"#;
+        let request = || {
+            Request::get("/photo.jpg")
+                .header(header::ACCEPT, "image/avif")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = make_test_router(&mock, endpoints)
+            .oneshot(request())
+            .await
+            .unwrap();
+        assert!(!response.headers().contains_key(header::CONTENT_LOCATION));
+
+        let response = make_test_router(&mock, &format!("content_location: true\n{endpoints}"))
+            .oneshot(request())
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/avif");
+        assert_eq!(
+            response.headers()[header::CONTENT_LOCATION],
+            "/photo.jpg.avif"
+        );
+    }
+
+    #[test]
+    fn test_accepts() {
+        let accept = "image/avif;q=0, image/webp,*/*;q=0.8";
+
+        assert!(!accepts(accept, "image/avif"));
+        assert!(accepts(accept, "image/webp"));
+        assert!(!accepts(accept, "image/png"));
+    }
+
+    #[tokio::test]
+    async fn test_image_variants() {
+        let mock = MockS3::start().await;
+        mock.put_object("photo.jpg", b"jpeg", "image/jpeg");
+        mock.put_object("photo.jpg.webp", b"webp", "application/octet-stream");
+        mock.put_object("other.jpg", b"jpeg", "image/jpeg");
+
+        let router = make_test_router(
+            &mock,
+            r#"
+endpoints:
+  - path: "/"
+    bucket_path: "/"
+    image_variants: true
+"#,
+        );
+        let get = |path: &'static str, accept: &'static str| {
+            router.clone().oneshot(
+                Request::get(path)
+                    .header(header::ACCEPT, accept)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = get("/photo.jpg", "image/avif,image/webp,*/*")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/webp");
+        assert_eq!(response.headers()[header::VARY], "Accept");
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            "webp"
+        );
+
+        let response = get("/photo.jpg", "image/png,*/*").await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/jpeg");
+        assert_eq!(response.headers()[header::VARY], "Accept");
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            "jpeg"
+        );
+
+        // falls back to the original if no variant exists
+        let response = get("/other.jpg", "image/avif,image/webp").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/jpeg");
+    }
+
+    #[tokio::test]
+    async fn test_access_denied() {
+        let mock = MockS3::start().await;
+        mock.deny_access("secret.txt");
+
+        for (yaml, denied_status) in [
+            (ROOT_ENDPOINT.to_owned(), StatusCode::FORBIDDEN),
+            (
+                format!("access_denied_as_not_found: true\n{ROOT_ENDPOINT}"),
+                StatusCode::NOT_FOUND,
+            ),
+        ] {
+            let router = make_test_router(&mock, &yaml);
+            let get = |path: &'static str| {
+                router
+                    .clone()
+                    .oneshot(Request::get(path).body(Body::empty()).unwrap())
+            };
+
+            let response = get("/missing.txt").await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+            let response = get("/secret.txt").await.unwrap();
+            assert_eq!(response.status(), denied_status);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_timing_allow_origin() {
+        let mock = MockS3::start().await;
+        mock.put_object("app.js", b"app", "text/javascript");
+
+        let request = || Request::get("/app.js").body(Body::empty()).unwrap();
+
+        let response = make_test_router(&mock, ROOT_ENDPOINT)
+            .oneshot(request())
+            .await
+            .unwrap();
+        assert!(!response.headers().contains_key(TIMING_ALLOW_ORIGIN));
+
+        let response = make_test_router(
+            &mock,
+            &format!("timing_allow_origin: \"https://rum.example.com\"\n{ROOT_ENDPOINT}"),
+        )
+        .oneshot(request())
+        .await
+        .unwrap();
+        assert_eq!(
+            response.headers()[TIMING_ALLOW_ORIGIN],
+            "https://rum.example.com"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_options_asterisk() {
+        let mock = MockS3::start().await;
+        let options = |router: Router| {
+            router.oneshot(
+                Request::options(Uri::from_static("*"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = options(make_test_router(&mock, ROOT_ENDPOINT))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()[header::ALLOW], "GET, HEAD, OPTIONS");
+
+        let router = make_test_router(
+            &mock,
+            &format!("read_only: false\nallow_delete: true\n{ROOT_ENDPOINT}"),
+        );
+        let response = options(router).await.unwrap();
+        assert_eq!(
+            response.headers()[header::ALLOW],
+            "GET, HEAD, PUT, DELETE, OPTIONS"
+        );
+        assert!(mock.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_endpoint_methods() {
+        let mock = MockS3::start().await;
+        mock.put_object("files/a.txt", b"a", "text/plain");
+        mock.put_object("meta/a.txt", b"a", "text/plain");
+        mock.put_object("other/a.txt", b"a", "text/plain");
+
+        let router = make_test_router(
+            &mock,
+            r#"
+endpoints:
+  - path: "/files/"
+    methods: ["GET"]
+  - path: "/meta/"
+    methods: ["HEAD"]
+  - path: "/other/"
+"#,
+        );
+        let send = |method: Method, path: &'static str| {
+            router.clone().oneshot(
+                Request::builder()
+                    .method(method)
+                    .uri(path)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        assert_eq!(
+            send(Method::GET, "/files/a.txt").await.unwrap().status(),
+            StatusCode::OK
+        );
+        let response = send(Method::HEAD, "/files/a.txt").await.unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[header::ALLOW], "GET, OPTIONS");
+
+        assert_eq!(
+            send(Method::HEAD, "/meta/a.txt").await.unwrap().status(),
+            StatusCode::OK
+        );
+        let response = send(Method::GET, "/meta/a.txt").await.unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[header::ALLOW], "HEAD, OPTIONS");
+
+        let response = send(Method::OPTIONS, "/other/a.txt").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()[header::ALLOW], "GET, HEAD, OPTIONS");
+
+        let response = send(Method::POST, "/other/a.txt").await.unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[header::ALLOW], "GET, HEAD, OPTIONS");
+
+        assert_eq!(mock.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_put_file() {
+        let mock = MockS3::start().await;
+        let endpoints = r#"
+endpoints:
+  - path: "/uploads/"
+    bucket_path: "/files/"
+    methods: ["GET", "PUT"]
+"#;
+        let put = |router: Router, path: &'static str| {
+            router.oneshot(
+                Request::put(path)
+                    .header(header::CONTENT_TYPE, "text/plain")
+                    .body(Body::from("uploaded"))
+                    .unwrap(),
+            )
+        };
+
+        // read-only by default
+        let router = make_test_router(&mock, endpoints);
+        let response = put(router, "/uploads/a.txt").await.unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[header::ALLOW], "GET, OPTIONS");
+        assert!(mock.requests().is_empty());
+
+        let router = make_test_router(&mock, &format!("read_only: false\n{endpoints}"));
+
+        let response = put(router.clone(), "/uploads/a.txt").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().contains_key(header::ETAG));
+
+        let requests = mock.requests();
+        assert_eq!(requests[0].method, Method::PUT);
+        assert_eq!(requests[0].uri.path(), "/test-bucket/files/a.txt");
+        assert_eq!(requests[0].headers[header::CONTENT_TYPE], "text/plain");
+
+        let response = router
+            .clone()
+            .oneshot(Request::get("/uploads/a.txt").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            "uploaded"
+        );
+
+        let response = put(router, "/elsewhere/a.txt").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(mock.requests().len(), 2);
+
+        let router = make_test_router(
+            &mock,
+            &format!("read_only: false\nmax_upload_size: 4\n{endpoints}"),
+        );
+        let response = put(router.clone(), "/uploads/b.txt").await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // the limit is enforced while reading bodies of unknown length
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for _ in 0..3 {
+                sender.send_data("abc".into()).await.ok();
+            }
+        });
+        let response = router
+            .oneshot(Request::put("/uploads/c.txt").body(body).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(mock.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_delete_file() {
+        let mock = MockS3::start().await;
+        mock.put_object("a.txt", b"a", "text/plain");
+
+        let endpoints = r#"
+endpoints:
+  - path: "/"
+    methods: ["GET", "DELETE"]
+"#;
+        let delete =
+            |router: Router| router.oneshot(Request::delete("/a.txt").body(Body::empty()).unwrap());
+
+        let router = make_test_router(&mock, endpoints);
+        let response = delete(router).await.unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[header::ALLOW], "GET, OPTIONS");
+        assert!(mock.requests().is_empty());
+
+        let router = make_test_router(&mock, &format!("allow_delete: true\n{endpoints}"));
+        let response = router
+            .clone()
+            .oneshot(Request::options("/a.txt").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[header::ALLOW], "GET, DELETE, OPTIONS");
+
+        let response = delete(router.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(mock.requests()[0].method, Method::DELETE);
+
+        let response = router
+            .oneshot(Request::get("/a.txt").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_max_path_depth() {
+        let mock = MockS3::start().await;
+        mock.put_object("a/b/c.txt", b"c", "text/plain");
+
+        let router = make_test_router(&mock, &format!("max_path_depth: 3\n{ROOT_ENDPOINT}"));
+        let get = |path: &'static str| {
+            router
+                .clone()
+                .oneshot(Request::get(path).body(Body::empty()).unwrap())
+        };
+
+        assert_eq!(get("/a/b/c.txt").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(
+            get("/a/b/c/d.txt").await.unwrap().status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            get("/a//////b.txt").await.unwrap().status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_endpoint_bucket() {
+        let mock = MockS3::start().await;
+        let other = MockS3::start().await;
+        mock.put_object("a.txt", b"default", "text/plain");
+        other.put_object("a.txt", b"other", "text/plain");
+
+        let router = make_test_router(
+            &mock,
+            &format!(
+                r#"
+endpoints:
+  - path: "/"
+  - path: "/other/"
+    bucket_path: "/"
+    bucket:
+      endpoint: "{}"
+      region: "us-east-1"
+      bucket_name: "{}"
+      anonymous: true
+"#,
+                other.endpoint(),
+                mock_s3::BUCKET_NAME
+            ),
+        );
+        let get = |path: &'static str| {
+            let router = router.clone();
+            async move {
+                let response = router
+                    .oneshot(Request::get(path).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                hyper::body::to_bytes(response.into_body()).await.unwrap()
+            }
+        };
+
+        assert_eq!(get("/a.txt").await, "default");
+        assert_eq!(get("/other/a.txt").await, "other");
+        assert_eq!(mock.requests().len(), 1);
+        assert_eq!(other.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_ensure_content_length() {
+        let mock = MockS3::start().await;
+        mock.insert_object(
+            "chunked.txt",
+            MockObject {
+                body: b"chunked".to_vec(),
+                chunked: true,
+                ..Default::default()
+            },
+        );
+
+        let get = |router: Router| {
+            router.oneshot(Request::get("/chunked.txt").body(Body::empty()).unwrap())
+        };
+
+        let response = get(make_test_router(&mock, ROOT_ENDPOINT)).await.unwrap();
+        assert!(!response.headers().contains_key(header::CONTENT_LENGTH));
+        assert_eq!(mock.requests().len(), 1);
+
+        let router = make_test_router(
+            &mock,
+            &format!("ensure_content_length: true\n{ROOT_ENDPOINT}"),
+        );
+        let response = get(router).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "7");
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            "chunked"
+        );
+        assert_eq!(mock.requests()[2].method, Method::HEAD);
+    }
+
+    #[tokio::test]
+    async fn test_precompressed_variants() {
+        let mock = MockS3::start().await;
+        mock.put_object("app.js", b"original", "application/javascript");
+        mock.put_object("app.js.br", b"brotli", "application/octet-stream");
+        mock.put_object("app.js.gz", b"gzip", "application/javascript");
+        mock.put_object("style.css", b"original", "text/css");
+        mock.put_object("image.png", b"original", "image/png");
+        mock.put_object("image.png.gz", b"gzip", "application/gzip");
+
+        let router = make_test_router(
+            &mock,
+            r#"
+compression:
+  enabled: true
+  min_size: 0
+endpoints:
+  - path: "/"
+    precompressed: true
+"#,
+        );
+        let get = |path: &'static str, headers: &[(header::HeaderName, &'static str)]| {
+            let mut request = Request::get(path);
+            for (name, value) in headers {
+                request = request.header(name, *value);
+            }
+
+            router.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+        let body = |response: Response| async {
+            hyper::body::to_bytes(response.into_body()).await.unwrap()
+        };
+
+        for (accept_encoding, encoding, content_type, expected) in [
+            ("gzip, deflate, br", "br", "text/javascript", "brotli"),
+            ("br;q=0.5, gzip", "gzip", "application/javascript", "gzip"),
+        ] {
+            let response = get("/app.js", &[(header::ACCEPT_ENCODING, accept_encoding)])
+                .await
+                .unwrap();
+            assert_eq!(response.headers()[header::CONTENT_ENCODING], encoding);
+            assert_eq!(response.headers()[header::CONTENT_TYPE], content_type);
+            assert_eq!(
+                response.headers()[header::CONTENT_LENGTH],
+                expected.len().to_string()
+            );
+            let vary = response
+                .headers()
+                .get_all(header::VARY)
+                .iter()
+                .collect::<Vec<_>>();
+            assert_eq!(vary, ["Accept-Encoding"]);
+            assert_eq!(body(response).await, expected);
+        }
+
+        // without an accepted variant the original is served, compressed on
+        // the fly if possible
+        let response = get("/app.js", &[(header::ACCEPT_ENCODING, "identity")])
+            .await
+            .unwrap();
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(body(response).await, "original");
+        let response = get("/style.css", &[(header::ACCEPT_ENCODING, "br")])
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "br");
+        assert_ne!(body(response).await, "original");
+
+        // ranges refer to the original
+        let response = get(
+            "/app.js",
+            &[
+                (header::ACCEPT_ENCODING, "gzip"),
+                (header::RANGE, "bytes=0-3"),
+            ],
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(body(response).await, "orig");
+        let response = get(
+            "/app.js",
+            &[
+                (header::ACCEPT_ENCODING, "gzip"),
+                (header::RANGE, "bytes=-4"),
+            ],
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 4-7/8");
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(body(response).await, "inal");
+
+        // variants are only looked up for text
+        let requests = mock.requests().len();
+        let response = get("/image.png", &[(header::ACCEPT_ENCODING, "gzip")])
+            .await
+            .unwrap();
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(body(response).await, "original");
+        assert_eq!(mock.requests().len(), requests + 1);
+    }
+
+    #[tokio::test]
+    async fn test_i18n_variants() {
+        let mock = MockS3::start().await;
+        mock.put_object("page.html", b"hello", "text/html");
+        mock.put_object("page.fr.html", b"bonjour", "text/html");
+        mock.put_object("other.html", b"other", "text/html");
+
+        let router = make_test_router(
+            &mock,
+            r#"
+endpoints:
+  - path: "/"
+    i18n_variants: true
+    locales: ["de", "fr"]
+"#,
+        );
+        let get = |path: &'static str, accept_language: &'static str| {
+            router.clone().oneshot(
+                Request::get(path)
+                    .header(header::ACCEPT_LANGUAGE, accept_language)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = get("/page.html", "fr-CH, fr;q=0.9, en;q=0.8")
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[header::CONTENT_LANGUAGE], "fr");
+        assert_eq!(response.headers()[header::VARY], "Accept-Language");
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            "bonjour"
+        );
+
+        // there's no German variant of the page
+        let response = get("/other.html", "de").await.unwrap();
+        assert!(!response.headers().contains_key(header::CONTENT_LANGUAGE));
+        assert_eq!(response.headers()[header::VARY], "Accept-Language");
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            "other"
+        );
+
+        let requests = mock.requests().len();
+        let response = get("/page.html", "es").await.unwrap();
+        assert!(!response.headers().contains_key(header::CONTENT_LANGUAGE));
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            "hello"
+        );
+        assert_eq!(mock.requests().len(), requests + 1);
+    }
+
+    #[tokio::test]
+    async fn test_health_probes() {
+        let mock = MockS3::start().await;
+        mock.put_object("probe.txt", b"", "text/plain");
+
+        let get = |router: Router, path: &'static str| async move {
+            router
+                .oneshot(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+                .status()
+        };
+
+        let router = make_test_router(&mock, ROOT_ENDPOINT);
+        assert_eq!(get(router.clone(), "/healthz").await, StatusCode::OK);
+        assert!(mock.requests().is_empty());
+        assert_eq!(get(router, "/readyz").await, StatusCode::OK);
+        assert_eq!(mock.requests().len(), 1);
+
+        let router = make_test_router(
+            &mock,
+            &format!("readiness_probe_key: \"probe.txt\"\n{ROOT_ENDPOINT}"),
+        );
+        assert_eq!(get(router, "/readyz").await, StatusCode::OK);
+        assert_eq!(mock.requests()[1].method, Method::HEAD);
+
+        let router = make_test_router(
+            &mock,
+            &format!("readiness_probe_key: \"missing.txt\"\n{ROOT_ENDPOINT}"),
+        );
+        assert_eq!(
+            get(router, "/readyz").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        // a bucket not answering in time is unreachable
+        mock.insert_object(
+            "slow.txt",
+            MockObject {
+                response_delay: std::time::Duration::from_secs(5),
+                ..Default::default()
+            },
+        );
+        let router = make_test_router(
+            &mock,
+            &format!(
+                "readiness_probe_key: \"slow.txt\"\nreadiness_probe_timeout: 1\n{ROOT_ENDPOINT}"
+            ),
+        );
+        let started = std::time::Instant::now();
+        assert_eq!(
+            get(router, "/readyz").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert!(started.elapsed() < std::time::Duration::from_secs(3));
+
+        let config = Configuration::from_yaml(
+            "bucket:\n  endpoint: \"http://127.0.0.1:1\"\n  region: \"us-east-1\"\n  bucket_name: \"test\"\n  anonymous: true\nhttp:\n  bind: \"127.0.0.1\"\n  port: 8000\nendpoints: []\n",
+        )
+        .unwrap();
+        let router = make_router(LiveConfig::new(
+            config.clone(),
+            Buckets::new(&config).unwrap(),
+        ));
+        assert_eq!(get(router.clone(), "/healthz").await, StatusCode::OK);
+        assert_eq!(
+            get(router, "/readyz").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let mock = MockS3::start().await;
+        mock.put_object("file.txt", b"hello world", "text/plain");
+
+        let router = make_test_router(
+            &mock,
+            &format!("metrics:\n  enabled: true\n  path: \"/stats\"\n{ROOT_ENDPOINT}"),
+        );
+        let get = |path: &'static str| {
+            router
+                .clone()
+                .oneshot(Request::get(path).body(Body::empty()).unwrap())
+        };
+
+        let response = get("/file.txt").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(
+            get("/missing.txt").await.unwrap().status(),
+            StatusCode::NOT_FOUND
+        );
+        router
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("BREW")
+                    .uri("/file.txt")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        // requests are in flight until their body was sent
+        let streaming = get("/file.txt").await.unwrap();
+        let response = get("/stats").await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains("s3_proxy_in_flight_requests 1\n"));
+        hyper::body::to_bytes(streaming.into_body()).await.unwrap();
+
+        get("/stats").await.unwrap();
+        let response = get("/stats").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let metrics = std::str::from_utf8(&body).unwrap();
+
+        assert!(metrics.contains("s3_proxy_requests_total{method=\"GET\",status=\"200\"} 2\n"));
+        assert!(metrics.contains("s3_proxy_requests_total{method=\"other\",status=\"405\"} 1\n"));
+        assert!(metrics.contains("s3_proxy_requests_total{method=\"GET\",status=\"404\"} 1\n"));
+        assert!(metrics.contains("s3_proxy_upstream_duration_seconds_count 3\n"));
+        assert!(metrics.contains("s3_proxy_streamed_bytes_total 22\n"));
+        assert!(metrics.contains("s3_proxy_in_flight_requests 0\n"));
+
+        // disabled by default, so the path is proxied
+        let router = make_test_router(&mock, ROOT_ENDPOINT);
+        let response = router
+            .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            mock.requests().last().unwrap().uri.path(),
+            "/test-bucket/metrics"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tls() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mock = MockS3::start().await;
+        mock.put_object("file.txt", b"hello world", "text/plain");
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let rustls = RustlsConfig::from_pem(
+            cert.serialize_pem().unwrap().into_bytes(),
+            cert.serialize_private_key_pem().into_bytes(),
+        )
+        .await
+        .unwrap();
+
+        // served over TLS, so it's allowed despite https_only
+        let config = make_test_config(&mock, &format!("https_only: forbid\n{ROOT_ENDPOINT}"));
+        let router = make_router(LiveConfig::new(
+            config.clone(),
+            Buckets::new(&config).unwrap(),
+        ));
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(shutdown::serve_tls(
+            axum_server::from_tcp_rustls(listener, rustls),
+            router,
+            std::future::pending(),
+            std::time::Duration::from_secs(1),
+        ));
+
+        let connector = native_tls::TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut stream = tokio_native_tls::TlsConnector::from(connector)
+            .connect("localhost", stream)
+            .await
+            .unwrap();
+
+        stream
+            .write_all(b"GET /file.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        let response = String::from_utf8(response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with("hello world"));
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_https_only() {
+        use axum::extract::ConnectInfo;
+
+        let mock = MockS3::start().await;
+        mock.put_object("file.txt", b"hello world", "text/plain");
+
+        let get = |router: &Router, peer: &str, proto: Option<&str>, path: &str| {
+            let mut request = Request::get(path)
+                .header(header::HOST, "example.com")
+                .extension(ConnectInfo(peer.parse::<std::net::SocketAddr>().unwrap()));
+            if let Some(proto) = proto {
+                request = request.header("x-forwarded-proto", proto);
+            }
+
+            router.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let router = make_test_router(
+            &mock,
+            &format!("https_only: redirect\ntrusted_proxies: [\"10.0.0.1\"]\n{ROOT_ENDPOINT}"),
+        );
+
+        let response = get(&router, "10.0.0.1:1234", Some("https"), "/file.txt")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = get(&router, "10.0.0.1:1234", Some("http"), "/file.txt?v=1")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            "https://example.com/file.txt?v=1"
+        );
+
+        // the first protocol is the one the client used
+        let response = get(&router, "10.0.0.1:1234", Some("http, https"), "/file.txt")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+
+        // only trusted proxies can claim HTTPS
+        let response = get(&router, "10.0.0.2:1234", Some("https"), "/file.txt")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+
+        let response = get(&router, "10.0.0.2:1234", None, "/healthz")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let router = make_test_router(
+            &mock,
+            &format!("https_only: forbid\ntrusted_proxies: [\"10.0.0.1\"]\n{ROOT_ENDPOINT}"),
+        );
+
+        let response = get(&router, "10.0.0.1:1234", Some("http"), "/file.txt")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = get(&router, "10.0.0.1:1234", Some("HTTPS"), "/file.txt")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_special_character_keys() {
+        let mock = MockS3::start().await;
+        for key in [
+            "a+b.txt",
+            "a&b=c.txt",
+            "café.txt",
+            "dir/a b.txt",
+            "a%2Bb.txt",
+        ] {
+            mock.put_object(key, key.as_bytes(), "text/plain");
+        }
+
+        let router = make_test_router(&mock, ROOT_ENDPOINT);
+        let cases = [
+            ("/a+b.txt", "a+b.txt", "/test-bucket/a%2Bb.txt"),
+            ("/a%2Bb.txt", "a+b.txt", "/test-bucket/a%2Bb.txt"),
+            ("/a&b=c.txt", "a&b=c.txt", "/test-bucket/a%26b%3Dc.txt"),
+            ("/a%26b%3Dc.txt", "a&b=c.txt", "/test-bucket/a%26b%3Dc.txt"),
+            ("/caf%C3%A9.txt", "café.txt", "/test-bucket/caf%C3%A9.txt"),
+            (
+                "/dir/a%20b.txt",
+                "dir/a b.txt",
+                "/test-bucket/dir/a%20b.txt",
+            ),
+            // decoded only once
+            ("/a%252Bb.txt", "a%2Bb.txt", "/test-bucket/a%252Bb.txt"),
+        ];
+
+        for (path, key, upstream_path) in cases {
+            let response = router
+                .clone()
+                .oneshot(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{path}");
+
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(body, key.as_bytes(), "{path}");
+            assert_eq!(
+                mock.requests().last().unwrap().uri.path(),
+                upstream_path,
+                "{path}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validate_if_range() {
+        let mock = MockS3::start().await;
+        mock.put_object("data.bin", b"old data", "application/octet-stream");
+        let old_etag = format!("\"{:x}\"", md5::compute(b"old data"));
+        mock.put_object("data.bin", b"new data", "application/octet-stream");
+        let new_etag = format!("\"{:x}\"", md5::compute(b"new data"));
+
+        let get = |router: &Router, if_range: &str| {
+            router.clone().oneshot(
+                Request::get("/data.bin")
+                    .header(header::RANGE, "bytes=0-2")
+                    .header(header::IF_RANGE, if_range)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let router = make_test_router(&mock, ROOT_ENDPOINT);
+        let response = get(&router, &old_etag).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+
+        let router = make_test_router(&mock, &format!("validate_if_range: true\n{ROOT_ENDPOINT}"));
+
+        let response = get(&router, &new_etag).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            "new"
+        );
+
+        let response = get(&router, &old_etag).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key(header::CONTENT_RANGE));
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            "new data"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_debug() {
+        let mock = MockS3::start().await;
+        let request = |token: &str| {
+            Request::get("/admin/debug")
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // without an admin section the path is proxied like any other
+        let response = make_test_router(&mock, ROOT_ENDPOINT)
+            .oneshot(request("secret"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(mock.requests().len(), 1);
+
+        let router = make_test_router(
+            &mock,
+            &format!("admin:\n  token: \"secret\"\n{ROOT_ENDPOINT}"),
+        );
+
+        let response = router.clone().oneshot(request("wrong")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        drop(response);
+
+        mock.put_object("file.txt", b"file", "text/plain");
+        let download = router
+            .clone()
+            .oneshot(Request::get("/file.txt").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(download.status(), StatusCode::OK);
+
+        let response = router.oneshot(request("secret")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let info: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        // the unread download and the debug request itself
+        assert_eq!(info["active_streams"], 2);
+
+        assert!(info["uptime_secs"].is_u64());
+        assert_eq!(info["etag_cache_entries"], 0);
+        assert_eq!(info["config"]["bucket"]["bucket_name"], "test-bucket");
+        assert_eq!(info["config"]["bucket"]["has_credentials"], true);
+        assert_eq!(info["config"]["endpoints"][0]["path"], "/");
+        assert!(!String::from_utf8_lossy(&body).contains("\"test\""));
+    }
+
+    #[tokio::test]
+    async fn test_max_upstream_concurrency() {
+        use std::time::Duration;
+
+        let mock = MockS3::start().await;
+        mock.insert_object(
+            "slow.bin",
+            MockObject {
+                body: b"abc".to_vec(),
+                byte_delay: Some(Duration::from_millis(200)),
+                ..Default::default()
+            },
+        );
+
+        let make_router = |queue_timeout: u64| {
+            let config = Configuration::from_yaml(&format!(
+                "{}  max_upstream_concurrency: 1\n  upstream_queue_timeout: {queue_timeout}\nhttp:\n  bind: \"127.0.0.1\"\n  port: 8000\n{ROOT_ENDPOINT}",
+                mock.bucket_yaml()
+            ))
+            .unwrap();
+            let buckets = Buckets::new(&config).unwrap();
+
+            super::make_router(LiveConfig::new(config, buckets))
+        };
+        let get = |router: &Router| {
+            router
+                .clone()
+                .oneshot(Request::get("/slow.bin").body(Body::empty()).unwrap())
+        };
+
+        let router = make_router(0);
+
+        // the first response holds the only permit while its body streams
+        let streaming = get(&router).await.unwrap();
+        assert_eq!(streaming.status(), StatusCode::OK);
+
+        let rejected = get(&router).await.unwrap();
+        assert_eq!(rejected.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(rejected.headers().contains_key(header::RETRY_AFTER));
+
+        hyper::body::to_bytes(streaming.into_body()).await.unwrap();
+        let response = get(&router).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        drop(response);
+
+        // with a queue timeout, excess requests wait for a permit instead
+        let router = make_router(5);
+        let read = || async {
+            let response = get(&router).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            hyper::body::to_bytes(response.into_body()).await.unwrap()
+        };
+        let (first, second) = tokio::join!(read(), read());
+        assert_eq!(first, "abc");
+        assert_eq!(second, "abc");
+    }
+
+    #[tokio::test]
+    async fn test_endpoint_max_concurrency() {
+        let mock = MockS3::start().await;
+        mock.insert_object(
+            "slow.bin",
+            MockObject {
+                body: b"abc".to_vec(),
+                byte_delay: Some(Duration::from_millis(200)),
+                ..Default::default()
+            },
+        );
+
+        let router = make_test_router(
+            &mock,
+            r#"
+endpoints:
+  - path: "/busy/"
+    bucket_path: "/"
+    max_concurrency: 1
+  - path: "/other/"
+    bucket_path: "/"
+    max_concurrency: 1
+"#,
+        );
+        let get = |path: &'static str| {
+            router
+                .clone()
+                .oneshot(Request::get(path).body(Body::empty()).unwrap())
+        };
+
+        // holds the busy endpoint's only permit while its body streams
+        let streaming = get("/busy/slow.bin").await.unwrap();
+        assert_eq!(streaming.status(), StatusCode::OK);
+
+        let rejected = get("/busy/slow.bin").await.unwrap();
+        assert_eq!(rejected.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(rejected.headers().contains_key(header::RETRY_AFTER));
+
+        let response = get("/other/slow.bin").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            "abc"
+        );
+
+        hyper::body::to_bytes(streaming.into_body()).await.unwrap();
+        let response = get("/busy/slow.bin").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_requests() {
+        use std::time::Duration;
+
+        let mock = MockS3::start().await;
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, "text/plain".parse().unwrap());
+        mock.insert_object(
+            "popular.txt",
+            MockObject {
+                body: b"popular".to_vec(),
+                headers,
+                response_delay: Duration::from_millis(200),
+                ..Default::default()
+            },
+        );
+
+        let router = make_test_router(&mock, &format!("coalesce_requests: true\n{ROOT_ENDPOINT}"));
+
+        let requests = (0..8)
+            .map(|_| {
+                tokio::spawn(
+                    router
+                        .clone()
+                        .oneshot(Request::get("/popular.txt").body(Body::empty()).unwrap()),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        for request in requests {
+            let response = request.await.unwrap().unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[header::CONTENT_TYPE], "text/plain");
+            assert_eq!(
+                hyper::body::to_bytes(response.into_body()).await.unwrap(),
+                "popular"
+            );
+        }
+        assert_eq!(mock.requests().len(), 1);
+
+        // range requests bypass coalescing
+        let (first, second) = tokio::join!(
+            router.clone().oneshot(
+                Request::get("/popular.txt")
+                    .header(header::RANGE, "bytes=0-2")
+                    .body(Body::empty())
+                    .unwrap()
+            ),
+            router.clone().oneshot(
+                Request::get("/popular.txt")
+                    .header(header::RANGE, "bytes=0-2")
+                    .body(Body::empty())
+                    .unwrap()
+            ),
+        );
+        assert_eq!(first.unwrap().status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(second.unwrap().status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(mock.requests().len(), 3);
+
+        // objects larger than coalesce_max_size are streamed to every request
+        let router = make_test_router(
+            &mock,
+            &format!("coalesce_requests: true\ncoalesce_max_size: 4\n{ROOT_ENDPOINT}"),
+        );
+        let requests = (0..4)
+            .map(|_| {
+                tokio::spawn(
+                    router
+                        .clone()
+                        .oneshot(Request::get("/popular.txt").body(Body::empty()).unwrap()),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        for request in requests {
+            let response = request.await.unwrap().unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[header::CONTENT_LENGTH], "7");
+            assert_eq!(
+                hyper::body::to_bytes(response.into_body()).await.unwrap(),
+                "popular"
+            );
+        }
+        assert_eq!(mock.requests().len(), 7);
+
+        // requests for the same bucket path of different buckets are separate
+        let other = MockS3::start().await;
+        other.put_object("popular.txt", b"other", "text/plain");
+        let router = make_test_router(
+            &mock,
+            &format!(
+                r#"
+coalesce_requests: true
+endpoints:
+  - path: "/a/"
+    bucket_path: "/"
+  - path: "/b/"
+    bucket_path: "/"
+    bucket:
+      endpoint: "{}"
+      region: "us-east-1"
+      bucket_name: "{}"
+      anonymous: true
+"#,
+                other.endpoint(),
+                mock_s3::BUCKET_NAME
+            ),
+        );
+        let get = |path: &'static str| {
+            let router = router.clone();
+            tokio::spawn(async move {
+                let response = router
+                    .oneshot(Request::get(path).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                hyper::body::to_bytes(response.into_body()).await.unwrap()
+            })
+        };
+
+        let (first, second) = tokio::join!(get("/a/popular.txt"), get("/b/popular.txt"));
+        assert_eq!(first.unwrap(), "popular");
+        assert_eq!(second.unwrap(), "other");
+        assert_eq!(mock.requests().len(), 8);
+        assert_eq!(other.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_log_paths() {
+        use std::{
+            io::Write,
+            sync::{Arc, Mutex},
+        };
+
+        #[derive(Clone, Default)]
+        struct Logs(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Logs {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mock = MockS3::start().await;
+        mock.put_object("secret/key.txt", b"secret", "text/plain");
+
+        let path = "/secret/key.txt";
+        let hash = config::LogPaths::Hashed.format(path).unwrap();
+
+        for (log_paths, logged) in [
+            ("full", Some(path)),
+            ("hashed", Some(&hash)),
+            ("omitted", None),
+        ] {
+            let logs = Logs::default();
+            let writer = logs.clone();
+            let subscriber = tracing_subscriber::fmt()
+                .with_writer(move || writer.clone())
+                .with_ansi(false)
+                .finish();
+            let _guard = tracing::subscriber::set_default(subscriber);
+
+            make_test_router(&mock, &format!("log_paths: {log_paths}\n{ROOT_ENDPOINT}"))
+                .oneshot(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+
+            let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+            assert!(logs.contains("GET"));
+            assert_eq!(logs.contains(path), log_paths == "full");
+            assert_eq!(logs.contains(&hash), log_paths == "hashed");
+            if let Some(logged) = logged {
+                assert!(logs.contains(&format!("path=\"{logged}\"")), "{logs}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_access_log() {
+        use std::{
+            io::Write,
+            sync::{Arc, Mutex},
+        };
+
+        #[derive(Clone, Default)]
+        struct Logs(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Logs {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mock = MockS3::start().await;
+        mock.put_object("media/file.txt", b"file", "text/plain");
+
+        let endpoints = r#"
+endpoints:
+  - path: "/files/"
+    bucket_path: "/media/"
+"#;
+
+        for (yaml, logged) in [
+            ("", false),
+            ("log_format: text\n", false),
+            ("log_format: json\n", true),
+        ] {
+            let logs = Logs::default();
+            let writer = logs.clone();
+            let subscriber = tracing_subscriber::fmt()
+                .json()
+                .flatten_event(true)
+                .with_writer(move || writer.clone())
+                .finish();
+            let _guard = tracing::subscriber::set_default(subscriber);
+
+            let router = make_test_router(&mock, &format!("{yaml}{endpoints}"));
+            let response = router
+                .clone()
+                .oneshot(Request::get("/files/file.txt").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let response = router
+                .oneshot(Request::get("/healthz").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+            let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+            let entries: Vec<serde_json::Value> = logs
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .filter(|entry: &serde_json::Value| entry["target"] == "access_log")
+                .collect();
+
+            if !logged {
+                assert!(entries.is_empty(), "{logs}");
+                continue;
+            }
+
+            assert_eq!(entries.len(), 1, "{logs}");
+            let entry = &entries[0];
+            assert_eq!(entry["method"], "GET");
+            assert_eq!(entry["path"], "/files/file.txt");
+            assert_eq!(entry["bucket_path"], "/media/file.txt");
+            assert_eq!(entry["status"], 200);
+            assert_eq!(entry["bytes"], 4);
+            assert!(entry["duration_ms"].is_number());
+        }
+
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .flatten_event(true)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let response = make_test_router(
+            &mock,
+            &format!("log_format: json\nlog_probes: true\n{endpoints}"),
+        )
+        .oneshot(Request::get("/healthz").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(
+            logs.lines()
+                .any(|line| line.contains(r#""target":"access_log""#)
+                    && line.contains(r#""path":"/healthz""#)),
+            "{logs}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reload_configuration() {
+        let mock = MockS3::start().await;
+        mock.put_object("file.txt", b"file", "text/plain");
+
+        let path =
+            std::env::temp_dir().join(format!("s3-proxy-reload-{}.yaml", std::process::id()));
+        let write_config = |endpoint: &str| {
+            std::fs::write(
+                &path,
+                format!(
+                    "{}http:\n  bind: \"127.0.0.1\"\n  port: 8000\nendpoints:\n  - path: \"{endpoint}\"\n    bucket_path: \"/\"\n",
+                    mock.bucket_yaml()
+                ),
+            )
+            .unwrap()
+        };
+        async fn status(router: &Router, uri: &str) -> StatusCode {
+            router
+                .clone()
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+                .status()
+        }
+
+        write_config("/a/");
+        let config = Configuration::from_file(&path).await.unwrap();
+        let live = LiveConfig::new(config.clone(), Buckets::new(&config).unwrap());
+        let router = make_router(live.clone());
+
+        assert_eq!(status(&router, "/a/file.txt").await, StatusCode::OK);
+        assert_eq!(status(&router, "/b/file.txt").await, StatusCode::NOT_FOUND);
+
+        write_config("/b/");
+        live.reload(&path).await.unwrap();
+
+        assert_eq!(status(&router, "/a/file.txt").await, StatusCode::NOT_FOUND);
+        assert_eq!(status(&router, "/b/file.txt").await, StatusCode::OK);
+
+        // refreshing the buckets keeps the configuration
+        let before = live.load();
+        live.refresh_buckets().await.unwrap();
+        assert!(!Arc::ptr_eq(&before, &live.load()));
+        assert_eq!(status(&router, "/b/file.txt").await, StatusCode::OK);
+
+        // invalid configurations are rejected, keeping the current one
+        std::fs::write(&path, "endpoints: [").unwrap();
+        assert!(live.reload(&path).await.is_err());
+        assert_eq!(status(&router, "/b/file.txt").await, StatusCode::OK);
+
+        // as are buckets failing the TLS check, the mock doesn't speak TLS
+        std::fs::write(
+            &path,
+            format!(
+                "{}http:\n  bind: \"127.0.0.1\"\n  port: 8000\nendpoints:\n  - path: \"/c/\"\n",
+                mock.bucket_yaml().replace("http://", "https://")
+            ),
+        )
+        .unwrap();
+        assert!(live.reload(&path).await.is_err());
+        assert_eq!(status(&router, "/b/file.txt").await, StatusCode::OK);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        use axum::extract::ConnectInfo;
+
+        let mock = MockS3::start().await;
+        mock.put_object("file.txt", b"file", "text/plain");
+
+        let get = |router: &Router, peer: &str, forwarded_for: Option<&str>, path: &str| {
+            let mut request = Request::get(path)
+                .extension(ConnectInfo(peer.parse::<std::net::SocketAddr>().unwrap()));
+            if let Some(forwarded_for) = forwarded_for {
+                request = request.header("x-forwarded-for", forwarded_for);
+            }
+
+            router.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let router = make_test_router(
+            &mock,
+            &format!(
+                "rate_limit:\n  requests_per_second: 0.1\n  burst: 2\ntrusted_proxies: [\"10.0.0.1\"]\n{ROOT_ENDPOINT}"
+            ),
+        );
+
+        for _ in 0..2 {
+            let response = get(&router, "192.0.2.1:1234", None, "/file.txt")
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = get(&router, "192.0.2.1:1234", None, "/file.txt")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "10");
+
+        // the probes aren't limited
+        let response = get(&router, "192.0.2.1:1234", None, "/healthz")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // only trusted proxies can forward the client's address, whose last
+        // untrusted entry is used
+        let response = get(&router, "192.0.2.1:1234", Some("192.0.2.2"), "/file.txt")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        for _ in 0..2 {
+            let response = get(
+                &router,
+                "10.0.0.1:1234",
+                Some("192.0.2.1, 192.0.2.2"),
+                "/file.txt",
+            )
+            .await
+            .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = get(
+            &router,
+            "10.0.0.1:1234",
+            Some("192.0.2.2, 10.0.0.1"),
+            "/file.txt",
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let err = Configuration::from_yaml(&format!(
+            "{}http:\n  bind: \"127.0.0.1\"\nrate_limit:\n  requests_per_second: 0\n  burst: 1\n{ROOT_ENDPOINT}",
+            mock.bucket_yaml()
+        ))
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<config::ConfigError>(),
+            Some(config::ConfigError::InvalidRateLimit)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        let mock = MockS3::start().await;
+        let css = "body { color: red; }\n".repeat(100);
+        mock.put_object("style.css", css.as_bytes(), "text/css");
+        mock.put_object("small.css", b"body {}", "text/css");
+        mock.put_object("image.png", &[0; 4096], "image/png");
+
+        let get = |router: &Router, path: &str, extra: Option<(header::HeaderName, &str)>| {
+            let mut request = Request::get(path).header(header::ACCEPT_ENCODING, "gzip");
+            if let Some((name, value)) = extra {
+                request = request.header(name, value);
+            }
+
+            router.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let router = make_test_router(
+            &mock,
+            &format!("compression:\n  enabled: true\n{ROOT_ENDPOINT}"),
+        );
+
+        let response = get(&router, "/style.css", None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[header::VARY], "accept-encoding");
+        assert!(!response.headers().contains_key(header::CONTENT_LENGTH));
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body[..2], [0x1f, 0x8b]);
+        assert!(body.len() < css.len());
+
+        // below min_size, not text, or partial
+        for (path, extra) in [
+            ("/small.css", None),
+            ("/image.png", None),
+            ("/style.css", Some((header::RANGE, "bytes=0-99"))),
+        ] {
+            let response = get(&router, path, extra).await.unwrap();
+            assert!(
+                !response.headers().contains_key(header::CONTENT_ENCODING),
+                "{path}"
+            );
+            assert!(
+                response.headers().contains_key(header::CONTENT_LENGTH),
+                "{path}"
+            );
+        }
+
+        let router = make_test_router(&mock, ROOT_ENDPOINT);
+        let response = get(&router, "/style.css", None).await.unwrap();
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+        assert!(!response.headers().contains_key(header::VARY));
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, css.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_reject_get_body() {
+        let mock = MockS3::start().await;
+        mock.put_object("file.txt", b"file", "text/plain");
+
+        let with_length = || {
+            Request::get("/file.txt")
+                .header(header::CONTENT_LENGTH, "4")
+                .body(Body::from("body"))
+                .unwrap()
+        };
+        let chunked = || {
+            Request::get("/file.txt")
+                .header(header::TRANSFER_ENCODING, "chunked")
+                .body(Body::from("body"))
+                .unwrap()
+        };
+
+        let ignoring = make_test_router(&mock, ROOT_ENDPOINT);
+        let rejecting = make_test_router(&mock, &format!("reject_get_body: true\n{ROOT_ENDPOINT}"));
+
+        for request in [with_length, chunked] {
+            let response = ignoring.clone().oneshot(request()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let response = rejecting.clone().oneshot(request()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+
+        let response = rejecting
+            .oneshot(
+                Request::get("/file.txt")
+                    .header(header::CONTENT_LENGTH, "0")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_verbose_errors() {
+        // nothing listens on port 1
+        let yaml = r#"
+bucket:
+  endpoint: "http://127.0.0.1:1"
+  region: "us-east-1"
+  bucket_name: "internal-bucket"
+  anonymous: true
+http:
+  bind: "127.0.0.1"
+  port: 8000
+endpoints:
+  - path: "/"
+"#;
+        let get = |yaml: &str| {
+            let config = Configuration::from_yaml(yaml).unwrap();
+            let buckets = Buckets::new(&config).unwrap();
+
+            make_router(LiveConfig::new(config, buckets))
+                .oneshot(Request::get("/a.txt").body(Body::empty()).unwrap())
+        };
+
+        let response = get(yaml).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(!response.headers().contains_key(header::RETRY_AFTER));
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            "Upstream error"
+        );
+
+        let response = get(&format!("service_unavailable_retry_after: 30\n{yaml}"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "30");
+
+        let response = get(&format!("verbose_errors: true\n{yaml}")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("127.0.0.1:1"));
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_mode() {
+        let mock = MockS3::start().await;
+        mock.put_object("file.txt", b"file", "text/plain");
+
+        let get = |router: &Router, path: &'static str| {
+            router
+                .clone()
+                .oneshot(Request::get(path).body(Body::empty()).unwrap())
+        };
+
+        let router = make_test_router(
+            &mock,
+            &format!(
+                "maintenance:\n  enabled: true\n  page: \"<p>Down</p>\"\n  retry_after: 60\n{ROOT_ENDPOINT}"
+            ),
+        );
+
+        for path in ["/file.txt", "/missing.txt"] {
+            let response = get(&router, path).await.unwrap();
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(response.headers()[header::RETRY_AFTER], "60");
+            assert_eq!(
+                hyper::body::to_bytes(response.into_body()).await.unwrap(),
+                "<p>Down</p>"
+            );
+        }
+        assert!(mock.requests().is_empty());
+
+        // toggled at runtime through the admin endpoint
+        let router = make_test_router(
+            &mock,
+            &format!("admin:\n  token: \"secret\"\n{ROOT_ENDPOINT}"),
+        );
+        let set_maintenance = |enabled: bool| {
+            router.clone().oneshot(
+                Request::put("/admin/maintenance")
+                    .header(header::AUTHORIZATION, "Bearer secret")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(format!("{{\"enabled\": {enabled}}}")))
+                    .unwrap(),
+            )
+        };
+
+        assert_eq!(
+            get(&router, "/file.txt").await.unwrap().status(),
+            StatusCode::OK
+        );
+
+        assert_eq!(
+            set_maintenance(true).await.unwrap().status(),
+            StatusCode::OK
+        );
+        let response = get(&router, "/file.txt").await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "300");
+
+        assert_eq!(
+            set_maintenance(false).await.unwrap().status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            get(&router, "/file.txt").await.unwrap().status(),
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cache_headers() {
+        let mock = MockS3::start().await;
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CACHE_CONTROL, "no-cache".parse().unwrap());
+        headers.insert(
+            header::EXPIRES,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        headers.insert(
+            header::LAST_MODIFIED,
+            "Tue, 20 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        mock.put_object_with_headers("own.txt", b"own", headers);
+        mock.put_object("plain.txt", b"plain", "text/plain");
+        let etag = format!("\"{:x}\"", md5::compute(b"plain"));
+
+        let router = make_test_router(
+            &mock,
+            r#"
+endpoints:
+  - path: "/"
+    bucket_path: "/"
+    default_cache_control: "public, max-age=3600"
+"#,
+        );
+        let get = |path: &'static str, if_none_match: Option<&str>| {
+            let mut request = Request::get(path);
+            if let Some(etag) = if_none_match {
+                request = request.header(header::IF_NONE_MATCH, etag);
+            }
+
+            router.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let response = get("/own.txt", None).await.unwrap();
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "no-cache");
+        assert_eq!(
+            response.headers()[header::EXPIRES],
+            "Wed, 21 Oct 2015 07:28:00 GMT"
+        );
+        assert_eq!(
+            response.headers()[header::LAST_MODIFIED],
+            "Tue, 20 Oct 2015 07:28:00 GMT"
+        );
+
+        let response = get("/plain.txt", None).await.unwrap();
+        assert_eq!(
+            response.headers()[header::CACHE_CONTROL],
+            "public, max-age=3600"
+        );
+        assert!(response.headers().get(header::EXPIRES).is_none());
+
+        let response = get("/plain.txt", Some(&etag)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(
+            response.headers()[header::CACHE_CONTROL],
+            "public, max-age=3600"
+        );
+
+        // without a default, nothing is added
+        let response = make_test_router(&mock, ROOT_ENDPOINT)
+            .oneshot(Request::get("/plain.txt").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert!(response.headers().get(header::CACHE_CONTROL).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_fingerprinted_assets() {
+        let mock = MockS3::start().await;
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CACHE_CONTROL, "no-cache".parse().unwrap());
+        mock.put_object_with_headers("app.3f9a0c1d.css", b"own", headers);
+        mock.put_object("app.3f9a0c1d.js", b"app", "text/javascript");
+        mock.put_object("app.js", b"app", "text/javascript");
+        mock.put_object("app.latest.js", b"app", "text/javascript");
+
+        let router = make_test_router(
+            &mock,
+            r#"
+endpoints:
+  - path: "/"
+    bucket_path: "/"
+    default_cache_control: "public, max-age=3600"
+    fingerprint_pattern: '\.[0-9a-f]{8}\.(js|css)$'
+"#,
+        );
+        let cache_control = |path: &'static str| {
+            let router = router.clone();
+            async move {
+                let response = router
+                    .oneshot(Request::get(path).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK, "{path}");
+                response.headers()[header::CACHE_CONTROL].clone()
+            }
+        };
+
+        assert_eq!(
+            cache_control("/app.3f9a0c1d.js").await,
+            "public, max-age=31536000, immutable"
+        );
+        for path in ["/app.js", "/app.latest.js"] {
+            assert_eq!(cache_control(path).await, "public, max-age=3600", "{path}");
+        }
+        // the bucket's Cache-Control still wins
+        assert_eq!(cache_control("/app.3f9a0c1d.css").await, "no-cache");
+    }
+
+    #[tokio::test]
+    async fn test_not_modified_weak_etag() {
+        let mock = MockS3::start().await;
+        let etag = format!("\"{:x}\"", md5::compute(b"app"));
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ETAG, etag.parse().unwrap());
+        headers.insert(header::CACHE_CONTROL, "max-age=3600".parse().unwrap());
+        mock.put_object_with_headers("app.js", b"app", headers);
+
+        let response = make_test_router(&mock, ROOT_ENDPOINT)
+            .oneshot(
+                Request::get("/app.js")
+                    .header(header::IF_NONE_MATCH, format!("\"outdated\", W/{etag}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], etag.as_str());
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "max-age=3600");
+        assert!(hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_zero_byte_objects() {
+        let mock = MockS3::start().await;
+        mock.put_object("empty.txt", b"", "text/plain");
+
+        let router = make_test_router(
+            &mock,
+            r#"
+endpoints:
+  - path: "/serve/"
+    bucket_path: "/"
+  - path: "/missing/"
+    bucket_path: "/"
+    treat_empty_as_missing: true
+"#,
+        );
+
+        let response = router
+            .clone()
+            .oneshot(
+                Request::get("/serve/empty.txt")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "0");
+        assert!(hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap()
+            .is_empty());
+
+        let response = router
+            .oneshot(
+                Request::get("/missing/empty.txt")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_copy_headers_skips_hop_by_hop() {
+        let mut source = HeaderMap::new();
+        source.insert(header::CONNECTION, HeaderValue::from_static("x-custom"));
+        source.insert(header::TRAILER, HeaderValue::from_static("etag"));
+        source.insert(
+            header::TRANSFER_ENCODING,
+            HeaderValue::from_static("chunked"),
+        );
+        source.insert(header::TE, HeaderValue::from_static("trailers"));
+        source.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+        source.insert("x-custom", HeaderValue::from_static("1"));
+        source.insert(header::ETAG, HeaderValue::from_static("\"abc\""));
+
+        let mut destination = HeaderMap::new();
+        copy_headers(
+            &mut destination,
+            &source,
+            &[
+                header::CONNECTION,
+                header::TRAILER,
+                header::TRANSFER_ENCODING,
+                header::TE,
+                HeaderName::from_static("keep-alive"),
+                HeaderName::from_static("x-custom"),
+                header::ETAG,
+            ],
+        );
+
+        assert_eq!(destination.len(), 1);
+        assert_eq!(destination[header::ETAG], "\"abc\"");
+    }
+
+    #[tokio::test]
+    async fn test_hop_by_hop_headers_not_forwarded() {
+        let mock = MockS3::start().await;
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        headers.insert(header::TRAILER, HeaderValue::from_static("x-checksum"));
+        headers.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+        mock.put_object_with_headers("a.txt", b"a", headers);
+
+        let router = make_test_router(&mock, ROOT_ENDPOINT);
+
+        let response = router
+            .oneshot(
+                Request::get("/a.txt")
+                    .header(header::TE, "trailers")
+                    .header(header::CONNECTION, "te")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        for name in HOP_BY_HOP_HEADERS {
+            assert!(
+                !response.headers().contains_key(*name),
+                "{name} was forwarded"
+            );
+        }
+    }
+
+    #[test]
+    fn test_referer_host() {
+        let headers = |name, value| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, HeaderValue::from_static(value));
+            headers
+        };
+
+        assert_eq!(
+            referer_host(&headers(
+                header::REFERER,
+                "https://example.com:8443/page?q=1"
+            )),
+            Some("example.com")
+        );
+        assert_eq!(
+            referer_host(&headers(header::ORIGIN, "https://example.com")),
+            Some("example.com")
+        );
+        assert_eq!(referer_host(&headers(header::ORIGIN, "null")), None);
+        assert_eq!(referer_host(&HeaderMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn test_hotlink_protection() {
+        let mock = MockS3::start().await;
+        mock.put_object("images/a.jpg", b"a", "image/jpeg");
+
+        let router = make_test_router(
+            &mock,
+            r#"
+endpoints:
+  - path: "/images/"
+    bucket_path: "/images/"
+    allowed_referers: ["example.com"]
+    allow_empty_referer: false
+"#,
+        );
+
+        let status = |referer: Option<&'static str>| {
+            let router = router.clone();
+            async move {
+                let mut request = Request::get("/images/a.jpg");
+                if let Some(referer) = referer {
+                    request = request.header(header::REFERER, referer);
+                }
+
+                router
+                    .oneshot(request.body(Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+                    .status()
+            }
+        };
+
+        assert_eq!(status(Some("https://example.com/")).await, StatusCode::OK);
+        assert_eq!(
+            status(Some("https://hotlinker.example.org/")).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(status(None).await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_cache_bust_assets() {
+        let mock = MockS3::start().await;
+        mock.put_object(
+            "site/index.html",
+            br#"<link rel="stylesheet" href="style.css"><script src="/static/app.js"></script><script src="missing.js"></script>"#,
+            "text/html; charset=utf-8",
+        );
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::ETAG,
+            HeaderValue::from_static("\"11112222333344445555\""),
+        );
+        mock.put_object_with_headers("site/style.css", b"body {}", headers);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::ETAG,
+            HeaderValue::from_static("\"aaaabbbbccccdddd-2\""),
+        );
+        mock.put_object_with_headers("assets/app.js", b"", headers);
+
+        let router = make_test_router(
+            &mock,
+            r#"
+endpoints:
+  - path: "/site/"
+    bucket_path: "/site/"
+    cache_bust_assets: true
+  - path: "/static/"
+    bucket_path: "/assets/"
+"#,
+        );
+
+        let response = router
+            .oneshot(
+                Request::get("/site/index.html")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let expected = r#"<link rel="stylesheet" href="style.css?v=11112222"><script src="/static/app.js?v=aaaabbbb"></script><script src="missing.js"></script>"#;
+
+        assert_eq!(
+            response.headers()[header::CONTENT_LENGTH],
+            expected.len().to_string()
+        );
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            expected
+        );
+    }
+
+    #[tokio::test]
+    async fn test_per_endpoint_cors() {
+        let mock = MockS3::start().await;
+        mock.put_object("public/a.txt", b"a", "text/plain");
+        mock.put_object("private/b.txt", b"b", "text/plain");
+
+        let router = make_test_router(
+            &mock,
+            r#"
+cors:
+  allowed_origins: ["https://app.example.com"]
+endpoints:
+  - path: "/public/"
+    bucket_path: "/public/"
+    cors:
+      allowed_origins: ["*"]
+  - path: "/private/"
+    bucket_path: "/private/"
+    cors:
+      allowed_origins: []
+  - path: "/"
+    bucket_path: "/"
+"#,
+        );
+
+        let allow_origin = |path: &'static str| {
+            let router = router.clone();
+            async move {
+                let response = router
+                    .oneshot(
+                        Request::get(path)
+                            .header(header::ORIGIN, "https://app.example.com")
+                            .body(Body::empty())
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+
+                response
+                    .headers()
+                    .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                    .map(|value| value.to_str().unwrap().to_owned())
+            }
+        };
+
+        assert_eq!(allow_origin("/public/a.txt").await.as_deref(), Some("*"));
+        assert_eq!(allow_origin("/private/b.txt").await, None);
+        assert_eq!(
+            allow_origin("/other.txt").await.as_deref(),
+            Some("https://app.example.com")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cors_preflight() {
+        let mock = MockS3::start().await;
+
+        let router = make_test_router(
+            &mock,
+            r#"
+cors:
+  allowed_origins: ["https://app.example.com"]
+  allowed_headers: ["Range", "If-None-Match"]
+  max_age: 600
+endpoints:
+  - path: "/any/"
+    bucket_path: "/"
+    cors:
+      allowed_origins: ["*"]
+      allowed_methods: ["GET"]
+      allowed_headers: ["*"]
+  - path: "/"
+    bucket_path: "/"
+"#,
+        );
+
+        let preflight = |path: &'static str, origin: &'static str| {
+            let router = router.clone();
+            async move {
+                let response = router
+                    .oneshot(
+                        Request::options(path)
+                            .header(header::ORIGIN, origin)
+                            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+                            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "x-custom")
+                            .body(Body::empty())
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::NO_CONTENT);
+                response.headers().clone()
+            }
+        };
+
+        let headers = preflight("/a.txt", "https://app.example.com").await;
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "GET, HEAD");
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "Range, If-None-Match"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "600");
+
+        let headers = preflight("/any/a.txt", "https://other.example.com").await;
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "GET");
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_HEADERS], "x-custom");
+        assert!(headers.get(header::ACCESS_CONTROL_MAX_AGE).is_none());
+
+        // nothing is allowed for other origins
+        let headers = preflight("/a.txt", "https://other.example.com").await;
+        assert!(headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+        assert!(headers.get(header::ACCESS_CONTROL_ALLOW_METHODS).is_none());
+        assert_eq!(headers[header::ALLOW], "GET, HEAD, OPTIONS");
+    }
+
+    #[test]
+    fn test_s3_range_header() {
+        use axum::headers::{Header, HeaderValue};
+
+        assert_eq!(
             s3_range_for_header(Range::bytes(0..=100).unwrap()),
-            Some((0, Some(100)))
+            Some(ByteRange::FromTo(0, 100))
+        );
+        assert_eq!(
+            s3_range_for_header(
+                Range::decode(&mut [HeaderValue::from_str("bytes=500-100").unwrap()].iter())
+                    .unwrap()
+            ),
+            None
         );
+        assert_eq!(ByteRange::FromTo(500, 100).resolve(1000), None);
         assert_eq!(
             s3_range_for_header(Range::bytes(0..).unwrap()),
-            Some((0, None))
+            Some(ByteRange::From(0))
         );
         assert_eq!(
             s3_range_for_header(Range::bytes(..=200).unwrap()),
-            Some((0, Some(200)))
+            Some(ByteRange::Suffix(200))
         );
         assert_eq!(
             s3_range_for_header(
@@ -237,5 +5942,11 @@ mod tests {
             ),
             None
         );
+        assert_eq!(
+            s3_range_for_header(
+                Range::decode(&mut [HeaderValue::from_str("bytes=-").unwrap()].iter()).unwrap()
+            ),
+            None
+        );
     }
 }
@@ -0,0 +1,260 @@
+//! Request metrics in the Prometheus text format.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    body::{self, BoxBody, Bytes, HttpBody},
+    extract::Extension,
+    http::{header, HeaderMap, HeaderValue, Method, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hyper::body::SizeHint;
+
+use crate::config::Configuration;
+
+/// Upper bounds of the upstream latency histogram's buckets, in seconds.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Observations per bucket of [`LATENCY_BUCKETS`], not cumulative.
+    counts: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    requests: Mutex<BTreeMap<(String, u16), u64>>,
+    upstream_latency: Mutex<Histogram>,
+    streamed_bytes: AtomicU64,
+    in_flight: AtomicI64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Registry(Arc<Inner>);
+
+impl Registry {
+    pub fn observe_upstream_latency(&self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        let mut histogram = self.0.upstream_latency.lock().unwrap();
+
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            histogram.counts[bucket] += 1;
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+    }
+
+    pub fn add_streamed_bytes(&self, bytes: usize) {
+        self.0
+            .streamed_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Returns the number of requests whose response is still being sent.
+    pub fn in_flight(&self) -> i64 {
+        self.0.in_flight.load(Ordering::Relaxed)
+    }
+
+    fn record_request(&self, method: &str, status: u16) {
+        *self
+            .0
+            .requests
+            .lock()
+            .unwrap()
+            .entry((method.to_owned(), status))
+            .or_default() += 1;
+    }
+
+    /// Formats all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP s3_proxy_requests_total Requests by method and status.\n");
+        out.push_str("# TYPE s3_proxy_requests_total counter\n");
+        for ((method, status), count) in self.0.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "s3_proxy_requests_total{{method=\"{method}\",status=\"{status}\"}} {count}"
+            );
+        }
+
+        out.push_str(
+            "# HELP s3_proxy_upstream_duration_seconds Time until the bucket's response headers.\n",
+        );
+        out.push_str("# TYPE s3_proxy_upstream_duration_seconds histogram\n");
+        let histogram = self.0.upstream_latency.lock().unwrap();
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.counts) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "s3_proxy_upstream_duration_seconds_bucket{{le=\"{bound}\"}} {cumulative}"
+            );
+        }
+        let _ = writeln!(
+            out,
+            "s3_proxy_upstream_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            histogram.count
+        );
+        let _ = writeln!(
+            out,
+            "s3_proxy_upstream_duration_seconds_sum {}",
+            histogram.sum
+        );
+        let _ = writeln!(
+            out,
+            "s3_proxy_upstream_duration_seconds_count {}",
+            histogram.count
+        );
+
+        out.push_str("# HELP s3_proxy_streamed_bytes_total Body bytes streamed from the bucket.\n");
+        out.push_str("# TYPE s3_proxy_streamed_bytes_total counter\n");
+        let _ = writeln!(
+            out,
+            "s3_proxy_streamed_bytes_total {}",
+            self.0.streamed_bytes.load(Ordering::Relaxed)
+        );
+
+        out.push_str("# HELP s3_proxy_in_flight_requests Requests currently being answered.\n");
+        out.push_str("# TYPE s3_proxy_in_flight_requests gauge\n");
+        let _ = writeln!(
+            out,
+            "s3_proxy_in_flight_requests {}",
+            self.0.in_flight.load(Ordering::Relaxed)
+        );
+
+        out
+    }
+}
+
+/// Returns the label of `method`, which is `other` for methods not defined by
+/// HTTP, so clients can't create any number of series.
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::PUT => "PUT",
+        Method::DELETE => "DELETE",
+        Method::OPTIONS => "OPTIONS",
+        Method::POST => "POST",
+        Method::PATCH => "PATCH",
+        Method::CONNECT => "CONNECT",
+        Method::TRACE => "TRACE",
+        _ => "other",
+    }
+}
+
+/// Counts a request as in flight until it's dropped.
+struct InFlightGuard(Registry);
+
+impl InFlightGuard {
+    fn enter(registry: Registry) -> Self {
+        registry.0.in_flight.fetch_add(1, Ordering::Relaxed);
+        Self(registry)
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0 .0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A response body holding an [`InFlightGuard`] until it's dropped, after it
+/// was fully sent or the client went away.
+struct TrackedBody {
+    inner: BoxBody,
+    _guard: InFlightGuard,
+}
+
+impl HttpBody for TrackedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Pin::new(&mut self.inner).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Counts requests by method and status if metrics are enabled, except those
+/// for the metrics. Requests are in flight until their response body was sent,
+/// which is counted regardless for the admin debug info.
+///
+/// Expects the [`Configuration`] in the request's extensions, so it has to be
+/// layered inside of it.
+pub async fn track_requests<B>(request: Request<B>, next: Next<B>) -> Response {
+    let Some(config) = request.extensions().get::<Configuration>() else {
+        return next.run(request).await;
+    };
+    let metrics = config.metrics();
+    if metrics.enabled() && request.uri().path() == metrics.path() {
+        return next.run(request).await;
+    }
+
+    let registry = config.registry().clone();
+    let recorded = metrics.enabled();
+    let method = method_label(request.method());
+
+    let guard = InFlightGuard::enter(registry.clone());
+    let response = next.run(request).await;
+
+    if recorded {
+        registry.record_request(method, response.status().as_u16());
+    }
+
+    response.map(|inner| {
+        body::boxed(TrackedBody {
+            inner,
+            _guard: guard,
+        })
+    })
+}
+
+pub async fn metrics(Extension(config): Extension<Configuration>) -> Response {
+    let body = config
+        .metrics_registry()
+        .map(Registry::render)
+        .unwrap_or_default();
+
+    (
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4"),
+        )],
+        body,
+    )
+        .into_response()
+}
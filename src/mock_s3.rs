@@ -0,0 +1,395 @@
+//! A minimal in-memory S3 server for tests.
+//!
+//! Only speaks enough of the S3 protocol for the proxy: path-style object
+//! requests against a single bucket.
+
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    body::{Body, Bytes, StreamBody},
+    handler::Handler,
+    http::{header, HeaderMap, HeaderValue, Method, Request, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Extension, Router,
+};
+use tokio_stream::StreamExt;
+
+#[derive(Clone, Debug, Default)]
+pub struct MockObject {
+    pub body: Vec<u8>,
+    pub headers: HeaderMap,
+    /// Delay before the response headers are sent.
+    pub response_delay: Duration,
+    /// If set, the body is sent in single bytes with this delay between them.
+    pub byte_delay: Option<Duration>,
+    /// Sends GET responses without `Content-Length`.
+    pub chunked: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct RecordedRequest {
+    pub method: Method,
+    pub uri: Uri,
+    pub headers: HeaderMap,
+}
+
+#[derive(Default)]
+struct MockState {
+    objects: Mutex<HashMap<String, MockObject>>,
+    denied: Mutex<HashSet<String>>,
+    /// Number of requests per key still to be answered with `500`.
+    failures: Mutex<HashMap<String, usize>>,
+    redirects: Mutex<HashMap<String, (StatusCode, String)>>,
+    requests: Mutex<Vec<RecordedRequest>>,
+}
+
+pub struct MockS3 {
+    addr: SocketAddr,
+    state: Arc<MockState>,
+}
+
+pub const BUCKET_NAME: &str = "test-bucket";
+
+impl MockS3 {
+    pub async fn start() -> Self {
+        let state = Arc::new(MockState::default());
+
+        let router = Router::new()
+            .fallback(handle.into_service())
+            .layer(Extension(state.clone()));
+
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(router.into_make_service());
+        let addr = server.local_addr();
+
+        tokio::spawn(server);
+
+        Self { addr, state }
+    }
+
+    pub fn endpoint(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Returns a `bucket` configuration section pointing at this server.
+    pub fn bucket_yaml(&self) -> String {
+        format!(
+            r#"bucket:
+  endpoint: "{}"
+  region: "us-east-1"
+  bucket_name: "{}"
+  access_key: "test"
+  secret_key: "test"
+"#,
+            self.endpoint(),
+            BUCKET_NAME
+        )
+    }
+
+    /// Returns a `bucket` configuration section for anonymous access to this server.
+    pub fn anonymous_bucket_yaml(&self) -> String {
+        format!(
+            "bucket:\n  endpoint: \"{}\"\n  region: \"us-east-1\"\n  bucket_name: \"{}\"\n  anonymous: true\n",
+            self.endpoint(),
+            BUCKET_NAME
+        )
+    }
+
+    /// Returns all requests received so far.
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.requests.lock().unwrap().clone()
+    }
+
+    pub fn put_object(&self, key: &str, body: &[u8], content_type: &str) {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+        headers.insert(
+            header::ETAG,
+            format!("\"{:x}\"", md5::compute(body)).parse().unwrap(),
+        );
+
+        self.put_object_with_headers(key, body, headers);
+    }
+
+    pub fn put_object_with_headers(&self, key: &str, body: &[u8], headers: HeaderMap) {
+        self.insert_object(
+            key,
+            MockObject {
+                body: body.to_vec(),
+                headers,
+                ..Default::default()
+            },
+        );
+    }
+
+    /// Answers requests for `key` with `403 AccessDenied`, like S3 does for
+    /// objects the credentials have no access to.
+    pub fn deny_access(&self, key: &str) {
+        self.state
+            .denied
+            .lock()
+            .unwrap()
+            .insert(key.trim_start_matches('/').to_owned());
+    }
+
+    /// Answers the next `count` requests for `key` with `500 InternalError`.
+    pub fn fail_next(&self, key: &str, count: usize) {
+        self.state
+            .failures
+            .lock()
+            .unwrap()
+            .insert(key.trim_start_matches('/').to_owned(), count);
+    }
+
+    /// Answers requests for `key` with a redirect to `location`.
+    pub fn redirect(&self, key: &str, status: StatusCode, location: &str) {
+        self.state.redirects.lock().unwrap().insert(
+            key.trim_start_matches('/').to_owned(),
+            (status, location.to_owned()),
+        );
+    }
+
+    pub fn insert_object(&self, key: &str, object: MockObject) {
+        self.state
+            .objects
+            .lock()
+            .unwrap()
+            .insert(key.trim_start_matches('/').to_owned(), object);
+    }
+}
+
+fn object_key(uri: &Uri) -> Option<String> {
+    let path = uri.path().trim_start_matches('/');
+    let key = path.strip_prefix(BUCKET_NAME)?.trim_start_matches('/');
+
+    Some(
+        percent_encoding::percent_decode_str(key)
+            .decode_utf8_lossy()
+            .into_owned(),
+    )
+}
+
+fn error_response(status: StatusCode, code: &str) -> Response {
+    (
+        status,
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/xml"),
+        )],
+        format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?><Error><Code>{code}</Code></Error>"),
+    )
+        .into_response()
+}
+
+/// Parses a `bytes=start-end` header as sent by `rust-s3`.
+fn parse_range(value: &str, len: usize) -> Option<(usize, usize)> {
+    let (start, end) = value.strip_prefix("bytes=")?.split_once('-')?;
+    let start = start.parse().ok()?;
+    let end = if end.is_empty() {
+        len.checked_sub(1)?
+    } else {
+        end.parse::<usize>().ok()?.min(len.checked_sub(1)?)
+    };
+
+    (start <= end).then_some((start, end))
+}
+
+fn query_param(uri: &Uri, name: &str) -> Option<String> {
+    uri.query()?.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        (key == name).then(|| {
+            percent_encoding::percent_decode_str(value)
+                .decode_utf8_lossy()
+                .into_owned()
+        })
+    })
+}
+
+fn list_objects(state: &MockState, uri: &Uri) -> Response {
+    let prefix = query_param(uri, "prefix").unwrap_or_default();
+    let max_keys = query_param(uri, "max-keys")
+        .and_then(|max_keys| max_keys.parse().ok())
+        .unwrap_or(1000);
+
+    let objects = state.objects.lock().unwrap();
+    let mut keys = objects
+        .keys()
+        .filter(|key| key.starts_with(&prefix))
+        .collect::<Vec<_>>();
+    keys.sort();
+
+    let contents = keys
+        .iter()
+        .take(max_keys)
+        .map(|key| {
+            format!(
+                "<Contents><Key>{key}</Key><LastModified>2022-01-01T00:00:00.000Z</LastModified><Size>{}</Size></Contents>",
+                objects[*key].body.len()
+            )
+        })
+        .collect::<String>();
+
+    (
+        [(header::CONTENT_TYPE, HeaderValue::from_static("application/xml"))],
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><ListBucketResult><Name>{BUCKET_NAME}</Name><Prefix>{prefix}</Prefix><IsTruncated>{}</IsTruncated>{contents}</ListBucketResult>",
+            keys.len() > max_keys
+        ),
+    )
+        .into_response()
+}
+
+async fn handle(Extension(state): Extension<Arc<MockState>>, request: Request<Body>) -> Response {
+    state.requests.lock().unwrap().push(RecordedRequest {
+        method: request.method().clone(),
+        uri: request.uri().clone(),
+        headers: request.headers().clone(),
+    });
+
+    let key = match object_key(request.uri()) {
+        Some(key) => key,
+        None => return error_response(StatusCode::NOT_FOUND, "NoSuchBucket"),
+    };
+
+    if key.is_empty() && request.method() == Method::GET {
+        return list_objects(&state, request.uri());
+    }
+
+    if state.denied.lock().unwrap().contains(&key) {
+        return error_response(StatusCode::FORBIDDEN, "AccessDenied");
+    }
+
+    if let Some(failures) = state.failures.lock().unwrap().get_mut(&key) {
+        if *failures > 0 {
+            *failures -= 1;
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, "InternalError");
+        }
+    }
+
+    if let Some((status, location)) = state.redirects.lock().unwrap().get(&key) {
+        return (*status, [(header::LOCATION, location.clone())]).into_response();
+    }
+
+    if request.method() == Method::DELETE {
+        state.objects.lock().unwrap().remove(&key);
+
+        return StatusCode::NO_CONTENT.into_response();
+    }
+
+    if request.method() == Method::PUT {
+        let mut headers = HeaderMap::new();
+        if let Some(content_type) = request.headers().get(header::CONTENT_TYPE) {
+            headers.insert(header::CONTENT_TYPE, content_type.clone());
+        }
+
+        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+        let etag: HeaderValue = format!("\"{:x}\"", md5::compute(&body)).parse().unwrap();
+        headers.insert(header::ETAG, etag.clone());
+
+        state.objects.lock().unwrap().insert(
+            key,
+            MockObject {
+                body: body.to_vec(),
+                headers,
+                ..Default::default()
+            },
+        );
+
+        return (StatusCode::OK, [(header::ETAG, etag)]).into_response();
+    }
+
+    let object = match state.objects.lock().unwrap().get(&key) {
+        Some(object) => object.clone(),
+        None => return error_response(StatusCode::NOT_FOUND, "NoSuchKey"),
+    };
+
+    tokio::time::sleep(object.response_delay).await;
+
+    let mut headers = object.headers.clone();
+
+    if let (Some(etag), Some(if_none_match)) = (
+        headers.get(header::ETAG),
+        request
+            .headers()
+            .get(header::IF_NONE_MATCH)
+            .and_then(|value| value.to_str().ok()),
+    ) {
+        // like S3, this only knows strong comparison
+        if if_none_match
+            .split(',')
+            .any(|candidate| candidate.trim() == etag || candidate.trim() == "*")
+        {
+            return (StatusCode::NOT_MODIFIED, headers).into_response();
+        }
+    }
+
+    if let Some(if_match) = request.headers().get(header::IF_MATCH) {
+        if headers.get(header::ETAG) != Some(if_match) {
+            return error_response(StatusCode::PRECONDITION_FAILED, "PreconditionFailed");
+        }
+    }
+
+    let range = request
+        .headers()
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok());
+
+    let (status, body) = match range {
+        Some(range) => match parse_range(range, object.body.len()) {
+            Some((start, end)) => {
+                headers.insert(
+                    header::CONTENT_RANGE,
+                    format!("bytes {start}-{end}/{}", object.body.len())
+                        .parse()
+                        .unwrap(),
+                );
+                (
+                    StatusCode::PARTIAL_CONTENT,
+                    object.body[start..=end].to_vec(),
+                )
+            }
+            None => {
+                return (
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    [(
+                        header::CONTENT_TYPE,
+                        HeaderValue::from_static("application/xml"),
+                    )],
+                    format!(
+                        "<?xml version=\"1.0\" encoding=\"UTF-8\"?><Error><Code>InvalidRange</Code><ActualObjectSize>{}</ActualObjectSize></Error>",
+                        object.body.len()
+                    ),
+                )
+                    .into_response()
+            }
+        },
+        None => (StatusCode::OK, object.body),
+    };
+
+    if !object.chunked || request.method() == Method::HEAD {
+        headers.insert(header::CONTENT_LENGTH, body.len().into());
+    }
+
+    if request.method() == Method::HEAD {
+        (status, headers).into_response()
+    } else if object.chunked {
+        let stream = tokio_stream::once(Ok::<_, Infallible>(Bytes::from(body)));
+
+        (status, headers, StreamBody::new(stream)).into_response()
+    } else if let Some(byte_delay) = object.byte_delay {
+        let bytes = body
+            .into_iter()
+            .map(|byte| Ok::<_, Infallible>(Bytes::from(vec![byte])));
+        let stream = tokio_stream::iter(bytes).throttle(byte_delay);
+
+        (status, headers, StreamBody::new(stream)).into_response()
+    } else {
+        (status, headers, Bytes::from(body)).into_response()
+    }
+}
@@ -0,0 +1,244 @@
+//! Rate limiting of requests per client IP.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::ConnectInfo,
+    http::{header, HeaderMap, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{
+    config::{Configuration, RateLimit},
+    https::is_unix_socket,
+};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// Clients tracked before buckets that are full again are dropped.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// How often buckets that are full again are dropped at most, so a stream of
+/// new clients doesn't scan all buckets for each of them.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, rate_limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate_limit.requests_per_second())
+            .min(rate_limit.burst() as f64);
+        self.updated = now;
+    }
+}
+
+#[derive(Default)]
+struct Clients {
+    buckets: HashMap<IpAddr, TokenBucket>,
+    /// When buckets that are full again were last dropped.
+    swept: Option<Instant>,
+}
+
+/// Token buckets of the clients, shared by all requests.
+#[derive(Clone, Default)]
+pub struct RateLimiter(Arc<Mutex<Clients>>);
+
+impl RateLimiter {
+    /// Takes a token from the bucket of `client`, or returns how long until
+    /// the next one is available.
+    ///
+    /// At most [`MAX_TRACKED_CLIENTS`] are tracked. New clients beyond that
+    /// aren't limited until buckets that are full again have been dropped,
+    /// as they'd start with a full bucket anyway.
+    fn acquire(
+        &self,
+        client: IpAddr,
+        rate_limit: &RateLimit,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let mut clients = self.0.lock().unwrap();
+        let Clients { buckets, swept } = &mut *clients;
+
+        if buckets.len() >= MAX_TRACKED_CLIENTS && !buckets.contains_key(&client) {
+            if swept.is_none_or(|swept| now.saturating_duration_since(swept) >= SWEEP_INTERVAL) {
+                buckets.retain(|_, bucket| {
+                    bucket.refill(rate_limit, now);
+                    bucket.tokens < rate_limit.burst() as f64
+                });
+                *swept = Some(now);
+            }
+
+            if buckets.len() >= MAX_TRACKED_CLIENTS {
+                return Ok(());
+            }
+        }
+
+        let bucket = buckets.entry(client).or_insert(TokenBucket {
+            tokens: rate_limit.burst() as f64,
+            updated: now,
+        });
+        bucket.refill(rate_limit, now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / rate_limit.requests_per_second(),
+            ))
+        }
+    }
+}
+
+/// Returns the IP of the client, which is the peer unless it's a trusted
+/// proxy. Then the last address in `X-Forwarded-For` not added by a trusted
+/// proxy is used, as earlier ones can be forged by the client.
+fn client_ip(config: &Configuration, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    if !config.is_trusted_proxy(peer) {
+        return peer;
+    }
+
+    forwarded_client_ip(config, headers).unwrap_or(peer)
+}
+
+fn forwarded_client_ip(config: &Configuration, headers: &HeaderMap) -> Option<IpAddr> {
+    let mut forwarded = headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|addr| addr.trim().parse::<IpAddr>().ok())
+        .collect::<Vec<_>>();
+
+    while let Some(addr) = forwarded.pop() {
+        match addr {
+            Some(addr) if config.is_trusted_proxy(addr) => continue,
+            addr => return addr,
+        }
+    }
+
+    None
+}
+
+/// Answers requests exceeding the client's `rate_limit` with `429 Too Many
+/// Requests`.
+///
+/// The health probes are exempt, as they're polled frequently from the same
+/// address. Requests over a unix socket are keyed by `X-Forwarded-For` only,
+/// and aren't limited without it. Expects the [`Configuration`] and the
+/// [`RateLimiter`] in the request's extensions, so it has to be layered inside
+/// of them.
+pub async fn limit_requests<B>(request: Request<B>, next: Next<B>) -> Response {
+    let (Some(config), Some(limiter)) = (
+        request.extensions().get::<Configuration>(),
+        request.extensions().get::<RateLimiter>(),
+    ) else {
+        return next.run(request).await;
+    };
+    let Some(rate_limit) = config.rate_limit() else {
+        return next.run(request).await;
+    };
+    if matches!(
+        request.uri().path(),
+        "/healthz" | "/healthz/buckets" | "/readyz"
+    ) {
+        return next.run(request).await;
+    }
+
+    let client = match request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(peer)) => Some(client_ip(config, peer.ip(), request.headers())),
+        None if is_unix_socket(&request) => forwarded_client_ip(config, request.headers()),
+        None => None,
+    };
+    let Some(client) = client else {
+        return next.run(request).await;
+    };
+
+    if let Err(wait) = limiter.acquire(client, rate_limit, Instant::now()) {
+        // Retry-After only has a resolution of seconds
+        let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, retry_after.to_string())],
+            "Too many requests",
+        )
+            .into_response();
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate_limit(requests_per_second: f64, burst: u32) -> RateLimit {
+        serde_yaml::from_str(&format!(
+            "requests_per_second: {requests_per_second}\nburst: {burst}"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::default();
+        let rate_limit = rate_limit(2.0, 3);
+        let client = "192.0.2.1".parse().unwrap();
+        let other = "192.0.2.2".parse().unwrap();
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.acquire(client, &rate_limit, now).is_ok());
+        }
+        assert_eq!(
+            limiter.acquire(client, &rate_limit, now),
+            Err(Duration::from_millis(500))
+        );
+        assert!(limiter.acquire(other, &rate_limit, now).is_ok());
+
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.acquire(client, &rate_limit, later).is_ok());
+        assert!(limiter.acquire(client, &rate_limit, later).is_err());
+    }
+
+    #[test]
+    fn test_max_tracked_clients() {
+        let limiter = RateLimiter::default();
+        let rate_limit = rate_limit(2.0, 3);
+        let now = Instant::now();
+        let tracked = || limiter.0.lock().unwrap().buckets.len();
+
+        for client in 0..MAX_TRACKED_CLIENTS as u32 {
+            let client = IpAddr::from(std::net::Ipv4Addr::from(client));
+            assert!(limiter.acquire(client, &rate_limit, now).is_ok());
+        }
+        assert_eq!(tracked(), MAX_TRACKED_CLIENTS);
+
+        // no bucket is full again, so new clients aren't tracked
+        let client = "192.0.2.1".parse().unwrap();
+        for _ in 0..4 {
+            assert!(limiter.acquire(client, &rate_limit, now).is_ok());
+        }
+        assert_eq!(tracked(), MAX_TRACKED_CLIENTS);
+
+        // the buckets are only swept once per SWEEP_INTERVAL
+        let later = now + Duration::from_millis(100);
+        assert!(limiter.acquire(client, &rate_limit, later).is_ok());
+        assert_eq!(tracked(), MAX_TRACKED_CLIENTS);
+
+        let later = now + SWEEP_INTERVAL;
+        assert!(limiter.acquire(client, &rate_limit, later).is_ok());
+        assert_eq!(tracked(), 1);
+    }
+}
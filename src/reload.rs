@@ -0,0 +1,161 @@
+//! Reloading the configuration on `SIGHUP`, and the buckets before their
+//! temporary credentials expire.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use arc_swap::ArcSwap;
+use axum::{http::Request, middleware::Next, response::Response};
+
+use crate::{
+    buckets::Buckets,
+    config::{ConfigError, Configuration},
+};
+
+/// How often the expiration of temporary credentials is checked.
+const CREDENTIALS_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long before they expire temporary credentials are refreshed. EC2 and
+/// ECS provide new ones at least five minutes before.
+const CREDENTIALS_REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+/// A configuration with the buckets built from it.
+pub struct Loaded {
+    pub config: Configuration,
+    pub buckets: Buckets,
+}
+
+/// The current configuration, which can be swapped while requests are handled.
+///
+/// Requests see the configuration and buckets that were current when they
+/// started, as [`Extension`](axum::Extension)s.
+#[derive(Clone)]
+pub struct LiveConfig(Arc<ArcSwap<Loaded>>);
+
+impl LiveConfig {
+    pub fn new(config: Configuration, buckets: Buckets) -> Self {
+        Self(Arc::new(ArcSwap::from_pointee(Loaded { config, buckets })))
+    }
+
+    pub fn load(&self) -> Arc<Loaded> {
+        self.0.load_full()
+    }
+
+    /// Reads the configuration from `path` and swaps it in with freshly built
+    /// buckets. The current configuration is kept if either fails, or if a
+    /// bucket endpoint doesn't support `min_upstream_tls_version`.
+    ///
+    /// Concurrency limits that didn't change keep counting requests holding
+    /// permits, and the bucket health checks probe the new buckets.
+    ///
+    /// Only the endpoints and options read per request take effect. `http`,
+    /// `bucket_health_interval`, enabling `admin`, `metrics`, `compression`,
+    /// `log_format` and `maintenance.enabled` require a restart, as does
+    /// `startup_probe_endpoints`, which is only checked at startup.
+    pub async fn reload(&self, path: &Path) -> anyhow::Result<()> {
+        let config = Configuration::from_file(path).await?;
+
+        // credentials are fetched with blocking requests
+        let (mut config, buckets) = tokio::task::spawn_blocking(move || {
+            let buckets = Buckets::new(&config);
+            (config, buckets)
+        })
+        .await
+        .expect("building the buckets panicked");
+        let buckets = buckets?;
+
+        crate::check_buckets_tls(&buckets, &config).await?;
+
+        let previous = self.load();
+        config.keep_metrics(&previous.config);
+        config.keep_permits(&previous.config);
+        self.0.store(Arc::new(Loaded { config, buckets }));
+
+        Ok(())
+    }
+
+    /// Rebuilds the buckets of the current configuration, which fetches fresh
+    /// credentials. The buckets are kept if the configuration was reloaded in
+    /// the meantime, as that built fresh ones already.
+    pub async fn refresh_buckets(&self) -> Result<(), ConfigError> {
+        let current = self.load();
+        let config = current.config.clone();
+
+        // credentials are fetched with blocking requests
+        let (config, buckets) = tokio::task::spawn_blocking(move || {
+            let buckets = Buckets::new(&config);
+            (config, buckets)
+        })
+        .await
+        .expect("building the buckets panicked");
+
+        self.0.compare_and_swap(
+            &current,
+            Arc::new(Loaded {
+                config,
+                buckets: buckets?,
+            }),
+        );
+
+        Ok(())
+    }
+
+    /// Inserts the current configuration and buckets into the request's
+    /// extensions.
+    pub async fn provide<B>(self, mut request: Request<B>, next: Next<B>) -> Response {
+        let loaded = self.load();
+        request.extensions_mut().insert(loaded.config.clone());
+        request.extensions_mut().insert(loaded.buckets.clone());
+
+        next.run(request).await
+    }
+}
+
+/// Reloads the configuration from `path` on every `SIGHUP`, logging invalid
+/// configurations instead of applying them.
+#[cfg(unix)]
+pub async fn reload_on_sighup(live: LiveConfig, path: PathBuf) {
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("Couldn't listen for SIGHUP");
+
+    while hangup.recv().await.is_some() {
+        match live.reload(&path).await {
+            Ok(()) => tracing::info!("Reloaded configuration from {}", path.display()),
+            Err(err) => tracing::error!(
+                "Couldn't reload configuration from {}, keeping the current one: {err:#}",
+                path.display()
+            ),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn reload_on_sighup(_live: LiveConfig, _path: PathBuf) {}
+
+/// Rebuilds the buckets shortly before their temporary credentials expire,
+/// retrying every minute if that fails. Until then the current credentials are
+/// used.
+pub async fn refresh_credentials(live: LiveConfig) {
+    let mut interval = tokio::time::interval(CREDENTIALS_CHECK_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let expiring = live
+            .load()
+            .buckets
+            .credentials_expiration()
+            .is_some_and(|expiration| expiration <= SystemTime::now() + CREDENTIALS_REFRESH_MARGIN);
+        if !expiring {
+            continue;
+        }
+
+        match live.refresh_buckets().await {
+            Ok(()) => tracing::info!("Refreshed temporary credentials"),
+            Err(err) => tracing::error!("Couldn't refresh temporary credentials: {err}"),
+        }
+    }
+}
@@ -0,0 +1,443 @@
+//! Graceful shutdown, draining in-flight requests before exiting.
+
+use std::{
+    future::Future,
+    path::Path,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use axum::{
+    body::{self, BoxBody, Bytes, HttpBody},
+    extract::{connect_info::Connected, Extension},
+    http::{HeaderMap, Request},
+    middleware::{self, Next},
+    response::Response,
+    BoxError, Router,
+};
+use axum_server::tls_rustls::RustlsAcceptor;
+use hyper::{
+    body::SizeHint,
+    server::{
+        accept::{self, Accept},
+        conn::AddrIncoming,
+    },
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::watch,
+};
+#[cfg(unix)]
+use tokio_stream::wrappers::UnixListenerStream;
+
+use crate::https::DirectTls;
+#[cfg(unix)]
+use crate::https::UnixSocket;
+
+/// Counts requests until their response body is fully sent or dropped.
+#[derive(Clone, Default)]
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn count(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn enter(&self) -> InFlightGuard {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        InFlightGuard(self.0.clone())
+    }
+}
+
+struct InFlightGuard(Arc<AtomicUsize>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A response body holding an [`InFlightGuard`] until it's dropped.
+struct GuardedBody {
+    inner: BoxBody,
+    _guard: InFlightGuard,
+}
+
+impl HttpBody for GuardedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Pin::new(&mut self.inner).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+async fn track_in_flight<B>(in_flight: InFlight, request: Request<B>, next: Next<B>) -> Response {
+    let guard = in_flight.enter();
+    let response = next.run(request).await;
+
+    response.map(|inner| {
+        body::boxed(GuardedBody {
+            inner,
+            _guard: guard,
+        })
+    })
+}
+
+/// Spawns connections on the runtime, aborting them once the sender of the
+/// channel is dropped.
+///
+/// Connections aren't owned by the server future, so they'd keep running
+/// after the drain timeout otherwise.
+#[derive(Clone)]
+struct Executor(watch::Receiver<()>);
+
+impl<F> hyper::rt::Executor<F> for Executor
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    fn execute(&self, future: F) {
+        let mut closed = self.0.clone();
+
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = future => {}
+                _ = closed.changed() => {}
+            }
+        });
+    }
+}
+
+/// Completes on `SIGTERM` or Ctrl-C.
+pub async fn signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Couldn't listen for Ctrl-C");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Couldn't listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+fn with_in_flight_tracking(router: Router) -> (Router, InFlight) {
+    let in_flight = InFlight::default();
+    let router = router.layer(middleware::from_fn({
+        let in_flight = in_flight.clone();
+        move |request, next| track_in_flight(in_flight.clone(), request, next)
+    }));
+
+    (router, in_flight)
+}
+
+/// Serves `router` until `shutdown` completes, then stops accepting connections
+/// and waits up to `drain_timeout` for in-flight requests before closing the
+/// remaining connections.
+pub async fn serve(
+    incoming: AddrIncoming,
+    router: Router,
+    shutdown: impl Future<Output = ()>,
+    drain_timeout: Duration,
+) -> anyhow::Result<()> {
+    // the peer address tells whether X-Forwarded-Proto can be trusted
+    serve_incoming::<_, std::net::SocketAddr>(incoming, router, shutdown, drain_timeout).await
+}
+
+/// Like [`serve`], but listening on a unix socket at `path`.
+///
+/// A stale socket file left behind by a previous run is replaced, the socket
+/// file is removed again once the server stopped.
+#[cfg(unix)]
+pub async fn serve_unix(
+    path: &Path,
+    router: Router,
+    shutdown: impl Future<Output = ()>,
+    drain_timeout: Duration,
+) -> anyhow::Result<()> {
+    remove_stale_socket(path)?;
+    let listener = tokio::net::UnixListener::bind(path)
+        .with_context(|| format!("Couldn't bind unix socket {}", path.display()))?;
+    let incoming = accept::from_stream(UnixListenerStream::new(listener));
+
+    let result = serve_incoming::<_, UnixSocket>(incoming, router, shutdown, drain_timeout).await;
+
+    if let Err(err) = std::fs::remove_file(path) {
+        tracing::warn!("Couldn't remove unix socket {}: {err}", path.display());
+    }
+
+    result
+}
+
+/// Removes the socket file at `path` unless nothing is listening on it
+/// anymore. Other files are left alone.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> anyhow::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                anyhow::bail!("Unix socket {} is in use", path.display());
+            }
+
+            Ok(std::fs::remove_file(path)?)
+        }
+        Ok(_) => anyhow::bail!("{} exists and isn't a unix socket", path.display()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+async fn serve_incoming<I, C>(
+    incoming: I,
+    router: Router,
+    shutdown: impl Future<Output = ()>,
+    drain_timeout: Duration,
+) -> anyhow::Result<()>
+where
+    I: Accept,
+    I::Error: Into<BoxError>,
+    I::Conn: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    C: for<'a> Connected<&'a I::Conn> + Clone + Send + Sync + 'static,
+{
+    let (router, in_flight) = with_in_flight_tracking(router);
+
+    // closes all connections when dropped at the end of this function
+    let (_close_connections, connections_closed) = watch::channel(());
+
+    let (shutdown_started, mut shutdown_rx) = tokio::sync::oneshot::channel();
+    let server = axum::Server::builder(incoming)
+        .executor(Executor(connections_closed))
+        .serve(router.into_make_service_with_connect_info::<C>())
+        .with_graceful_shutdown(async {
+            shutdown.await;
+            let _ = shutdown_started.send(());
+        });
+    tokio::pin!(server);
+
+    tokio::select! {
+        result = &mut server => return Ok(result?),
+        _ = &mut shutdown_rx => {}
+    }
+
+    tracing::info!(
+        "Shutting down, waiting for {} requests in flight",
+        in_flight.count()
+    );
+
+    match tokio::time::timeout(drain_timeout, server).await {
+        Ok(result) => Ok(result?),
+        Err(_) => {
+            tracing::warn!(
+                "Closing connections of {} requests still in flight after {:?}",
+                in_flight.count(),
+                drain_timeout
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Like [`serve`], but for a server terminating TLS. Requests are marked with
+/// [`DirectTls`].
+pub async fn serve_tls(
+    server: axum_server::Server<RustlsAcceptor>,
+    router: Router,
+    shutdown: impl Future<Output = ()>,
+    drain_timeout: Duration,
+) -> anyhow::Result<()> {
+    let (router, in_flight) = with_in_flight_tracking(router.layer(Extension(DirectTls)));
+
+    let handle = axum_server::Handle::new();
+    let server = server
+        .handle(handle.clone())
+        .serve(router.into_make_service_with_connect_info::<std::net::SocketAddr>());
+    tokio::pin!(server);
+
+    tokio::select! {
+        result = &mut server => return Ok(result?),
+        _ = shutdown => {}
+    }
+
+    tracing::info!(
+        "Shutting down, waiting for {} requests in flight",
+        in_flight.count()
+    );
+    handle.graceful_shutdown(None);
+
+    match tokio::time::timeout(drain_timeout, &mut server).await {
+        Ok(result) => Ok(result?),
+        Err(_) => {
+            tracing::warn!(
+                "Closing connections of {} requests still in flight after {:?}",
+                in_flight.count(),
+                drain_timeout
+            );
+            handle.shutdown();
+            Ok(server.await?)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use axum::{body::StreamBody, routing::get};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_stream::StreamExt;
+
+    use super::*;
+
+    /// Serves a body of `len` bytes, sent one every `byte_delay`, and returns
+    /// the raw response read until the connection closes.
+    async fn shutdown_during_download(
+        len: usize,
+        byte_delay: Duration,
+        drain_timeout: Duration,
+    ) -> (String, Duration) {
+        let router = Router::new().route(
+            "/slow",
+            get(move || async move {
+                let bytes = (0..len).map(|_| Ok::<_, std::convert::Infallible>(Bytes::from("a")));
+                StreamBody::new(tokio_stream::iter(bytes).throttle(byte_delay))
+            }),
+        );
+
+        let incoming = AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = incoming.local_addr();
+        let (trigger, shutdown) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(
+            incoming,
+            router,
+            async {
+                let _ = shutdown.await;
+            },
+            drain_timeout,
+        ));
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = vec![0; 16];
+        stream.read_exact(&mut response).await.unwrap();
+
+        let started = Instant::now();
+        trigger.send(()).unwrap();
+        stream.read_to_end(&mut response).await.unwrap();
+        server.await.unwrap().unwrap();
+
+        (String::from_utf8(response).unwrap(), started.elapsed())
+    }
+
+    #[tokio::test]
+    async fn test_drain_in_flight_requests() {
+        let (response, _) =
+            shutdown_during_download(5, Duration::from_millis(100), Duration::from_secs(5)).await;
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        // the chunked body is complete
+        assert!(response.ends_with("0\r\n\r\n"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_serve_unix() {
+        let path = std::env::temp_dir().join(format!("s3-proxy-serve-{}.sock", std::process::id()));
+        // left behind by a previous run, nothing listens on it anymore
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+        let router = Router::new().route("/", get(|| async { "hello" }));
+        let (trigger, shutdown) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn({
+            let path = path.clone();
+            async move {
+                serve_unix(
+                    &path,
+                    router,
+                    async {
+                        let _ = shutdown.await;
+                    },
+                    Duration::from_secs(5),
+                )
+                .await
+            }
+        });
+
+        let mut stream = loop {
+            match tokio::net::UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("hello"));
+
+        trigger.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        assert!(!path.exists());
+
+        // other files aren't replaced
+        std::fs::write(&path, "not a socket").unwrap();
+        let err = serve_unix(&path, Router::new(), async {}, Duration::from_secs(5))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("isn't a unix socket"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_drain_timeout() {
+        let (response, elapsed) =
+            shutdown_during_download(100, Duration::from_millis(100), Duration::from_millis(200))
+                .await;
+
+        assert!(!response.ends_with("0\r\n\r\n"));
+        assert!(elapsed < Duration::from_secs(5));
+    }
+}